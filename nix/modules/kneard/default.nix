@@ -75,6 +75,14 @@ in
   ];
 
   config = {
+    assertions = [{
+      # DelegateSubgroup of the kuutamod service
+      assertion = lib.versionAtLeast config.systemd.package.version "254";
+      message = ''
+        kneard requires systemd 254 or newer to run neard in its own cgroup (DelegateSubgroup)
+      '';
+    }];
+
     services.consul = {
       enable = true;
       extraConfig = {
@@ -163,6 +171,11 @@ in
             ++ lib.optional (cfg.publicAddress != null) "KUUTAMO_PUBLIC_ADDRESS=${cfg.publicAddress}";
 
           RuntimeDirectory = "kuutamod";
+          # kneard runs neard in its own cgroup below the one of the service.
+          # kneard and ExecReload run in the subgroup kneard, since the cgroup of the
+          # service cannot contain processes once kneard enables controllers for neard.
+          Delegate = true;
+          DelegateSubgroup = "kneard";
 
           ExecReload = [
             "+${pkgs.writeShellScript "kneard-schedule-reload" ''
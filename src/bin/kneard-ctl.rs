@@ -4,7 +4,9 @@
 
 use anyhow::{anyhow, Context, Result};
 use clap::Parser;
-use kneard::commands::control_commands::{CheckRpcArgs, Command, RestartArgs, SystemInfoArgs};
+use kneard::commands::control_commands::{
+    CheckRpcArgs, Command, HandoverArgs, NeardLogsArgs, RestartArgs, StageNeardArgs, SystemInfoArgs,
+};
 use kneard::commands::{system_info, CommandClient};
 use std::path::PathBuf;
 use std::time::Duration;
@@ -38,13 +40,66 @@ async fn show_active_validator(kuutamo_client: &CommandClient, args: &Args) -> R
         );
     } else {
         match validator {
-            Some(v) => println!("Name: {}\nNode: {}", v.name, v.node),
+            Some(v) => {
+                println!("Name: {}\nNode: {}", v.name, v.node);
+                if v.drained {
+                    println!("Drained: gives up its leadership in the next maintenance window");
+                }
+            }
             None => println!("No active validator"),
         }
     }
     Ok(())
 }
 
+async fn show_history(kuutamo_client: &CommandClient, args: &Args) -> Result<()> {
+    let transitions = kuutamo_client.transitions().await?;
+    if args.json {
+        println!(
+            "{}",
+            serde_json::to_string(&transitions).context("Failed to serialize json")?
+        );
+    } else {
+        for t in transitions {
+            println!("{t}");
+        }
+    }
+    Ok(())
+}
+
+async fn show_crashes(kuutamo_client: &CommandClient, args: &Args) -> Result<()> {
+    let crashes = kuutamo_client.crashes().await?;
+    if args.json {
+        println!(
+            "{}",
+            serde_json::to_string(&crashes).context("Failed to serialize json")?
+        );
+    } else if crashes.is_empty() {
+        println!("neard did not exit yet");
+    } else {
+        for c in crashes {
+            println!("{c}");
+        }
+    }
+    Ok(())
+}
+
+async fn show_validator_stats(kuutamo_client: &CommandClient, args: &Args) -> Result<()> {
+    let stats = kuutamo_client.validator_stats().await?;
+    if args.json {
+        println!(
+            "{}",
+            serde_json::to_string(&stats).context("Failed to serialize json")?
+        );
+    } else {
+        match stats {
+            Some(s) => println!("{s}"),
+            None => println!("Not validating in the current epoch"),
+        }
+    }
+    Ok(())
+}
+
 async fn schedule_restart(
     kuutamo_client: &CommandClient,
     control_socket: &PathBuf,
@@ -61,6 +116,7 @@ async fn schedule_restart(
                 restart_arg.minimum_length,
                 restart_arg.schedule_at,
                 restart_arg.cancel,
+                restart_arg.force,
             )
             .await;
         if r.is_ok() && restart_arg.wait {
@@ -77,6 +133,19 @@ async fn show_maintenance_status(kuutamo_client: &CommandClient) -> Result<()> {
     Ok(println!("{}", &kuutamo_client.maintenance_status().await?))
 }
 
+async fn show_maintenance_windows(kuutamo_client: &CommandClient, args: &Args) -> Result<()> {
+    let plan = kuutamo_client.maintenance_windows().await?;
+    if args.json {
+        println!(
+            "{}",
+            serde_json::to_string(&plan).context("Failed to serialize json")?
+        );
+    } else {
+        println!("{plan}");
+    }
+    Ok(())
+}
+
 async fn check_rpc_status(kuutamo_client: &CommandClient, watch: bool) -> Result<()> {
     if watch {
         while kuutamo_client.rpc_status().await.is_ok() {
@@ -88,6 +157,20 @@ async fn check_rpc_status(kuutamo_client: &CommandClient, watch: bool) -> Result
     }
 }
 
+async fn stage_neard(kuutamo_client: &CommandClient, stage_args: StageNeardArgs) -> Result<()> {
+    let binary = match stage_args.path {
+        // kneard runs in another working directory
+        Some(path) => Some(
+            path.canonicalize()
+                .with_context(|| format!("cannot find neard binary {}", path.display()))?,
+        ),
+        None => None,
+    };
+    kuutamo_client
+        .stage_neard(binary.as_deref(), stage_args.force)
+        .await
+}
+
 /// The kneard-ctl program entry point
 #[tokio::main]
 pub async fn main() {
@@ -99,7 +182,20 @@ pub async fn main() {
             schedule_restart(&kuutamo_client, &args.control_socket, operation_arg).await
         }
         Command::MaintenanceStatus => show_maintenance_status(&kuutamo_client).await,
+        Command::MaintenanceWindows => show_maintenance_windows(&kuutamo_client, &args).await,
         Command::CheckRpc(CheckRpcArgs { watch }) => check_rpc_status(&kuutamo_client, watch).await,
+        Command::History => show_history(&kuutamo_client, &args).await,
+        Command::Handover(HandoverArgs { to }) => kuutamo_client.handover(&to).await,
+        Command::Drain => kuutamo_client.drain(true).await,
+        Command::Undrain => kuutamo_client.drain(false).await,
+        Command::Crashes => show_crashes(&kuutamo_client, &args).await,
+        Command::NeardLogs(NeardLogsArgs { lines, follow }) => {
+            kuutamo_client
+                .neard_logs(lines, follow, &mut std::io::stdout())
+                .await
+        }
+        Command::ValidatorStats => show_validator_stats(&kuutamo_client, &args).await,
+        Command::StageNeard(stage_args) => stage_neard(&kuutamo_client, stage_args).await,
         Command::SystemInfo(SystemInfoArgs { inline }) => {
             system_info::system_info(inline);
             Ok(())
@@ -0,0 +1,353 @@
+//! Runs neard in its own cgroup v2 below the cgroup of kneard, so that the memory, cpu
+//! and io neard uses can be limited and memory spikes of neard do not starve kneard
+
+use crate::settings::Settings;
+use anyhow::{bail, Context, Result};
+use lazy_static::lazy_static;
+use log::{info, warn};
+use nix::unistd::{write, Pid};
+use prometheus::{register_gauge, register_int_gauge, register_int_gauge_vec, Gauge};
+use prometheus::{IntGauge, IntGaugeVec};
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::os::fd::RawFd;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// Where the cgroup v2 hierarchy is mounted
+const CGROUP_MOUNT: &str = "/sys/fs/cgroup";
+/// Controllers kneard enables for neard, if the kernel and systemd delegate them
+const CONTROLLERS: [&str; 3] = ["memory", "cpu", "io"];
+
+lazy_static! {
+    static ref NEARD_CGROUP: Mutex<Option<NeardCgroup>> = Mutex::new(None);
+    static ref CGROUP_MEMORY: IntGauge = register_int_gauge!(
+        "kuutamod_neard_cgroup_memory_bytes",
+        "Memory used by the cgroup of neard, including the page cache"
+    )
+    .unwrap();
+    static ref CGROUP_MEMORY_EVENTS: IntGaugeVec = register_int_gauge_vec!(
+        "kuutamod_neard_cgroup_memory_events",
+        "How often the cgroup of neard hit memory.high (high), memory.max (max) or the OOM killer (oom_kill)",
+        &["type"],
+    )
+    .unwrap();
+    static ref CGROUP_CPU: Gauge = register_gauge!(
+        "kuutamod_neard_cgroup_cpu_seconds",
+        "Cpu time used by the cgroup of neard"
+    )
+    .unwrap();
+    static ref CGROUP_IO: IntGaugeVec = register_int_gauge_vec!(
+        "kuutamod_neard_cgroup_io_bytes",
+        "Bytes read or written (type) by the cgroup of neard",
+        &["type"],
+    )
+    .unwrap();
+}
+
+/// Resource limits of the cgroup of neard. `None` leaves the kernel default.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CgroupLimits {
+    /// Memory in bytes above which neard is throttled and reclaimed heavily
+    pub memory_high: Option<u64>,
+    /// Memory in bytes above which the OOM killer is invoked for neard
+    pub memory_max: Option<u64>,
+    /// Share of cpu time relative to kneard, from 1 to 10000
+    pub cpu_weight: Option<u16>,
+    /// Share of io relative to kneard, from 1 to 10000
+    pub io_weight: Option<u16>,
+}
+
+fn mib_to_bytes(mib: u64) -> u64 {
+    mib * 1024 * 1024
+}
+
+impl CgroupLimits {
+    /// Returns the limits configured in the settings
+    pub fn from_settings(settings: &Settings) -> Self {
+        Self {
+            memory_high: settings.neard_memory_high.map(mib_to_bytes),
+            memory_max: settings.neard_memory_max.map(mib_to_bytes),
+            cpu_weight: settings.neard_cpu_weight,
+            io_weight: settings.neard_io_weight,
+        }
+    }
+}
+
+/// The cgroup neard runs in
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NeardCgroup {
+    path: PathBuf,
+}
+
+/// Returns the cgroup v2 path of the current process, i.e. `/system.slice/kuutamod.service`
+fn own_cgroup() -> Result<String> {
+    let content =
+        fs::read_to_string("/proc/self/cgroup").context("cannot read /proc/self/cgroup")?;
+    match content.lines().find_map(|l| l.strip_prefix("0::")) {
+        Some(path) => Ok(path.to_string()),
+        None => bail!("no cgroup v2 hierarchy, only cgroup v1 is mounted"),
+    }
+}
+
+fn write_file(path: &Path, content: &str) -> Result<()> {
+    fs::write(path, content)
+        .with_context(|| format!("cannot write '{content}' to {}", path.display()))
+}
+
+fn limit_value(limit: Option<impl ToString>) -> String {
+    limit.map_or_else(|| "max".to_string(), |l| l.to_string())
+}
+
+impl NeardCgroup {
+    /// Creates the cgroup of neard next to the cgroup of kneard. Afterwards every neard
+    /// started by kneard runs in it. Without cgroup v2, or if systemd does not delegate the
+    /// cgroup of the service to kneard (`Delegate=yes` and `DelegateSubgroup=kneard`), neard
+    /// runs in the cgroup of kneard.
+    pub fn setup(limits: &CgroupLimits) {
+        let res = own_cgroup().and_then(|own| Self::create(Path::new(CGROUP_MOUNT), &own, limits));
+        let cgroup = match res {
+            Ok(cgroup) => {
+                info!("Run neard in cgroup {}", cgroup.path.display());
+                Some(cgroup)
+            }
+            Err(e) => {
+                warn!("Run neard without its own cgroup: {:#}", e);
+                None
+            }
+        };
+        match NEARD_CGROUP.lock() {
+            Ok(mut lock) => *lock = cgroup,
+            Err(e) => warn!("Cannot take neard cgroup lock: {}", e),
+        }
+    }
+
+    /// Returns the cgroup set up for neard, if there is one
+    pub fn get() -> Option<Self> {
+        NEARD_CGROUP.lock().ok().and_then(|lock| lock.clone())
+    }
+
+    /// Creates `<service>/neard` for neard, if kneard runs in `<service>/kneard`. A cgroup
+    /// that enables controllers for its children must not contain processes itself, so
+    /// systemd has to start kneard and `ExecReload` in that subgroup.
+    fn create(mount: &Path, own: &str, limits: &CgroupLimits) -> Result<Self> {
+        let own = Path::new(own);
+        let service = match (own.file_name(), own.parent()) {
+            (Some(name), Some(service)) if name == "kneard" && service != Path::new("/") => {
+                service
+            }
+            _ => bail!(
+                "kneard runs in cgroup {} instead of the subgroup kneard of its service (DelegateSubgroup=kneard)",
+                own.display()
+            ),
+        };
+        let parent = mount.join(service.strip_prefix("/").unwrap_or(service));
+        let available = fs::read_to_string(parent.join("cgroup.controllers"))
+            .with_context(|| format!("{} is not a cgroup v2", parent.display()))?;
+
+        let neard = parent.join("neard");
+        fs::create_dir_all(&neard)
+            .with_context(|| format!("cannot create cgroup {}", neard.display()))?;
+
+        let controllers = CONTROLLERS
+            .iter()
+            .filter(|c| available.split_whitespace().any(|a| a == **c))
+            .map(|c| format!("+{c}"))
+            .collect::<Vec<_>>();
+        if controllers.len() < CONTROLLERS.len() {
+            warn!(
+                "Only {} of the cgroup controllers {} are available for neard",
+                controllers.join(" "),
+                CONTROLLERS.join(" ")
+            );
+        }
+        write_file(
+            &parent.join("cgroup.subtree_control"),
+            &controllers.join(" "),
+        )?;
+
+        let cgroup = Self { path: neard };
+        cgroup.apply(limits, &available)?;
+        Ok(cgroup)
+    }
+
+    fn apply(&self, limits: &CgroupLimits, available: &str) -> Result<()> {
+        let has = |controller| available.split_whitespace().any(|a| a == controller);
+        if has("memory") {
+            write_file(
+                &self.path.join("memory.high"),
+                &limit_value(limits.memory_high),
+            )?;
+            write_file(
+                &self.path.join("memory.max"),
+                &limit_value(limits.memory_max),
+            )?;
+        } else if limits.memory_high.is_some() || limits.memory_max.is_some() {
+            warn!("Cannot limit the memory of neard without the memory controller");
+        }
+        if has("cpu") {
+            let weight = limits.cpu_weight.unwrap_or(100);
+            write_file(&self.path.join("cpu.weight"), &weight.to_string())?;
+        } else if limits.cpu_weight.is_some() {
+            warn!("Cannot set the cpu weight of neard without the cpu controller");
+        }
+        if has("io") {
+            let weight = limits.io_weight.unwrap_or(100);
+            write_file(&self.path.join("io.weight"), &format!("default {weight}"))?;
+        } else if limits.io_weight.is_some() {
+            warn!("Cannot set the io weight of neard without the io controller");
+        }
+        Ok(())
+    }
+
+    /// Path of the cgroup in the cgroup filesystem
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Opens `cgroup.procs` of the cgroup, so that a child can move itself into the cgroup
+    /// with [`join_cgroup`] before it executes neard
+    pub fn open_procs(&self) -> Result<File> {
+        let path = self.path.join("cgroup.procs");
+        OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .with_context(|| format!("cannot open {}", path.display()))
+    }
+
+    /// Returns true if the process with `pid` runs in the cgroup
+    pub fn contains(&self, pid: Pid) -> bool {
+        fs::read_to_string(format!("/proc/{pid}/cgroup"))
+            .ok()
+            .and_then(|c| {
+                c.lines()
+                    .find_map(|l| l.strip_prefix("0::"))
+                    .map(|p| Path::new(CGROUP_MOUNT).join(p.trim_start_matches('/')))
+            })
+            .is_some_and(|p| p == self.path)
+    }
+
+    /// Exports the usage of the cgroup as metrics. Controllers that are not enabled are skipped.
+    pub fn export(&self) {
+        let read = |file| fs::read_to_string(self.path.join(file)).ok();
+        if let Some(current) = read("memory.current").and_then(|c| c.trim().parse().ok()) {
+            CGROUP_MEMORY.set(current);
+        }
+        if let Some(events) = read("memory.events") {
+            for (key, value) in parse_flat_keyed(&events) {
+                if ["high", "max", "oom_kill"].contains(&key) {
+                    CGROUP_MEMORY_EVENTS
+                        .with_label_values(&[key])
+                        .set(value as i64);
+                }
+            }
+        }
+        if let Some(stat) = read("cpu.stat") {
+            if let Some((_, usec)) = parse_flat_keyed(&stat).find(|(k, _)| *k == "usage_usec") {
+                CGROUP_CPU.set(usec as f64 / 1_000_000.0);
+            }
+        }
+        if let Some(stat) = read("io.stat") {
+            let (read, written) = parse_io_stat(&stat);
+            CGROUP_IO.with_label_values(&["read"]).set(read as i64);
+            CGROUP_IO.with_label_values(&["write"]).set(written as i64);
+        }
+    }
+}
+
+/// Moves the calling process and all its threads into the cgroup of `procs`, which was
+/// opened with [`NeardCgroup::open_procs`]. It only calls write(2), so it is
+/// async-signal-safe and can run between fork and exec.
+pub fn join_cgroup(procs: RawFd) -> io::Result<()> {
+    write(procs, b"0")?;
+    Ok(())
+}
+
+/// Parses cgroup files with lines of `<key> <value>`
+fn parse_flat_keyed(content: &str) -> impl Iterator<Item = (&str, u64)> {
+    content.lines().filter_map(|line| {
+        let (key, value) = line.split_once(' ')?;
+        Some((key, value.trim().parse().ok()?))
+    })
+}
+
+/// Sums up the bytes read and written on all devices in `io.stat`
+fn parse_io_stat(content: &str) -> (u64, u64) {
+    let mut read = 0;
+    let mut written = 0;
+    for field in content.split_whitespace() {
+        if let Some((key, value)) = field.split_once('=') {
+            match (key, value.parse::<u64>()) {
+                ("rbytes", Ok(v)) => read += v,
+                ("wbytes", Ok(v)) => written += v,
+                _ => {}
+            }
+        }
+    }
+    (read, written)
+}
+
+/// Exports the usage of neard's cgroup, if neard runs in its own cgroup
+pub fn export_neard_cgroup() {
+    if let Some(cgroup) = NeardCgroup::get() {
+        cgroup.export();
+    }
+}
+
+#[test]
+fn test_neard_cgroup() {
+    use std::os::fd::AsRawFd;
+
+    let mount = tempfile::tempdir().unwrap();
+    let own = mount.path().join("system.slice/kuutamod.service");
+    fs::create_dir_all(&own).unwrap();
+    let limits = CgroupLimits {
+        memory_high: Some(mib_to_bytes(1024)),
+        memory_max: None,
+        cpu_weight: Some(50),
+        io_weight: None,
+    };
+
+    let create = |own| NeardCgroup::create(mount.path(), own, &limits);
+
+    // not delegated to us
+    assert!(create("/system.slice/kuutamod.service/kneard").is_err());
+    // not in the subgroup of the service
+    assert!(create("/system.slice/kuutamod.service").is_err());
+    assert!(create("/kneard").is_err());
+    assert!(create("/").is_err());
+
+    fs::write(
+        own.join("cgroup.controllers"),
+        "cpuset cpu io memory pids\n",
+    )
+    .unwrap();
+    let cgroup = create("/system.slice/kuutamod.service/kneard").unwrap();
+    assert_eq!(cgroup.path(), own.join("neard"));
+    let read = |file: &str| fs::read_to_string(own.join(file)).unwrap();
+    assert_eq!(read("cgroup.subtree_control"), "+memory +cpu +io");
+    assert_eq!(read("neard/memory.high"), "1073741824");
+    assert_eq!(read("neard/memory.max"), "max");
+    assert_eq!(read("neard/cpu.weight"), "50");
+    assert_eq!(read("neard/io.weight"), "default 100");
+
+    // i.e. when set up again in the same process
+    assert_eq!(
+        create("/system.slice/kuutamod.service/kneard").unwrap(),
+        cgroup
+    );
+
+    fs::write(own.join("neard/cgroup.procs"), "").unwrap();
+    let procs = cgroup.open_procs().unwrap();
+    join_cgroup(procs.as_raw_fd()).unwrap();
+    assert_eq!(read("neard/cgroup.procs"), "0");
+    assert!(!cgroup.contains(Pid::this()));
+
+    let events = "low 0\nhigh 12\nmax 3\noom 1\noom_kill 1\n";
+    assert_eq!(
+        parse_flat_keyed(events).find(|(k, _)| *k == "high"),
+        Some(("high", 12))
+    );
+    let io = "8:0 rbytes=100 wbytes=200 rios=1 wios=2 dbytes=0 dios=0\n259:0 rbytes=1000 wbytes=2000 rios=3 wios=4\n";
+    assert_eq!(parse_io_stat(io), (1100, 2200));
+}
@@ -0,0 +1,51 @@
+//! Wall clock of the supervisor, so that tests can control the time
+
+use std::fmt;
+use std::time::SystemTime;
+use tokio::time::Instant;
+
+/// Source of the current wall clock time
+pub trait Clock: fmt::Debug + Send + Sync {
+    /// Returns the current time
+    fn now(&self) -> SystemTime;
+}
+
+/// Clock of the operating system
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// Clock that advances with the clock of tokio, i.e. it stands still while
+/// the time of the runtime is paused
+#[derive(Debug, Clone, Copy)]
+pub struct TokioClock {
+    start: SystemTime,
+    started_at: Instant,
+}
+
+impl TokioClock {
+    /// Returns a clock that starts at the current system time
+    pub fn new() -> Self {
+        Self {
+            start: SystemTime::now(),
+            started_at: Instant::now(),
+        }
+    }
+}
+
+impl Default for TokioClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for TokioClock {
+    fn now(&self) -> SystemTime {
+        self.start + self.started_at.elapsed()
+    }
+}
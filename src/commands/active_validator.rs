@@ -1,10 +1,10 @@
 use anyhow::{Context, Result};
 use log::info;
 use serde::{Deserialize, Serialize};
-use std::fs;
-use std::path::PathBuf;
 
-use crate::{consul_client::ConsulClient, leader_protocol::consul_leader_key};
+use crate::drain::is_drained;
+use crate::leader_protocol::{leader_election, leader_key};
+use crate::settings::Settings;
 
 /// A consul session according to https://www.consul.io/api-docs/session
 // FIXME The fields are here inherited from consul and we probably want to change them a bit...
@@ -16,57 +16,34 @@ pub struct Validator {
     /// Local name without domain
     #[serde(rename = "Name")]
     pub name: String,
+    /// Whether the validator is drained and gives up its leadership in the next
+    /// maintenance window. Only known on the node of the validator itself.
+    #[serde(rename = "Drained", default)]
+    pub drained: bool,
 }
 
-pub async fn active_validator(
-    account_id: &str,
-    consul_url: &str,
-    consul_token_file: &Option<PathBuf>,
-) -> Result<Option<Validator>> {
-    let token = match consul_token_file {
-        Some(ref file) => {
-            let s = fs::read_to_string(file)
-                .with_context(|| format!("cannot read consul token file {}", file.display()))?;
-            Some(s.trim_end().to_string())
-        }
-        None => None,
-    };
-    let client = ConsulClient::new(consul_url, token.as_deref())
-        .context("Failed to create consul client")?;
+pub async fn active_validator(settings: &Settings) -> Result<Option<Validator>> {
+    // re-read the token, it might have been rotated since startup
+    let mut settings = settings.clone();
+    settings.read_tokens()?;
+    let election = leader_election(&settings)?;
 
-    let res = client
-        .get(&consul_leader_key(account_id))
-        .await
-        .context("Failed to get leader key from consul")?;
-    let value = match res {
-        None => {
-            info!("No leader found for {}", account_id);
-            return Ok(None);
-        }
-        Some(session) => session,
-    };
-    let uuid = match value.session {
-        None => {
-            info!("Last leader session was expired for {}", account_id);
-            return Ok(None);
-        }
-        Some(val) => val,
-    };
-    let res = client
-        .get_session(&uuid)
+    let res = election
+        .leader(&leader_key(&settings.account_id))
         .await
-        .context("Failed to get leader key from consul")?;
-
-    let session = match res {
+        .context("Failed to get leader from election backend")?;
+    let leader = match res {
         None => {
-            info!("Last leader session was expired for {}", account_id);
+            info!("No leader found for {}", settings.account_id);
             return Ok(None);
         }
-        Some(session) => session,
+        Some(leader) => leader,
     };
 
+    let drained = leader.name == settings.node_id && is_drained(&settings.neard_home);
     Ok(Some(Validator {
-        node: session.node().to_string(),
-        name: session.name().to_string(),
+        node: leader.node,
+        name: leader.name,
+        drained,
     }))
 }
@@ -1,11 +1,20 @@
+use std::io::Write;
 use std::path::{Path, PathBuf};
 
 use anyhow::{bail, Context, Result};
-use hyper::{Body, Client, Method, Request, Response};
+use hyper::{body::HttpBody, Body, Client, Method, Request, Response};
 use hyperlocal::{UnixClientExt, Uri};
 use serde::de::DeserializeOwned;
 
-use super::{active_validator::Validator, ApiResponse, ScheduleRestartOperation};
+use crate::crash_report::CrashRecord;
+use crate::journal::TransitionEntry;
+use crate::maintenance::MaintenancePlan;
+use crate::validator_stats::ValidatorStats;
+
+use super::{
+    active_validator::Validator, ApiResponse, DrainOperation, HandoverOperation,
+    ScheduleRestartOperation, StageNeardOperation,
+};
 
 async fn parse_response<T: DeserializeOwned>(req: Response<Body>) -> Result<T> {
     let body_bytes = hyper::body::to_bytes(req.into_body()).await?;
@@ -52,12 +61,151 @@ impl CommandClient {
         parse_response(res).await.context("cannot parse validator")
     }
 
+    /// Get state transitions of the supervisor, oldest first
+    pub async fn transitions(&self) -> Result<Vec<TransitionEntry>> {
+        let url = Uri::new(&self.socket_path, "/transitions").into();
+        let res = Client::unix().get(url).await.with_context(|| {
+            format!(
+                "failed to connect to kneard via {}",
+                self.socket_path.display()
+            )
+        })?;
+        let code = res.status();
+        if !code.is_success() {
+            let resp: ApiResponse = parse_response(res)
+                .await
+                .context("failed to parse response")?;
+            bail!(
+                "Request to get state transitions failed: {} (status: {})",
+                resp.message,
+                resp.status
+            )
+        };
+        parse_response(res)
+            .await
+            .context("cannot parse state transitions")
+    }
+
+    /// Get the upcoming maintenance windows of our validator
+    pub async fn maintenance_windows(&self) -> Result<MaintenancePlan> {
+        let url = Uri::new(&self.socket_path, "/maintenance_windows").into();
+        let res = Client::unix().get(url).await.with_context(|| {
+            format!(
+                "failed to connect to kneard via {}",
+                self.socket_path.display()
+            )
+        })?;
+        let code = res.status();
+        if !code.is_success() {
+            let resp: ApiResponse = parse_response(res)
+                .await
+                .context("failed to parse response")?;
+            bail!(
+                "Request to get maintenance windows failed: {} (status: {})",
+                resp.message,
+                resp.status
+            )
+        };
+        parse_response(res)
+            .await
+            .context("cannot parse maintenance windows")
+    }
+
+    /// Get blocks and chunks our validator produced in the current epoch,
+    /// None if it does not validate in this epoch
+    pub async fn validator_stats(&self) -> Result<Option<ValidatorStats>> {
+        let url = Uri::new(&self.socket_path, "/validator_stats").into();
+        let res = Client::unix().get(url).await.with_context(|| {
+            format!(
+                "failed to connect to kneard via {}",
+                self.socket_path.display()
+            )
+        })?;
+        let code = res.status();
+        if !code.is_success() {
+            let resp: ApiResponse = parse_response(res)
+                .await
+                .context("failed to parse response")?;
+            bail!(
+                "Request to get validator stats failed: {} (status: {})",
+                resp.message,
+                resp.status
+            )
+        };
+        parse_response(res)
+            .await
+            .context("cannot parse validator stats")
+    }
+
+    /// Get recorded exits of neard, oldest first
+    pub async fn crashes(&self) -> Result<Vec<CrashRecord>> {
+        let url = Uri::new(&self.socket_path, "/crashes").into();
+        let res = Client::unix().get(url).await.with_context(|| {
+            format!(
+                "failed to connect to kneard via {}",
+                self.socket_path.display()
+            )
+        })?;
+        let code = res.status();
+        if !code.is_success() {
+            let resp: ApiResponse = parse_response(res)
+                .await
+                .context("failed to parse response")?;
+            bail!(
+                "Request to get crash records failed: {} (status: {})",
+                resp.message,
+                resp.status
+            )
+        };
+        parse_response(res)
+            .await
+            .context("cannot parse crash records")
+    }
+
+    /// Writes the last `lines` lines of neard output to `out`. If `follow` is set,
+    /// new lines are written until kneard closes the connection.
+    pub async fn neard_logs(
+        &self,
+        lines: usize,
+        follow: bool,
+        out: &mut (dyn Write + Send),
+    ) -> Result<()> {
+        let path = format!("/neard/logs?lines={}&follow={}", lines, u8::from(follow));
+        let url = Uri::new(&self.socket_path, &path).into();
+        let res = Client::unix().get(url).await.with_context(|| {
+            format!(
+                "failed to connect to kneard via {}",
+                self.socket_path.display()
+            )
+        })?;
+        let code = res.status();
+        if !code.is_success() {
+            let resp: ApiResponse = parse_response(res)
+                .await
+                .context("failed to parse response")?;
+            bail!(
+                "Request to get neard logs failed: {} (status: {})",
+                resp.message,
+                resp.status
+            )
+        };
+        let mut body = res.into_body();
+        while let Some(chunk) = body.data().await {
+            let chunk = chunk.context("failed to read neard logs")?;
+            out.write_all(&chunk)
+                .context("failed to write neard logs")?;
+            out.flush().context("failed to write neard logs")?;
+        }
+        Ok(())
+    }
+
     /// Initiate or cancel the schedule of restart
     pub async fn schedule_restart(
         &self,
         minimum_length: Option<u64>,
         schedule_at: Option<u64>,
         cancel: bool,
+        force: bool,
     ) -> Result<()> {
         let url = hyperlocal::Uri::new(&self.socket_path, "/schedule_restart");
 
@@ -65,6 +213,7 @@ impl CommandClient {
             minimum_length,
             schedule_at,
             cancel,
+            force,
         })?;
         let req = Request::builder()
             .method(Method::POST)
@@ -96,6 +245,114 @@ impl CommandClient {
         Ok(())
     }
 
+    /// Hand over the leadership to the given node
+    pub async fn handover(&self, to: &str) -> Result<()> {
+        let url = hyperlocal::Uri::new(&self.socket_path, "/handover");
+
+        let body = serde_json::to_string(&HandoverOperation { to: to.to_string() })?;
+        let req = Request::builder()
+            .method(Method::POST)
+            .uri(url)
+            .body(Body::from(body))
+            .context("failed to build request")?;
+
+        let res = Client::unix().request(req).await.with_context(|| {
+            format!(
+                "failed to connect to kneard via {}",
+                self.socket_path.display()
+            )
+        })?;
+
+        let code = res.status();
+        let v: ApiResponse = parse_response(res)
+            .await
+            .context("failed to parse response")?;
+
+        if code.is_success() {
+            println!("{}", v.message);
+        } else {
+            bail!(
+                "Request to hand over leadership failed: {} (status: {})",
+                v.message,
+                v.status
+            )
+        }
+        Ok(())
+    }
+
+    /// Drain or undrain the node
+    pub async fn drain(&self, drained: bool) -> Result<()> {
+        let url = hyperlocal::Uri::new(&self.socket_path, "/drain");
+
+        let body = serde_json::to_string(&DrainOperation { drained })?;
+        let req = Request::builder()
+            .method(Method::POST)
+            .uri(url)
+            .body(Body::from(body))
+            .context("failed to build request")?;
+
+        let res = Client::unix().request(req).await.with_context(|| {
+            format!(
+                "failed to connect to kneard via {}",
+                self.socket_path.display()
+            )
+        })?;
+
+        let code = res.status();
+        let v: ApiResponse = parse_response(res)
+            .await
+            .context("failed to parse response")?;
+
+        if code.is_success() {
+            println!("{}", v.message);
+        } else {
+            bail!(
+                "Request to change drain state failed: {} (status: {})",
+                v.message,
+                v.status
+            )
+        }
+        Ok(())
+    }
+
+    /// Switch to the given neard binary, or back to the previous one if None
+    pub async fn stage_neard(&self, binary: Option<&Path>, force: bool) -> Result<()> {
+        let url = hyperlocal::Uri::new(&self.socket_path, "/stage_neard");
+
+        let body = serde_json::to_string(&StageNeardOperation {
+            binary: binary.map(Path::to_path_buf),
+            force,
+        })?;
+        let req = Request::builder()
+            .method(Method::POST)
+            .uri(url)
+            .body(Body::from(body))
+            .context("failed to build request")?;
+
+        let res = Client::unix().request(req).await.with_context(|| {
+            format!(
+                "failed to connect to kneard via {}",
+                self.socket_path.display()
+            )
+        })?;
+
+        let code = res.status();
+        let v: ApiResponse = parse_response(res)
+            .await
+            .context("failed to parse response")?;
+
+        if code.is_success() {
+            println!("{}", v.message);
+        } else {
+            bail!(
+                "Request to stage neard failed: {} (status: {})",
+                v.message,
+                v.status
+            )
+        }
+        Ok(())
+    }
+
     /// Get maintenance status
     pub async fn maintenance_status(&self) -> Result<String> {
         let url = Uri::new(&self.socket_path, "/maintenance_status").into();
@@ -1,5 +1,7 @@
 //! Command to kneard
 
+use std::path::PathBuf;
+
 /// Command to kneard
 #[allow(clippy::derive_partial_eq_without_eq)]
 #[derive(clap::Subcommand, PartialEq, Debug, Clone)]
@@ -10,6 +12,9 @@ pub enum Command {
     /// Show the status of maintenance restart
     MaintenanceStatus,
 
+    /// Show the maintenance windows of the current and next epoch
+    MaintenanceWindows,
+
     /// Show the current voted validator
     ActiveValidator,
 
@@ -18,13 +23,38 @@ pub enum Command {
 
     /// Show system info
     SystemInfo(SystemInfoArgs),
+
+    /// Show the state transitions of kneard
+    History,
+
+    /// Hand over the leadership to another node in the next maintenance window
+    Handover(HandoverArgs),
+
+    /// Show recorded exits of neard
+    Crashes,
+
+    /// Show the latest output of neard
+    NeardLogs(NeardLogsArgs),
+
+    /// Show blocks and chunks our validator produced in the current epoch
+    ValidatorStats,
+
+    /// Never become validator on this node and give up the leadership in the next maintenance window
+    Drain,
+
+    /// Allow this node to become validator again
+    Undrain,
+
+    /// Switch to another neard binary with a restart in the next maintenance window
+    StageNeard(StageNeardArgs),
 }
 
 /// Arguments for restart command
 #[derive(clap::Args, PartialEq, Debug, Clone)]
 pub struct RestartArgs {
     /// Specify the minimum length in blocks for the maintenance shutdown, if not provided,
-    /// neard will try to shutdown in the longest maintenance window in the current epoch
+    /// neard will try to shutdown in the longest maintenance window in the current epoch.
+    /// Voters shut down right away, unless this or --schedule-at is given.
     pub minimum_length: Option<u64>,
 
     /// Specify the block height to shutdown at, and will not check on it in maintenance window or
@@ -36,6 +66,11 @@ pub struct RestartArgs {
     #[arg(long)]
     pub cancel: bool,
 
+    /// Schedule even if the maintenance window is shorter than the expected db migration of neard,
+    /// the duration of the migration is unknown or --schedule-at is given
+    #[arg(long)]
+    pub force: bool,
+
     /// Cli will wait for restart
     #[clap(long)]
     pub wait: bool,
@@ -56,3 +91,39 @@ pub struct SystemInfoArgs {
     #[clap(long)]
     pub inline: bool,
 }
+
+/// Arguments for handover command
+#[derive(clap::Args, PartialEq, Debug, Clone)]
+pub struct HandoverArgs {
+    /// Node id of the voting node that should become validator
+    #[arg(long)]
+    pub to: String,
+}
+
+/// Arguments for stage-neard command
+#[derive(clap::Args, PartialEq, Debug, Clone)]
+pub struct StageNeardArgs {
+    /// Path of the neard binary to switch to
+    #[arg(required_unless_present = "rollback")]
+    pub path: Option<PathBuf>,
+
+    /// Switch back to the neard binary used before the last switch
+    #[arg(long, conflicts_with = "path")]
+    pub rollback: bool,
+
+    /// Switch even if the maintenance window is shorter than the expected db migration of neard
+    #[arg(long)]
+    pub force: bool,
+}
+
+/// Arguments for neard-logs command
+#[derive(clap::Args, PartialEq, Debug, Clone)]
+pub struct NeardLogsArgs {
+    /// Number of lines to show
+    #[arg(short = 'n', long, default_value_t = 100)]
+    pub lines: usize,
+
+    /// Keep showing new output of neard
+    #[arg(short, long)]
+    pub follow: bool,
+}
@@ -8,6 +8,7 @@ mod server;
 pub mod system_info;
 
 use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
 
 use clap::Parser;
 pub use client::CommandClient;
@@ -27,6 +28,33 @@ struct ScheduleRestartOperation {
     /// not.
     #[arg(long)]
     schedule_at: Option<u64>,
+
+    /// Schedule even if the maintenance window is shorter than the expected db migration,
+    /// its duration is unknown or `schedule_at` is given
+    #[arg(long)]
+    #[serde(default)]
+    force: bool,
+}
+
+#[derive(PartialEq, Serialize, Deserialize, Debug, Clone)]
+struct HandoverOperation {
+    /// Node id of the node to hand over the leadership to
+    to: String,
+}
+
+#[derive(PartialEq, Serialize, Deserialize, Debug, Clone)]
+struct DrainOperation {
+    /// Whether the node should be drained or undrained
+    drained: bool,
+}
+
+#[derive(PartialEq, Serialize, Deserialize, Debug, Clone)]
+struct StageNeardOperation {
+    /// Path of the neard binary to switch to, None to switch back to the previous binary
+    binary: Option<PathBuf>,
+    /// Schedule even if the maintenance window is shorter than the expected db migration
+    #[serde(default)]
+    force: bool,
 }
 
 #[derive(PartialEq, Serialize, Deserialize, Debug, Clone)]
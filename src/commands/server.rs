@@ -1,12 +1,8 @@
-use std::{
-    fmt::Display,
-    fs,
-    path::PathBuf,
-    sync::{atomic::Ordering, Arc},
-};
+use std::{fmt::Display, fs, path::PathBuf, sync::Arc};
 
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 use hyper::{
+    body::Bytes,
     header::{HeaderValue, CONTENT_TYPE},
     service::{make_service_fn, service_fn},
     Body, Method, Request, Response, Server, StatusCode,
@@ -15,11 +11,25 @@ use hyperlocal::UnixServerExt;
 use log::warn;
 use serde::{de::DeserializeOwned, Serialize};
 use serde_json::json;
+use tokio::sync::broadcast::error::RecvError;
 use tokio::sync::mpsc::{self, Sender};
 
-use crate::{ipc, near_client::NeardClient, settings::Settings, supervisor::SHUTDOWN_WITH_NEARD};
+use crate::{
+    crash_report::crash_journal,
+    ipc,
+    journal::Journal,
+    maintenance::MaintenancePlan,
+    near_client::NeardClient,
+    neard_logs::neard_logs,
+    schedule::{Schedule, ScheduledOperation},
+    settings::Settings,
+    validator_stats::ValidatorStats,
+};
 
-use super::{active_validator::active_validator, ScheduleRestartOperation};
+use super::{
+    active_validator::active_validator, DrainOperation, HandoverOperation,
+    ScheduleRestartOperation, StageNeardOperation,
+};
 
 fn server_error<T: Display>(msg: T) -> Response<Body>
 where
@@ -91,16 +101,48 @@ fn not_found() -> Response<Body> {
     resp
 }
 
+/// How many lines of neard logs are returned by default
+const DEFAULT_LOG_LINES: usize = 100;
+
+/// Returns the requested number of lines and whether to follow the neard logs
+fn parse_log_query(query: Option<&str>) -> Result<(usize, bool)> {
+    let mut lines = DEFAULT_LOG_LINES;
+    let mut follow = false;
+    for pair in query
+        .unwrap_or_default()
+        .split('&')
+        .filter(|p| !p.is_empty())
+    {
+        let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+        match key {
+            "lines" => {
+                lines = value
+                    .parse()
+                    .with_context(|| format!("invalid number of lines: {value}"))?
+            }
+            "follow" => follow = matches!(value, "1" | "true"),
+            _ => bail!("unknown parameter: {}", key),
+        }
+    }
+    Ok((lines, follow))
+}
+
 /// A unix-socket based http server to provide remote control
 struct CommandServer {
-    account_id: String,
-    consul_url: String,
-    consul_token_path: Option<PathBuf>,
+    settings: Settings,
     control_socket: PathBuf,
     supervisor_request_chan: Sender<ipc::Request>,
     near_client: NeardClient,
 }
 
+/// Warns about the db migration after a scheduled operation
+fn migration_note(operation: &ScheduledOperation) -> String {
+    match operation.migration {
+        Some(ref migration) => format!(", neard will run a {migration} afterwards"),
+        None => String::new(),
+    }
+}
+
 fn json_response<T: Serialize>(obj: T) -> Response<Body> {
     match serde_json::to_vec(&obj) {
         Err(e) => server_error(e),
@@ -121,9 +163,7 @@ impl CommandServer {
     /// Creates a new instance
     pub fn new(settings: &Settings, supervisor_request_chan: Sender<ipc::Request>) -> Result<Self> {
         Ok(CommandServer {
-            account_id: settings.account_id.to_string(),
-            consul_url: settings.consul_url.to_string(),
-            consul_token_path: settings.consul_token_file.to_owned(),
+            settings: settings.clone(),
             control_socket: settings.control_socket.to_owned(),
             supervisor_request_chan,
             near_client: NeardClient::new(&format!(
@@ -140,8 +180,16 @@ impl CommandServer {
             ))),
             (&Method::GET, "/active_validator") => self.handle_active_validator().await,
             (&Method::POST, "/schedule_restart") => self.handle_schedule_restart(req).await,
+            (&Method::POST, "/handover") => self.handle_handover(req).await,
+            (&Method::POST, "/drain") => self.handle_drain(req).await,
+            (&Method::POST, "/stage_neard") => self.handle_stage_neard(req).await,
             (&Method::GET, "/maintenance_status") => self.handle_maintenance_status().await,
+            (&Method::GET, "/maintenance_windows") => self.handle_maintenance_windows().await,
             (&Method::GET, "/rpc_status") => self.handle_rpc_status().await,
+            (&Method::GET, "/transitions") => self.handle_transitions().await,
+            (&Method::GET, "/crashes") => self.handle_crashes().await,
+            (&Method::GET, "/neard/logs") => self.handle_neard_logs(&req).await,
+            (&Method::GET, "/validator_stats") => self.handle_validator_stats().await,
             _ => Ok(not_found()),
         }
     }
@@ -153,6 +201,7 @@ impl CommandServer {
             args.minimum_length,
             args.schedule_at,
             args.cancel,
+            args.force,
             tx,
         );
 
@@ -163,17 +212,22 @@ impl CommandServer {
         }
 
         match rx.recv().await {
-            Some(r) => match (r.shutdown_at_blockheight, args.cancel) {
-                (Ok(Some(height)), false) => Ok(Response::new(Body::from(format!(
-                    "{{\"status\": 200, \"message\": \"will shutdown at block height: {height}\"}}",
-                )))),
+            Some(r) => match (r.scheduled, args.cancel) {
+                (Ok(Some(operation)), false) => Ok(json_response(json!({
+                    "status": 200,
+                    "message": format!(
+                        "will {} at block height: {} (scheduled while {}){}",
+                        operation.mode, operation.height, operation.origin, migration_note(&operation)
+                    ),
+                    "operation": operation,
+                }))),
                 (Ok(None), true) => Ok(Response::new(Body::from(
                     r#"{"status": 200, "message": "shutdown cancelled"}"#,
                 ))),
                 (Ok(None), false) => Ok(Response::new(Body::from(
                     r#"{"status": 200, "message": "is shutting down at current block"}"#,
                 ))),
-                (Err(e), false) => Ok(server_error(format!("fail to schedule restart: {e:}"))),
+                (Err(e), false) => Ok(server_error(format!("fail to schedule restart: {e:#}"))),
                 (Err(e), true) => Ok(server_error(format!("fail to cancel restart: {e:}"))),
                 (Ok(Some(_)), true) => Ok(server_error(
                     "unexpedted response for cancel restart".to_string(),
@@ -183,36 +237,122 @@ impl CommandServer {
         }
     }
 
+    async fn handle_handover(&self, req: Request<Body>) -> hyper::Result<Response<Body>> {
+        let (tx, mut rx) = mpsc::channel(1);
+        let args: HandoverOperation = ok_or_500!(json_request(req).await);
+        let req = ipc::Request::Handover(args.to.clone(), tx);
+
+        if let Err(e) = self.supervisor_request_chan.send(req).await {
+            return Ok(server_error(format!(
+                "channel to supervisor was already closed before sending: {e}"
+            )));
+        }
+
+        match rx.recv().await {
+            Some(r) => match r.result {
+                Ok(()) => Ok(json_response(json!({
+                    "status": 200,
+                    "message": format!("will hand over to {} in the next maintenance window", args.to),
+                }))),
+                Err(e) => Ok(server_error(format!("fail to hand over: {e:}"))),
+            },
+            None => Ok(server_error("channel to supervisor was closed")),
+        }
+    }
+
+    async fn handle_drain(&self, req: Request<Body>) -> hyper::Result<Response<Body>> {
+        let (tx, mut rx) = mpsc::channel(1);
+        let args: DrainOperation = ok_or_500!(json_request(req).await);
+        let req = ipc::Request::Drain(args.drained, tx);
+
+        if let Err(e) = self.supervisor_request_chan.send(req).await {
+            return Ok(server_error(format!(
+                "channel to supervisor was already closed before sending: {e}"
+            )));
+        }
+
+        match rx.recv().await {
+            Some(r) => match (r.result, args.drained) {
+                (Ok(()), true) => Ok(json_response(json!({
+                    "status": 200,
+                    "message": "drained, this node will not become validator and gives up its leadership in the next maintenance window",
+                }))),
+                (Ok(()), false) => Ok(json_response(json!({
+                    "status": 200,
+                    "message": "undrained, this node may become validator again",
+                }))),
+                (Err(e), _) => Ok(server_error(format!("fail to change drain state: {e:}"))),
+            },
+            None => Ok(server_error("channel to supervisor was closed")),
+        }
+    }
+
+    async fn handle_stage_neard(&self, req: Request<Body>) -> hyper::Result<Response<Body>> {
+        let (tx, mut rx) = mpsc::channel(1);
+        let args: StageNeardOperation = ok_or_500!(json_request(req).await);
+        let req = ipc::Request::StageNeard(args.binary, args.force, tx);
+
+        if let Err(e) = self.supervisor_request_chan.send(req).await {
+            return Ok(server_error(format!(
+                "channel to supervisor was already closed before sending: {e}"
+            )));
+        }
+
+        match rx.recv().await {
+            Some(r) => match r.result {
+                Ok((version, Some(operation))) => Ok(json_response(json!({
+                    "status": 200,
+                    "message": format!(
+                        "staged neard {version}, will restart with it at block height: {}{}",
+                        operation.height, migration_note(&operation)
+                    ),
+                    "operation": operation,
+                }))),
+                Ok((version, None)) => Ok(json_response(json!({
+                    "status": 200,
+                    "message": format!("staged neard {version}, restarting with it now"),
+                }))),
+                Err(e) => Ok(server_error(format!("fail to stage neard: {e:#}"))),
+            },
+            None => Ok(server_error("channel to supervisor was closed")),
+        }
+    }
+
     async fn handle_maintenance_status(&self) -> hyper::Result<Response<Body>> {
-        let (metrics, final_block) =
-            tokio::join!(self.near_client.metrics(), self.near_client.final_block());
-
-        let resp = match (
-            metrics.ok().and_then(|m| {
-                m.get("near_block_expected_shutdown")
-                    .and_then(|s| s.parse::<u64>().ok())
-            }),
-            final_block,
-            SHUTDOWN_WITH_NEARD.load(Ordering::SeqCst),
-        ) {
-            (Some(expect), Ok(current), true) if expect > 0 => Response::new(Body::from(format!(
-                "{{\"status\": 200, \"message\": \"maintenance shutdown in {} blocks, current: {current:}, shutdown at: {expect}\"}}",
-                expect - current
-            ))),
-            (Some(expect), Ok(current), false) if expect > 0 => Response::new(Body::from(format!(
-                "{{\"status\": 200, \"message\": \"maintenance restart in {} blocks,  current: {current:}, restart at: {expect}\"}}",
-                expect - current
-            ))),
-            (Some(expect), Err(e), true) if expect > 0 => Response::new(Body::from(format!("{{\"status\": 200, \"message\": \"maintenance shutdown will be at {expect}, fail to fetch current block {e:}\"}}"))),
-            (Some(expect), Err(e), false) if expect > 0 => Response::new(Body::from(format!("{{\"status\": 200, \"message\": \"maintenance restart will be at {expect}, fail to fetch current block {e:}\"}}"))),
-            (_, Ok(current), true) => Response::new(Body::from(format!(
-                "{{\"status\": 200, \"message\": \"maintenance shutdown set, current: {current:}\"}}",
-            ))),
-            (_, _, false) => Response::new(Body::from("{\"status\": 200, \"message\": \"no maintenance setting now\"}")),
-            (_, Err(e), _) => gateway_timeout(format!("fail to fetch current block from neard, please try again later. error: {e:}")),
+        let operation = match Schedule::new(&self.settings.neard_home).get() {
+            Ok(Some(operation)) => operation,
+            Ok(None) => {
+                return Ok(json_response(json!({
+                    "status": 200,
+                    "message": "no maintenance setting now",
+                })))
+            }
+            Err(e) => {
+                return Ok(server_error(format!(
+                    "fail to read scheduled operation: {e:#}"
+                )))
+            }
         };
+        let (mode, expect) = (operation.mode, operation.height);
+        let message = match self.near_client.final_block().await {
+            Ok(current) => format!(
+                "maintenance {mode} in {} blocks, current: {current:}, {mode} at: {expect}",
+                expect.saturating_sub(current)
+            ),
+            Err(e) => {
+                format!("maintenance {mode} will be at {expect}, fail to fetch current block {e:}")
+            }
+        };
+        Ok(json_response(json!({
+            "status": 200,
+            "message": message,
+            "operation": operation,
+        })))
+    }
 
-        Ok(resp)
+    async fn handle_maintenance_windows(&self) -> hyper::Result<Response<Body>> {
+        let plan = MaintenancePlan::query(&self.near_client, &self.settings.account_id).await;
+        Ok(json_response(ok_or_500!(plan)))
     }
 
     async fn handle_rpc_status(&self) -> hyper::Result<Response<Body>> {
@@ -225,9 +365,53 @@ impl CommandServer {
         Ok(resp)
     }
 
+    async fn handle_transitions(&self) -> hyper::Result<Response<Body>> {
+        let entries = Journal::new(&self.settings.neard_home).entries();
+        Ok(json_response(ok_or_500!(entries)))
+    }
+
+    async fn handle_crashes(&self) -> hyper::Result<Response<Body>> {
+        let entries = crash_journal(&self.settings.neard_home).entries();
+        Ok(json_response(ok_or_500!(entries)))
+    }
+
+    async fn handle_neard_logs(&self, req: &Request<Body>) -> hyper::Result<Response<Body>> {
+        let (lines, follow) = ok_or_500!(parse_log_query(req.uri().query()));
+        let text = |lines: Vec<String>| {
+            lines
+                .into_iter()
+                .map(|l| format!("{l}\n"))
+                .collect::<String>()
+        };
+        if !follow {
+            return Ok(Response::new(Body::from(text(neard_logs().tail(lines)))));
+        }
+        let (tail, mut rx) = neard_logs().follow(lines);
+        let (mut sender, body) = Body::channel();
+        tokio::spawn(async move {
+            let mut chunk = text(tail);
+            loop {
+                // stops once the client disconnects
+                if !chunk.is_empty() && sender.send_data(Bytes::from(chunk)).await.is_err() {
+                    return;
+                }
+                chunk = match rx.recv().await {
+                    Ok(line) => format!("{line}\n"),
+                    Err(RecvError::Lagged(n)) => format!("... skipped {n} lines\n"),
+                    Err(RecvError::Closed) => return,
+                };
+            }
+        });
+        Ok(Response::new(body))
+    }
+
+    async fn handle_validator_stats(&self) -> hyper::Result<Response<Body>> {
+        let stats = ValidatorStats::query(&self.near_client, &self.settings.account_id).await;
+        Ok(json_response(ok_or_500!(stats)))
+    }
+
     async fn handle_active_validator(&self) -> hyper::Result<Response<Body>> {
-        let validator =
-            active_validator(&self.account_id, &self.consul_url, &self.consul_token_path).await;
+        let validator = active_validator(&self.settings).await;
         Ok(json_response(ok_or_500!(validator)))
     }
 }
@@ -266,3 +450,15 @@ pub async fn spawn_control_server(settings: &Settings, tx: Sender<ipc::Request>)
     s.await.context("Failed to start server")?;
     Ok(())
 }
+
+#[test]
+fn test_parse_log_query() {
+    assert_eq!(parse_log_query(None).unwrap(), (DEFAULT_LOG_LINES, false));
+    assert_eq!(
+        parse_log_query(Some("lines=10&follow=1")).unwrap(),
+        (10, true)
+    );
+    assert_eq!(parse_log_query(Some("follow=0")).unwrap(), (100, false));
+    assert!(parse_log_query(Some("lines=many")).is_err());
+    assert!(parse_log_query(Some("level=warn")).is_err());
+}
@@ -9,12 +9,13 @@ struct SystemInfo {
     git_commit_date: String,
 }
 
-fn parse_neard_version(raw_version: &str) -> Result<(String, String, String)> {
+/// Returns the build, protocol and db version from the output of `neard -V`
+pub(crate) fn parse_neard_version(raw_version: &str) -> Result<(String, String, String)> {
     let mut develop_version = false;
     let mut version = String::new();
     let mut protocol_version = String::new();
     let mut db_version = String::new();
-    for cap in Regex::new(r"((?P<para>\S*)\s(?P<value>\S*))")?.captures_iter(raw_version) {
+    for cap in Regex::new(r"\((?P<para>\S+)\s(?P<value>[^)]*)\)")?.captures_iter(raw_version) {
         if cap["para"] == *"release" && cap["value"] == *"trunk" {
             develop_version = true;
         }
@@ -1,15 +1,19 @@
 //! Consul client implementation
 
+use crate::leader_protocol::{ElectionError, ElectionSession, Leader, LeaderElection};
 use anyhow::{bail, Context, Result};
+use base64::{engine::general_purpose, Engine as _};
+use futures_util::future::BoxFuture;
+use futures_util::FutureExt;
 use reqwest::header::{HeaderMap, HeaderValue};
 use reqwest::Client;
 use reqwest::Url;
 use serde::Deserialize;
 use serde::Serialize;
 use std::collections::HashMap;
-use std::fmt;
 use std::str;
 use std::sync::Mutex;
+use tokio::time::Duration;
 
 /// A client implementing the Consul leader election: https://learn.hashicorp.com/tutorials/consul/application-leader-elections
 #[derive(Debug)]
@@ -69,19 +73,6 @@ impl ConsulSession {
     }
 }
 
-#[derive(Debug)]
-/// Semantically errors returned by consul
-pub enum ConsulError {
-    /// Session does not (longer) exists
-    SessionNotFound,
-}
-impl std::error::Error for ConsulError {}
-impl fmt::Display for ConsulError {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{self:?}")
-    }
-}
-
 /// Return type of a consul key
 /// This is what a normal session looks like
 /// [
@@ -147,25 +138,6 @@ impl ConsulClient {
         Ok(client)
     }
 
-    /// Set consul auth token.
-    pub fn set_token(&self, token: Option<&str>) -> Result<()> {
-        let mut headers = match self.headers.lock() {
-            Ok(h) => h,
-            Err(e) => {
-                bail!("Cannot get header lock: {}", e);
-            }
-        };
-        if let Some(token) = token {
-            headers.insert(
-                "X-Consul-Token",
-                HeaderValue::from_str(token).context("invalid consul token")?,
-            );
-        } else {
-            headers.remove("X-Consul-Token");
-        }
-        Ok(())
-    }
-
     fn headers(&self) -> Result<HeaderMap> {
         match self.headers.lock() {
             Ok(h) => Ok(h.clone()),
@@ -175,67 +147,6 @@ impl ConsulClient {
         }
     }
 
-    /// Initializes and returns a new session.
-    /// Also see `<https://www.consul.io/api-docs/session#create-session>``
-    ///
-    /// # Arguments
-    ///
-    /// * `session_name` - Human readable name of the session
-    /// * `ttl` - ttl in seconds
-    pub async fn create_session(&self, session_name: &str, ttl: u64) -> Result<ConsulSession> {
-        let mut map = HashMap::new();
-        map.insert("Name", session_name);
-        // How long the session persists until locks are release
-        let ttl = format!("{ttl}s");
-        map.insert("TTL", &ttl);
-        // Delete old locks
-        map.insert("Behavior", "delete");
-        // Delete locks without delay if ttl or session is expired. kneard
-        // will stop validating before the ttl expires.
-        map.insert("LockDelay", "0s");
-
-        let url = self
-            .url
-            .join("/v1/session/create")
-            .context("Failed to create session url")?;
-        let res = self
-            .client
-            .put(url)
-            .headers(self.headers()?)
-            .json(&map)
-            .send()
-            .await
-            .context("Failed to create session")?;
-        let code = res.status();
-        if !code.is_success() {
-            let text = res.text().await.unwrap_or_else(|_| "".to_string());
-            bail!(
-                "failed to create session, consul returned (code: {}): {}",
-                code,
-                text
-            )
-        }
-
-        let m = res
-            .json::<HashMap<String, String>>()
-            .await
-            .context("expected Session result to be a hashmap")?;
-        let id = m.get("ID").context("no ID field found in session object")?;
-
-        Ok(ConsulSession {
-            id: id.to_string(),
-            name: session_name.to_string(),
-            node: "".to_string(),
-            lock_delay: 0,
-            behavior: SessionBehavior::Delete,
-            ttl,
-            node_checks: None,
-            service_checks: None,
-            create_index: 0,
-            modify_index: 0,
-        })
-    }
-
     /// Returns the requested session information.
     /// Returns None if no such session is exists or it has been expired
     ///
@@ -276,64 +187,6 @@ impl ConsulClient {
         }
     }
 
-    /// This renews the given consul session. This is used with sessions that have a TTL, and it extends the expiration by the TTL.
-    /// Also see `<https://www.consul.io/api-docs/session#renew-session>`
-    pub async fn renew_session(&self, session: &ConsulSession) -> Result<()> {
-        let url = self
-            .url
-            .join(&format!("/v1/session/renew/{}", session.id()))
-            .context("Failed to create session renew url")?;
-
-        let resp = self
-            .client
-            .put(url)
-            .headers(self.headers()?)
-            .send()
-            .await
-            .context("Failed to renew session")?;
-        match resp.status() {
-            code if code.is_success() => Ok(()),
-            reqwest::StatusCode::NOT_FOUND => {
-                bail!(ConsulError::SessionNotFound)
-            }
-            code => {
-                let text = resp.text().await.unwrap_or_else(|_| "".to_string());
-                bail!(
-                    "failed to renew session, consul returned (code: {}): {}",
-                    code,
-                    text
-                )
-            }
-        }
-    }
-
-    /// Delete a given consul session (`<https://www.consul.io/api-docs/session#delete-session>`)
-    pub async fn delete_session(&self, session: &ConsulSession) -> Result<()> {
-        let url = self
-            .url
-            .join(&format!("/v1/session/destroy/{}", session.id()))
-            .context("Failed to create `destroy session` url")?;
-
-        let res = self
-            .client
-            .put(url)
-            .headers(self.headers()?)
-            .send()
-            .await
-            .context("Failed to delete session")?;
-        match res.status() {
-            code if code.is_success() => Ok(()),
-            code => {
-                let text = res.text().await.unwrap_or_else(|_| "".to_string());
-                bail!(
-                    "Failed to delete session, consul returned (code: {}): {}",
-                    code,
-                    text
-                )
-            }
-        }
-    }
-
     /// This endpoint returns the specified key.
     /// Returns Ok(None) if no key exists at the given path.
     /// Also see `<https://www.consul.io/api-docs/kv#read-key>`
@@ -368,44 +221,302 @@ impl ConsulClient {
             }
         }
     }
+}
 
-    /// Acquire a lock for the given key and hold by the given session.
-    /// Returns true if the client acquire the session.
-    /// Also see `<https://www.consul.io/api-docs/session#delete-session>`
+impl LeaderElection for ConsulClient {
+    /// Initializes and returns a new session.
+    /// Also see `<https://www.consul.io/api-docs/session#create-session>``
     ///
     /// # Arguments
     ///
-    /// * `key` - name of the key to acquire a lock for
-    /// * `value` - value to store in the key
-    /// * `session` - consul session that tries to acquire the lock
-    pub async fn acquire_key<T>(&self, key: &str, value: T, session: &ConsulSession) -> Result<bool>
-    where
-        T: Serialize,
-    {
-        let mut url = self
-            .url
-            .join(&format!("/v1/kv/{key}"))
-            .context("Failed to create kv url")?;
-        url.set_query(Some(&format!("acquire={}", session.id())));
+    /// * `session_name` - Human readable name of the session
+    /// * `ttl` - How long the session persists until locks are released
+    fn create_session<'a>(
+        &'a self,
+        session_name: &'a str,
+        ttl: Duration,
+    ) -> BoxFuture<'a, Result<ElectionSession>> {
+        async move {
+            let mut map = HashMap::new();
+            map.insert("Name", session_name);
+            // How long the session persists until locks are release
+            let ttl = format!("{}s", ttl.as_secs());
+            map.insert("TTL", &ttl);
+            // Delete old locks
+            map.insert("Behavior", "delete");
+            // Delete locks without delay if ttl or session is expired. kneard
+            // will stop validating before the ttl expires.
+            map.insert("LockDelay", "0s");
 
-        let res = self
-            .client
-            .put(url)
-            .headers(self.headers()?)
-            .json(&value)
-            .send()
-            .await
-            .context("Failed to acquire key")?;
-        match res.status() {
-            code if code.is_success() => Ok(res.json::<bool>().await?),
-            code => {
+            let url = self
+                .url
+                .join("/v1/session/create")
+                .context("Failed to create session url")?;
+            let res = self
+                .client
+                .put(url)
+                .headers(self.headers()?)
+                .json(&map)
+                .send()
+                .await
+                .context("Failed to create session")?;
+            let code = res.status();
+            if !code.is_success() {
                 let text = res.text().await.unwrap_or_else(|_| "".to_string());
                 bail!(
-                    "failed to acquire key, consul returned (code: {}): {}",
+                    "failed to create session, consul returned (code: {}): {}",
                     code,
                     text
                 )
             }
+
+            let m = res
+                .json::<HashMap<String, String>>()
+                .await
+                .context("expected Session result to be a hashmap")?;
+            let id = m.get("ID").context("no ID field found in session object")?;
+
+            Ok(ElectionSession::new(
+                id.to_string(),
+                session_name.to_string(),
+            ))
+        }
+        .boxed()
+    }
+
+    /// This renews the given consul session. This is used with sessions that have a TTL, and it extends the expiration by the TTL.
+    /// Also see `<https://www.consul.io/api-docs/session#renew-session>`
+    fn renew_session<'a>(&'a self, session: &'a ElectionSession) -> BoxFuture<'a, Result<()>> {
+        async move {
+            let url = self
+                .url
+                .join(&format!("/v1/session/renew/{}", session.id()))
+                .context("Failed to create session renew url")?;
+
+            let resp = self
+                .client
+                .put(url)
+                .headers(self.headers()?)
+                .send()
+                .await
+                .context("Failed to renew session")?;
+            match resp.status() {
+                code if code.is_success() => Ok(()),
+                reqwest::StatusCode::NOT_FOUND => {
+                    bail!(ElectionError::SessionNotFound)
+                }
+                code => {
+                    let text = resp.text().await.unwrap_or_else(|_| "".to_string());
+                    bail!(
+                        "failed to renew session, consul returned (code: {}): {}",
+                        code,
+                        text
+                    )
+                }
+            }
+        }
+        .boxed()
+    }
+
+    /// Delete a given consul session (`<https://www.consul.io/api-docs/session#delete-session>`)
+    fn destroy_session<'a>(&'a self, session: &'a ElectionSession) -> BoxFuture<'a, Result<()>> {
+        async move {
+            let url = self
+                .url
+                .join(&format!("/v1/session/destroy/{}", session.id()))
+                .context("Failed to create `destroy session` url")?;
+
+            let res = self
+                .client
+                .put(url)
+                .headers(self.headers()?)
+                .send()
+                .await
+                .context("Failed to delete session")?;
+            match res.status() {
+                code if code.is_success() => Ok(()),
+                code => {
+                    let text = res.text().await.unwrap_or_else(|_| "".to_string());
+                    bail!(
+                        "Failed to delete session, consul returned (code: {}): {}",
+                        code,
+                        text
+                    )
+                }
+            }
+        }
+        .boxed()
+    }
+
+    /// Acquire a lock for the given key and hold by the given session.
+    /// Returns true if the client acquire the session.
+    /// Also see `<https://www.consul.io/api-docs/kv#create-update-key>`
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - name of the key to acquire a lock for
+    /// * `metadata` - value to store in the key
+    /// * `session` - consul session that tries to acquire the lock
+    fn acquire_lock<'a>(
+        &'a self,
+        key: &'a str,
+        metadata: &'a HashMap<&'static str, String>,
+        session: &'a ElectionSession,
+    ) -> BoxFuture<'a, Result<bool>> {
+        async move {
+            let mut url = self
+                .url
+                .join(&format!("/v1/kv/{key}"))
+                .context("Failed to create kv url")?;
+            url.set_query(Some(&format!("acquire={}", session.id())));
+
+            let res = self
+                .client
+                .put(url)
+                .headers(self.headers()?)
+                .json(metadata)
+                .send()
+                .await
+                .context("Failed to acquire key")?;
+            match res.status() {
+                code if code.is_success() => Ok(res.json::<bool>().await?),
+                code => {
+                    let text = res.text().await.unwrap_or_else(|_| "".to_string());
+                    bail!(
+                        "failed to acquire key, consul returned (code: {}): {}",
+                        code,
+                        text
+                    )
+                }
+            }
         }
+        .boxed()
+    }
+
+    fn release_lock<'a>(
+        &'a self,
+        key: &'a str,
+        session: &'a ElectionSession,
+    ) -> BoxFuture<'a, Result<()>> {
+        async move {
+            let mut url = self
+                .url
+                .join(&format!("/v1/kv/{key}"))
+                .context("Failed to create kv url")?;
+            url.set_query(Some(&format!("release={}", session.id())));
+
+            let res = self
+                .client
+                .put(url)
+                .headers(self.headers()?)
+                .send()
+                .await
+                .context("Failed to release key")?;
+            match res.status() {
+                code if code.is_success() => Ok(()),
+                code => {
+                    let text = res.text().await.unwrap_or_else(|_| "".to_string());
+                    bail!(
+                        "failed to release key, consul returned (code: {}): {}",
+                        code,
+                        text
+                    )
+                }
+            }
+        }
+        .boxed()
+    }
+
+    /// Looks up the session that holds the lock of the given key.
+    /// Returns None if there is no lock or the session holding it has expired.
+    fn leader<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<Option<Leader>>> {
+        async move {
+            let value = match self.get(key).await.context("Failed to get leader key")? {
+                None => return Ok(None),
+                Some(value) => value,
+            };
+            let uuid = match value.session {
+                None => return Ok(None),
+                Some(val) => val,
+            };
+            let session = match self
+                .get_session(&uuid)
+                .await
+                .context("Failed to get leader session")?
+            {
+                None => return Ok(None),
+                Some(session) => session,
+            };
+            Ok(Some(Leader {
+                node: session.node().to_string(),
+                name: session.name().to_string(),
+                session: uuid,
+                lock_index: value.lock_index,
+            }))
+        }
+        .boxed()
+    }
+
+    /// Lists all keys below `prefix` that are locked by a session.
+    /// Also see `<https://www.consul.io/api-docs/kv#recurse>`
+    fn list_locks<'a>(
+        &'a self,
+        prefix: &'a str,
+    ) -> BoxFuture<'a, Result<Vec<HashMap<String, String>>>> {
+        async move {
+            let mut url = self
+                .url
+                .join(&format!("/v1/kv/{prefix}"))
+                .context("Failed to create kv url")?;
+            url.set_query(Some("recurse=true"));
+            let res = self
+                .client
+                .get(url)
+                .headers(self.headers()?)
+                .send()
+                .await
+                .context("Failed to list keys")?;
+            let values = match res.status() {
+                code if code.is_success() => res
+                    .json::<Vec<ConsulValue>>()
+                    .await
+                    .context("Failed to decode response")?,
+                reqwest::StatusCode::NOT_FOUND => vec![],
+                code => {
+                    let text = res.text().await.unwrap_or_else(|_| "".to_string());
+                    bail!(
+                        "Failed to list keys, consul returned (code: {}): {}",
+                        code,
+                        text
+                    );
+                }
+            };
+            Ok(values
+                .into_iter()
+                .filter(|v| v.session.is_some())
+                .filter_map(|v| general_purpose::STANDARD.decode(v.value).ok())
+                .filter_map(|v| serde_json::from_slice(&v).ok())
+                .collect())
+        }
+        .boxed()
+    }
+
+    /// Set consul auth token.
+    fn set_token(&self, token: Option<&str>) -> Result<()> {
+        let mut headers = match self.headers.lock() {
+            Ok(h) => h,
+            Err(e) => {
+                bail!("Cannot get header lock: {}", e);
+            }
+        };
+        if let Some(token) = token {
+            headers.insert(
+                "X-Consul-Token",
+                HeaderValue::from_str(token).context("invalid consul token")?,
+            );
+        } else {
+            headers.remove("X-Consul-Token");
+        }
+        Ok(())
     }
 }
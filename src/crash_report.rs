@@ -0,0 +1,220 @@
+//! Records why neard exited and delays restarts of a crashing neard
+
+use crate::journal::Journal;
+use crate::neard_process::Neard;
+use anyhow::{bail, Result};
+use chrono::{DateTime, Utc};
+use lazy_static::lazy_static;
+use log::warn;
+use prometheus::{register_int_counter_vec, IntCounterVec};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io;
+use std::os::unix::process::ExitStatusExt;
+use std::path::Path;
+use std::process::ExitStatus;
+use std::time::SystemTime;
+use tokio::time::{Duration, Instant};
+
+lazy_static! {
+    static ref NEARD_EXITS: IntCounterVec = register_int_counter_vec!(
+        "kuutamod_neard_exits_total",
+        "How often neard exited",
+        &["reason"],
+    )
+    .unwrap();
+}
+
+/// How many crash records are kept
+const MAX_CRASH_RECORDS: usize = 100;
+/// Delay before the first restart after a failure, doubled on every further failure
+const RESTART_BACKOFF_START: Duration = Duration::from_secs(1);
+/// Longest delay between two restarts
+const RESTART_BACKOFF_MAX: Duration = Duration::from_secs(300);
+/// A neard that ran at least that long before it failed is not considered crash looping
+const STABLE_RUNTIME: Duration = Duration::from_secs(600);
+
+/// Why neard exited
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ExitReason {
+    /// neard exited with code 0, i.e. after a scheduled shutdown
+    Success,
+    /// neard exited with an error code
+    Error,
+    /// neard was killed by a signal
+    Signal,
+    /// The status api of neard did not come up in time, so we stopped it
+    StartupTimeout,
+    /// neard used more memory than allowed, so we restarted it
+    MemoryLimit,
+}
+
+impl ExitReason {
+    /// Classifies the result of waiting for neard
+    pub fn from_status(status: &io::Result<ExitStatus>) -> Self {
+        match status {
+            Ok(s) if s.success() => ExitReason::Success,
+            Ok(s) if s.signal().is_some() => ExitReason::Signal,
+            _ => ExitReason::Error,
+        }
+    }
+}
+
+impl fmt::Display for ExitReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            ExitReason::Success => "success",
+            ExitReason::Error => "error",
+            ExitReason::Signal => "signal",
+            ExitReason::StartupTimeout => "startup_timeout",
+            ExitReason::MemoryLimit => "memory_limit",
+        };
+        write!(f, "{s}")
+    }
+}
+
+/// An exit of neard
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct CrashRecord {
+    /// When neard exited
+    pub timestamp: DateTime<Utc>,
+    /// State of the supervisor when neard exited
+    pub state: String,
+    /// Why neard exited
+    pub reason: ExitReason,
+    /// Exit code, if neard exited by itself
+    pub exit_code: Option<i32>,
+    /// Signal that killed neard
+    pub signal: Option<i32>,
+    /// How many seconds neard was running
+    pub runtime_secs: u64,
+    /// The last lines neard wrote to stderr
+    pub stderr: Vec<String>,
+}
+
+impl CrashRecord {
+    /// Describes an exit of `neard`, `status` is None if it did not exit by itself
+    pub fn new(
+        neard: &dyn Neard,
+        state: &str,
+        reason: ExitReason,
+        status: Option<&ExitStatus>,
+        now: SystemTime,
+    ) -> Self {
+        Self {
+            timestamp: now.into(),
+            state: state.to_string(),
+            reason,
+            exit_code: status.and_then(|s| s.code()),
+            signal: status.and_then(|s| s.signal()),
+            runtime_secs: neard.started_at().elapsed().as_secs(),
+            stderr: neard.stderr_tail(),
+        }
+    }
+}
+
+impl fmt::Display for CrashRecord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}: neard ", self.timestamp.to_rfc3339(), self.state)?;
+        match (self.exit_code, self.signal) {
+            (Some(code), _) => write!(f, "exited with code {code}")?,
+            (None, Some(signal)) => write!(f, "was killed by signal {signal}")?,
+            (None, None) => write!(f, "was stopped")?,
+        }
+        write!(f, " after {}s ({})", self.runtime_secs, self.reason)?;
+        for line in &self.stderr {
+            write!(f, "\n    {line}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Returns the crash records stored in `neard_home`
+pub fn crash_journal(neard_home: &Path) -> Journal<CrashRecord> {
+    Journal::open(neard_home.join("kuutamod-crashes.jsonl"), MAX_CRASH_RECORDS)
+}
+
+/// Records exits of neard and tracks its restart budget
+#[derive(Debug)]
+pub struct CrashReports {
+    journal: Journal<CrashRecord>,
+    budget: u32,
+    failures: u32,
+    restart_at: Instant,
+}
+
+impl CrashReports {
+    /// Returns crash reports stored in `neard_home`. neard may fail `budget` times
+    /// in a row, before we give up. A budget of 0 means that we never give up.
+    pub fn new(neard_home: &Path, budget: u32) -> Self {
+        Self {
+            journal: crash_journal(neard_home),
+            budget,
+            failures: 0,
+            restart_at: Instant::now(),
+        }
+    }
+
+    /// When neard may be started again
+    pub fn restart_at(&self) -> Instant {
+        self.restart_at
+    }
+
+    /// Records an exit of neard and delays its next start, if it failed.
+    /// Fails if neard exhausted its restart budget.
+    pub fn record(&mut self, record: &CrashRecord) -> Result<()> {
+        NEARD_EXITS
+            .with_label_values(&[&record.reason.to_string()])
+            .inc();
+        warn!("{}", record);
+        if let Err(e) = self.journal.append(record) {
+            warn!("Failed to record exit of neard: {:#}", e);
+        }
+        let delay = match record.reason {
+            ExitReason::Success => {
+                self.failures = 0;
+                Duration::ZERO
+            }
+            _ => self.failed(Duration::from_secs(record.runtime_secs))?,
+        };
+        self.restart_at = Instant::now() + delay;
+        Ok(())
+    }
+
+    fn failed(&mut self, runtime: Duration) -> Result<Duration> {
+        if runtime >= STABLE_RUNTIME {
+            self.failures = 0;
+        }
+        self.failures += 1;
+        if self.budget > 0 && self.failures > self.budget {
+            bail!("neard failed {} times in a row, giving up", self.failures);
+        }
+        let delay = RESTART_BACKOFF_START.saturating_mul(1 << (self.failures - 1).min(16));
+        Ok(delay.min(RESTART_BACKOFF_MAX))
+    }
+}
+
+#[test]
+fn test_restart_backoff() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut crashes = CrashReports::new(dir.path(), 10);
+    let delays = (0..10)
+        .map(|_| crashes.failed(Duration::from_secs(5)).unwrap().as_secs())
+        .collect::<Vec<_>>();
+    assert_eq!(delays, vec![1, 2, 4, 8, 16, 32, 64, 128, 256, 300]);
+    assert!(crashes.failed(Duration::from_secs(5)).is_err());
+
+    // a neard that ran for a while is not crash looping
+    assert_eq!(crashes.failed(STABLE_RUNTIME).unwrap().as_secs(), 1);
+    assert_eq!(crashes.failed(Duration::ZERO).unwrap().as_secs(), 2);
+
+    let mut unlimited = CrashReports::new(dir.path(), 0);
+    for _ in 0..100 {
+        unlimited.failed(Duration::ZERO).unwrap();
+    }
+    assert_eq!(
+        unlimited.failed(Duration::ZERO).unwrap(),
+        RESTART_BACKOFF_MAX
+    );
+}
@@ -0,0 +1,200 @@
+//! Watches the filesystem of neard's data, so that a validator gives up its
+//! leadership before neard fails because its disk is full
+
+use crate::settings::Settings;
+use anyhow::{bail, Context, Result};
+use lazy_static::lazy_static;
+use nix::sys::statvfs::statvfs;
+use prometheus::{register_gauge_vec, register_int_gauge_vec, GaugeVec, IntGaugeVec};
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+lazy_static! {
+    static ref DISK_FREE: IntGaugeVec = register_int_gauge_vec!(
+        "kuutamod_disk_free",
+        "Free bytes or inodes on the filesystem of neard's data",
+        &["type"],
+    )
+    .unwrap();
+    static ref DISK_USAGE: GaugeVec = register_gauge_vec!(
+        "kuutamod_disk_usage_ratio",
+        "Ratio of used bytes or inodes on the filesystem of neard's data",
+        &["type"],
+    )
+    .unwrap();
+}
+
+/// Usage thresholds in percent of the space or inodes of neard's filesystem
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiskRules {
+    /// Directory on the filesystem neard stores its data in
+    pub data_dir: PathBuf,
+    /// Above this usage neard is unhealthy and does not compete for validating
+    pub warning: u8,
+    /// Above this usage the validator gives up its leadership in the next maintenance window
+    pub critical: u8,
+}
+
+impl DiskRules {
+    /// Returns the rules configured in the settings
+    pub fn from_settings(settings: &Settings) -> Self {
+        Self {
+            data_dir: settings.neard_home.clone(),
+            warning: settings.disk_warning_usage,
+            critical: settings.disk_critical_usage,
+        }
+    }
+
+    /// Checks that the validator stops competing before it gives up its leadership
+    pub fn validate(&self) -> Result<()> {
+        if self.critical > 100 {
+            bail!(
+                "critical disk usage ({}%) must not exceed 100%",
+                self.critical
+            );
+        }
+        if self.warning >= self.critical {
+            bail!(
+                "warning disk usage ({}%) must be smaller than the critical disk usage ({}%)",
+                self.warning,
+                self.critical
+            );
+        }
+        Ok(())
+    }
+
+    /// Queries the usage of neard's filesystem and exports it as metrics
+    pub fn check(&self) -> Result<DiskUsage> {
+        let usage = DiskUsage::query(&self.data_dir)?;
+        usage.export();
+        Ok(usage)
+    }
+
+    /// Whether `usage` exceeds the critical threshold
+    pub fn is_critical(&self, usage: &DiskUsage) -> bool {
+        usage.percent() > self.critical
+    }
+}
+
+/// Space and inodes of a filesystem
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DiskUsage {
+    /// Size of the filesystem in bytes
+    pub total_bytes: u64,
+    /// Bytes unprivileged processes like neard can still use
+    pub free_bytes: u64,
+    /// Number of inodes of the filesystem
+    pub total_inodes: u64,
+    /// Inodes unprivileged processes can still use
+    pub free_inodes: u64,
+}
+
+fn ratio(free: u64, total: u64) -> f64 {
+    if total == 0 {
+        // i.e. filesystems without a fixed number of inodes
+        return 0.0;
+    }
+    1.0 - free.min(total) as f64 / total as f64
+}
+
+impl DiskUsage {
+    /// Returns the usage of the filesystem `path` is on
+    pub fn query(path: &Path) -> Result<Self> {
+        let stat = statvfs(path)
+            .with_context(|| format!("cannot get filesystem of {}", path.display()))?;
+        let fragment_size = stat.fragment_size() as u64;
+        Ok(Self {
+            total_bytes: stat.blocks() as u64 * fragment_size,
+            free_bytes: stat.blocks_available() as u64 * fragment_size,
+            total_inodes: stat.files() as u64,
+            free_inodes: stat.files_available() as u64,
+        })
+    }
+
+    /// Ratio of used bytes
+    pub fn bytes_ratio(&self) -> f64 {
+        ratio(self.free_bytes, self.total_bytes)
+    }
+
+    /// Ratio of used inodes
+    pub fn inodes_ratio(&self) -> f64 {
+        ratio(self.free_inodes, self.total_inodes)
+    }
+
+    /// Usage in percent of whatever runs out first, bytes or inodes
+    pub fn percent(&self) -> u8 {
+        (self.bytes_ratio().max(self.inodes_ratio()) * 100.0).floor() as u8
+    }
+
+    fn export(&self) {
+        DISK_FREE
+            .with_label_values(&["bytes"])
+            .set(self.free_bytes as i64);
+        DISK_FREE
+            .with_label_values(&["inodes"])
+            .set(self.free_inodes as i64);
+        DISK_USAGE
+            .with_label_values(&["bytes"])
+            .set(self.bytes_ratio());
+        DISK_USAGE
+            .with_label_values(&["inodes"])
+            .set(self.inodes_ratio());
+    }
+}
+
+impl fmt::Display for DiskUsage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:.1}% of space and {:.1}% of inodes used",
+            self.bytes_ratio() * 100.0,
+            self.inodes_ratio() * 100.0
+        )
+    }
+}
+
+#[test]
+fn test_disk_usage() {
+    let usage = DiskUsage {
+        total_bytes: 1000,
+        free_bytes: 100,
+        total_inodes: 100,
+        free_inodes: 50,
+    };
+    assert_eq!(usage.percent(), 90);
+    assert_eq!(usage.to_string(), "90.0% of space and 50.0% of inodes used");
+    let rules = DiskRules {
+        data_dir: PathBuf::from("/var/lib/neard"),
+        warning: 85,
+        critical: 90,
+    };
+    assert!(!rules.is_critical(&usage));
+    rules.validate().unwrap();
+    for (warning, critical) in [(90, 90), (95, 90), (90, 101)] {
+        let rules = DiskRules {
+            warning,
+            critical,
+            ..rules.clone()
+        };
+        assert!(rules.validate().is_err(), "{rules:?} should be invalid");
+    }
+
+    // running out of inodes is as bad as running out of space
+    let usage = DiskUsage {
+        free_inodes: 4,
+        ..usage
+    };
+    assert_eq!(usage.percent(), 96);
+    assert!(rules.is_critical(&usage));
+
+    let no_inodes = DiskUsage {
+        total_inodes: 0,
+        free_inodes: 0,
+        ..usage
+    };
+    assert_eq!(no_inodes.percent(), 90);
+
+    let dir = tempfile::tempdir().unwrap();
+    let usage = DiskUsage::query(dir.path()).unwrap();
+    assert!(usage.total_bytes >= usage.free_bytes);
+}
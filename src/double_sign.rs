@@ -0,0 +1,311 @@
+//! Detects another node that validates with our validator key.
+//!
+//! The leader lock only coordinates kneard instances. It does not help if an operator
+//! starts neard with our key by hand, so we also look at what the network tells us.
+
+use crate::near_client::{NeardApi, NetworkInfo};
+use crate::settings::Settings;
+use anyhow::{Context, Result};
+use lazy_static::lazy_static;
+use near_primitives::types::AccountId;
+use prometheus::{register_int_counter_vec, IntCounterVec};
+use std::fmt;
+use tokio::time::{sleep, Duration, Instant};
+
+lazy_static! {
+    static ref DOUBLE_SIGN_DETECTIONS: IntCounterVec = register_int_counter_vec!(
+        "kuutamod_double_sign_detections_total",
+        "How often another node validating with our key was detected",
+        &["source"],
+    )
+    .unwrap();
+}
+
+/// How many blocks of our account we accept to be missing in the metrics of our neard,
+/// since the validators rpc and the metrics are not queried at the same time
+const PRODUCED_BLOCKS_TOLERANCE: u64 = 2;
+/// How long we do not try to become validator after a detection
+const DETECTION_HOLD_OFF: Duration = Duration::from_secs(60);
+/// How long we wait before we look at the network again to confirm a suspicion
+const CONFIRMATION_DELAY: Duration = Duration::from_secs(5);
+
+/// What made us believe that another node validates with our key
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DoubleSignEvidence {
+    /// Our account was announced by a node with the given node key
+    ForeignAnnouncement(String),
+    /// A peer at the given address is connected with our validator node key
+    DuplicateNodeKey(String),
+    /// The network counted more blocks for our account than our neard produced
+    UnexpectedBlocks {
+        /// Blocks produced by our account since we started to validate
+        network: u64,
+        /// Blocks produced by our neard in the same time
+        local: u64,
+    },
+}
+
+impl DoubleSignEvidence {
+    fn source(&self) -> &'static str {
+        match self {
+            DoubleSignEvidence::ForeignAnnouncement(_)
+            | DoubleSignEvidence::DuplicateNodeKey(_) => "network_info",
+            DoubleSignEvidence::UnexpectedBlocks { .. } => "validators",
+        }
+    }
+}
+
+impl fmt::Display for DoubleSignEvidence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DoubleSignEvidence::ForeignAnnouncement(peer_id) => {
+                write!(f, "our account is announced by node {peer_id}")
+            }
+            DoubleSignEvidence::DuplicateNodeKey(addr) => {
+                write!(f, "peer {addr} uses our validator node key")
+            }
+            DoubleSignEvidence::UnexpectedBlocks { network, local } => write!(
+                f,
+                "network counted {network} blocks for our account, but we only produced {local}"
+            ),
+        }
+    }
+}
+
+/// Checks the network for other nodes validating with our key
+#[derive(Debug)]
+pub struct DoubleSignGuard {
+    account_id: AccountId,
+    node_public_key: String,
+    /// Produced blocks reported by the network and our neard when we started to validate
+    baseline: Option<(u64, u64)>,
+    /// Evidence in the network info of the previous check. Peers of the previous validator
+    /// linger for a while after a failover, so evidence only counts if it is seen twice.
+    suspected: Option<DoubleSignEvidence>,
+    detected_at: Option<Instant>,
+}
+
+impl DoubleSignGuard {
+    /// Returns a guard for the validator configured in the settings
+    pub fn new(settings: &Settings) -> Self {
+        Self {
+            account_id: settings.account_id.clone(),
+            node_public_key: settings.validator_node_public_key.clone(),
+            baseline: None,
+            suspected: None,
+            detected_at: None,
+        }
+    }
+
+    /// True, if we should not become validator because of a recent detection
+    pub fn holds_off(&self) -> bool {
+        self.detected_at
+            .is_some_and(|at| at.elapsed() < DETECTION_HOLD_OFF)
+    }
+
+    /// Forgets the blocks counted so far, i.e. when our validator (re)starts
+    pub fn reset(&mut self) {
+        self.baseline = None;
+        self.suspected = None;
+    }
+
+    fn detected(&mut self, evidence: Option<DoubleSignEvidence>) -> Option<DoubleSignEvidence> {
+        if let Some(ref e) = evidence {
+            DOUBLE_SIGN_DETECTIONS
+                .with_label_values(&[e.source()])
+                .inc();
+            self.detected_at = Some(Instant::now());
+        }
+        evidence
+    }
+
+    /// Runs all checks against neard and returns the first evidence found
+    pub async fn check(&mut self, c: &dyn NeardApi) -> Result<Option<DoubleSignEvidence>> {
+        if let Some(evidence) = self.check_network(c).await? {
+            return Ok(Some(evidence));
+        }
+        self.check_produced_blocks(c).await
+    }
+
+    /// Looks for peers of neard that announce our account or use our validator node key.
+    /// Evidence is only returned, if the previous check found some as well.
+    pub async fn check_network(&mut self, c: &dyn NeardApi) -> Result<Option<DoubleSignEvidence>> {
+        let info = c
+            .network_info()
+            .await
+            .context("failed to get network info")?;
+        let evidence = self.inspect_network(&info);
+        let evidence = self.confirm(evidence);
+        Ok(self.detected(evidence))
+    }
+
+    /// Like `check_network`, but looks at the network a second time right away if the
+    /// first check raised a suspicion, i.e. before we start to validate
+    pub async fn check_network_now(
+        &mut self,
+        c: &dyn NeardApi,
+    ) -> Result<Option<DoubleSignEvidence>> {
+        let evidence = self.check_network(c).await?;
+        if evidence.is_some() || self.suspected.is_none() {
+            return Ok(evidence);
+        }
+        sleep(CONFIRMATION_DELAY).await;
+        self.check_network(c).await
+    }
+
+    fn confirm(&mut self, evidence: Option<DoubleSignEvidence>) -> Option<DoubleSignEvidence> {
+        let suspected = std::mem::replace(&mut self.suspected, evidence.clone());
+        evidence.filter(|_| suspected.is_some())
+    }
+
+    /// Compares the blocks the network counted for our account with the blocks our neard produced
+    pub async fn check_produced_blocks(
+        &mut self,
+        c: &dyn NeardApi,
+    ) -> Result<Option<DoubleSignEvidence>> {
+        let validators = c.validators().await.context("failed to get validators")?;
+        let network = match validators
+            .current_validators
+            .iter()
+            .find(|v| v.account_id == self.account_id)
+        {
+            Some(v) => v.num_produced_blocks,
+            // we are not validating in this epoch
+            None => return Ok(None),
+        };
+        let metrics = c.metrics().await.context("failed to get neard metrics")?;
+        let local = metrics
+            .get("near_block_produced_total")
+            .and_then(|v| v.parse::<u64>().ok())
+            .context("neard does not report near_block_produced_total")?;
+        let evidence = self.observe_produced(network, local);
+        Ok(self.detected(evidence))
+    }
+
+    fn inspect_network(&self, info: &NetworkInfo) -> Option<DoubleSignEvidence> {
+        if let Some(p) = info
+            .known_producers
+            .iter()
+            .find(|p| p.account_id == self.account_id && p.peer_id != self.node_public_key)
+        {
+            return Some(DoubleSignEvidence::ForeignAnnouncement(p.peer_id.clone()));
+        }
+        // a peer with our node key that does not validate for our account cannot sign for us
+        info.active_peers
+            .iter()
+            .find(|p| {
+                p.id == self.node_public_key && p.account_id.as_ref() == Some(&self.account_id)
+            })
+            .map(|p| {
+                DoubleSignEvidence::DuplicateNodeKey(
+                    p.addr.clone().unwrap_or_else(|| "unknown".to_string()),
+                )
+            })
+    }
+
+    fn observe_produced(&mut self, network: u64, local: u64) -> Option<DoubleSignEvidence> {
+        let (base_network, base_local) = match self.baseline {
+            // counters are reset on a new epoch or a restart of neard
+            Some((n, l)) if network >= n && local >= l => (n, l),
+            _ => {
+                self.baseline = Some((network, local));
+                return None;
+            }
+        };
+        let network = network - base_network;
+        let local = local - base_local;
+        if network > local + PRODUCED_BLOCKS_TOLERANCE {
+            Some(DoubleSignEvidence::UnexpectedBlocks { network, local })
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+fn test_guard() -> DoubleSignGuard {
+    DoubleSignGuard {
+        account_id: "kuutamo.pool.f863973.m0".parse().unwrap(),
+        node_public_key: "ed25519:ours".to_string(),
+        baseline: None,
+        suspected: None,
+        detected_at: None,
+    }
+}
+
+#[test]
+fn test_inspect_network() {
+    use crate::near_client::{NetworkInfoPeer, NetworkInfoProducer};
+    let guard = test_guard();
+    let producer = |account_id: &str, peer_id: &str| NetworkInfoProducer {
+        account_id: account_id.parse().unwrap(),
+        peer_id: peer_id.to_string(),
+    };
+    let mut info = NetworkInfo {
+        active_peers: vec![],
+        known_producers: vec![
+            producer("kuutamo.pool.f863973.m0", "ed25519:ours"),
+            producer("other.pool.f863973.m0", "ed25519:theirs"),
+        ],
+    };
+    assert_eq!(guard.inspect_network(&info), None);
+
+    info.active_peers.push(NetworkInfoPeer {
+        id: "ed25519:ours".to_string(),
+        addr: Some("10.0.0.2:24567".to_string()),
+        account_id: None,
+    });
+    assert_eq!(guard.inspect_network(&info), None);
+    info.active_peers[0].account_id = Some("kuutamo.pool.f863973.m0".parse().unwrap());
+    assert_eq!(
+        guard.inspect_network(&info),
+        Some(DoubleSignEvidence::DuplicateNodeKey(
+            "10.0.0.2:24567".to_string()
+        ))
+    );
+
+    info.known_producers
+        .push(producer("kuutamo.pool.f863973.m0", "ed25519:foreign"));
+    assert_eq!(
+        guard.inspect_network(&info),
+        Some(DoubleSignEvidence::ForeignAnnouncement(
+            "ed25519:foreign".to_string()
+        ))
+    );
+}
+
+#[test]
+fn test_confirm_network_evidence() {
+    let mut guard = test_guard();
+    let evidence = || {
+        Some(DoubleSignEvidence::DuplicateNodeKey(
+            "10.0.0.2:24567".to_string(),
+        ))
+    };
+    // i.e. a peer of the previous validator right after a failover
+    assert_eq!(guard.confirm(evidence()), None);
+    assert_eq!(guard.confirm(None), None);
+    assert_eq!(guard.confirm(evidence()), None);
+    assert_eq!(guard.confirm(evidence()), evidence());
+    guard.reset();
+    assert_eq!(guard.confirm(evidence()), None);
+}
+
+#[test]
+fn test_observe_produced() {
+    let mut guard = test_guard();
+    assert_eq!(guard.observe_produced(100, 0), None);
+    assert_eq!(guard.observe_produced(110, 10), None);
+    // small differences are expected, since both counters are not read at the same time
+    assert_eq!(guard.observe_produced(112, 10), None);
+    assert_eq!(
+        guard.observe_produced(120, 12),
+        Some(DoubleSignEvidence::UnexpectedBlocks {
+            network: 20,
+            local: 12
+        })
+    );
+    // new epoch
+    assert_eq!(guard.observe_produced(5, 15), None);
+    assert_eq!(guard.observe_produced(10, 20), None);
+}
@@ -0,0 +1,44 @@
+//! A drained node keeps neard running and synced, but never becomes validator,
+//! i.e. while its hardware is maintained
+
+use anyhow::{Context, Result};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+fn drain_file(neard_home: &Path) -> PathBuf {
+    neard_home.join("kuutamod-drained")
+}
+
+/// Returns true, if the node using `neard_home` is drained
+pub fn is_drained(neard_home: &Path) -> bool {
+    drain_file(neard_home).exists()
+}
+
+/// Drains or undrains the node using `neard_home`. The state is kept in
+/// `neard_home`, so that it survives restarts of kneard.
+pub fn set_drained(neard_home: &Path, drained: bool) -> Result<()> {
+    let path = drain_file(neard_home);
+    if drained {
+        fs::write(&path, "").with_context(|| format!("cannot create {}", path.display()))
+    } else {
+        match fs::remove_file(&path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                Err(e).with_context(|| format!("cannot remove {}", path.display()))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[test]
+fn test_drain() {
+    let dir = tempfile::tempdir().unwrap();
+    assert!(!is_drained(dir.path()));
+    set_drained(dir.path(), true).unwrap();
+    set_drained(dir.path(), true).unwrap();
+    assert!(is_drained(dir.path()));
+    set_drained(dir.path(), false).unwrap();
+    set_drained(dir.path(), false).unwrap();
+    assert!(!is_drained(dir.path()));
+}
@@ -0,0 +1,486 @@
+//! etcd v3 client implementation
+//!
+//! Talks to the JSON gateway of etcd (`/v3/...`), which represents 64 bit
+//! integers as strings and keys and values as base64.
+
+use crate::leader_protocol::{ElectionError, ElectionSession, Leader, LeaderElection};
+use anyhow::{bail, Context, Result};
+use base64::{engine::general_purpose, Engine as _};
+use futures_util::future::BoxFuture;
+use futures_util::FutureExt;
+use reqwest::header::{HeaderMap, HeaderValue, AUTHORIZATION};
+use reqwest::Client;
+use reqwest::Url;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use tokio::time::Duration;
+
+/// A client implementing leader election on top of etcd leases and transactions
+#[derive(Debug)]
+pub struct EtcdClient {
+    client: Client,
+    url: Url,
+    headers: Mutex<HeaderMap>,
+}
+
+/// A key-value pair as returned by etcd (`mvccpb.KeyValue`)
+#[derive(Deserialize, Debug, Clone)]
+pub struct EtcdKeyValue {
+    /// Base64-encoded key
+    pub key: String,
+    /// Base64-encoded value
+    #[serde(default)]
+    pub value: String,
+    /// Revision of the last creation of this key
+    #[serde(default)]
+    pub create_revision: String,
+    /// Revision of the last modification of this key
+    #[serde(default)]
+    pub mod_revision: String,
+    /// Id of the lease attached to the key, "0" or missing if there is none
+    #[serde(default)]
+    pub lease: String,
+}
+
+#[derive(Deserialize)]
+struct RangeResponse {
+    #[serde(default)]
+    kvs: Vec<EtcdKeyValue>,
+}
+
+fn encode(s: &str) -> String {
+    general_purpose::STANDARD.encode(s)
+}
+
+impl EtcdKeyValue {
+    /// Whether the key is attached to a lease, i.e. held by a session
+    fn has_lease(&self) -> bool {
+        !self.lease.is_empty() && self.lease != "0"
+    }
+
+    /// Decodes the metadata stored as json in the value
+    fn metadata(&self) -> Result<HashMap<String, String>> {
+        let value = general_purpose::STANDARD
+            .decode(&self.value)
+            .context("value is not valid base64")?;
+        serde_json::from_slice(&value).context("value is not valid json")
+    }
+}
+
+/// Returns the id of the lease in a response of `/v3/lease/grant`
+fn granted_lease(res: &HashMap<String, Value>) -> Result<String> {
+    match res.get("ID") {
+        Some(Value::String(id)) => Ok(id.to_string()),
+        Some(Value::Number(id)) => Ok(id.to_string()),
+        _ => bail!("no ID field found in lease grant response"),
+    }
+}
+
+/// Returns the remaining TTL of the lease in a response of `/v3/lease/keepalive`.
+/// etcd omits the TTL of expired or unknown leases.
+fn keepalive_ttl(res: &Value) -> i64 {
+    res["result"]["TTL"]
+        .as_str()
+        .and_then(|ttl| ttl.parse::<i64>().ok())
+        .or_else(|| res["result"]["TTL"].as_i64())
+        .unwrap_or(0)
+}
+
+/// Returns the transaction that creates `key` attached to `lease`, if it does not exist yet.
+/// Otherwise it returns the existing key.
+fn acquire_txn(key: &str, value: &str, lease: &str) -> Value {
+    let key = encode(key);
+    json!({
+        "compare": [{
+            "key": key,
+            "target": "CREATE",
+            "result": "EQUAL",
+            "create_revision": "0",
+        }],
+        "success": [{
+            "request_put": { "key": key, "value": encode(value), "lease": lease }
+        }],
+        "failure": [{
+            "request_range": { "key": key }
+        }],
+    })
+}
+
+/// Returns true, if the response of `acquire_txn` shows that the key is attached to `lease`
+fn acquired(res: &Value, lease: &str) -> bool {
+    if res["succeeded"].as_bool().unwrap_or(false) {
+        return true;
+    }
+    // The key already exists, check if we are the current holder.
+    let kvs = &res["responses"][0]["response_range"]["kvs"];
+    let holder: Vec<EtcdKeyValue> = serde_json::from_value(kvs.clone()).unwrap_or_default();
+    holder.iter().any(|kv| kv.lease == lease)
+}
+
+/// Returns the end of the range of all keys starting with `prefix`,
+/// i.e. the prefix with its last byte incremented
+fn range_end(prefix: &str) -> Vec<u8> {
+    let mut end = prefix.as_bytes().to_vec();
+    while let Some(last) = end.pop() {
+        if last < 0xff {
+            end.push(last + 1);
+            return end;
+        }
+    }
+    // no key is larger than the prefix, "\0" stands for all keys
+    vec![0]
+}
+
+/// Returns the leader stored in `kv`, if the key is held by a session.
+/// The create revision of the key is used as fencing token.
+fn decode_leader(kv: EtcdKeyValue) -> Result<Option<Leader>> {
+    if !kv.has_lease() {
+        return Ok(None);
+    }
+    let metadata = kv.metadata().context("invalid leader value")?;
+    Ok(Some(Leader {
+        node: metadata.get("Hostname").cloned().unwrap_or_default(),
+        name: metadata.get("NodeId").cloned().unwrap_or_default(),
+        lock_index: kv
+            .create_revision
+            .parse()
+            .context("create revision of leader key is not a number")?,
+        session: kv.lease,
+    }))
+}
+
+/// Returns the metadata of all keys held by a session, skipping keys with invalid values
+fn decode_locks(kvs: Vec<EtcdKeyValue>) -> Vec<HashMap<String, String>> {
+    kvs.into_iter()
+        .filter(EtcdKeyValue::has_lease)
+        .filter_map(|kv| kv.metadata().ok())
+        .collect()
+}
+
+impl EtcdClient {
+    /// Returns a new etcd client for the given endpoint
+    ///
+    /// # Arguments
+    ///
+    /// * `url` - The etcd endpoint url
+    /// * `token` - Auth token passed in the `Authorization` header
+    pub fn new(url: &str, token: Option<&str>) -> Result<EtcdClient> {
+        let url = Url::parse(url).with_context(|| "Failed to create etcd url")?;
+        let client = EtcdClient {
+            client: Client::new(),
+            url,
+            headers: Mutex::new(HeaderMap::new()),
+        };
+        client.set_token(token)?;
+        Ok(client)
+    }
+
+    fn headers(&self) -> Result<HeaderMap> {
+        match self.headers.lock() {
+            Ok(h) => Ok(h.clone()),
+            Err(e) => {
+                bail!("Cannot get header lock: {}", e);
+            }
+        }
+    }
+
+    async fn post<T: DeserializeOwned>(&self, path: &str, body: &Value) -> Result<T> {
+        let url = self
+            .url
+            .join(path)
+            .with_context(|| format!("Failed to create {path} url"))?;
+        let res = self
+            .client
+            .post(url)
+            .headers(self.headers()?)
+            .json(body)
+            .send()
+            .await
+            .with_context(|| format!("Failed to request {path}"))?;
+        let code = res.status();
+        if !code.is_success() {
+            let text = res.text().await.unwrap_or_else(|_| "".to_string());
+            bail!("{} failed, etcd returned (code: {}): {}", path, code, text)
+        }
+        res.json::<T>()
+            .await
+            .with_context(|| format!("Failed to decode response of {path}"))
+    }
+
+    /// Returns the key-value pair stored at the given key.
+    /// Also see `<https://etcd.io/docs/v3.5/dev-guide/api_reference_v3/#service-kv-etcdserveretcdserverpbrpcproto>`
+    pub async fn get(&self, key: &str) -> Result<Option<EtcdKeyValue>> {
+        let res: RangeResponse = self
+            .post("/v3/kv/range", &json!({ "key": encode(key) }))
+            .await?;
+        Ok(res.kvs.into_iter().next())
+    }
+}
+
+impl LeaderElection for EtcdClient {
+    /// Grants a new lease, which is the etcd counterpart of a consul session.
+    fn create_session<'a>(
+        &'a self,
+        session_name: &'a str,
+        ttl: Duration,
+    ) -> BoxFuture<'a, Result<ElectionSession>> {
+        async move {
+            let res: HashMap<String, Value> = self
+                .post(
+                    "/v3/lease/grant",
+                    &json!({ "TTL": ttl.as_secs().to_string(), "ID": "0" }),
+                )
+                .await
+                .context("Failed to grant lease")?;
+            let id = granted_lease(&res)?;
+            Ok(ElectionSession::new(id, session_name.to_string()))
+        }
+        .boxed()
+    }
+
+    /// Refreshes the lease. etcd reports a TTL of zero for expired or unknown leases.
+    fn renew_session<'a>(&'a self, session: &'a ElectionSession) -> BoxFuture<'a, Result<()>> {
+        async move {
+            let res: Value = self
+                .post("/v3/lease/keepalive", &json!({ "ID": session.id() }))
+                .await
+                .context("Failed to renew lease")?;
+            if keepalive_ttl(&res) <= 0 {
+                bail!(ElectionError::SessionNotFound)
+            }
+            Ok(())
+        }
+        .boxed()
+    }
+
+    /// Revokes the lease, which also deletes all keys attached to it.
+    fn destroy_session<'a>(&'a self, session: &'a ElectionSession) -> BoxFuture<'a, Result<()>> {
+        async move {
+            let _: Value = self
+                .post("/v3/lease/revoke", &json!({ "ID": session.id() }))
+                .await
+                .context("Failed to revoke lease")?;
+            Ok(())
+        }
+        .boxed()
+    }
+
+    /// Creates the key attached to our lease, if it does not exist yet.
+    /// Returns true if the key is (already) attached to our lease.
+    fn acquire_lock<'a>(
+        &'a self,
+        key: &'a str,
+        metadata: &'a HashMap<&'static str, String>,
+        session: &'a ElectionSession,
+    ) -> BoxFuture<'a, Result<bool>> {
+        async move {
+            let value = serde_json::to_string(metadata).context("Failed to serialize metadata")?;
+            let res: Value = self
+                .post("/v3/kv/txn", &acquire_txn(key, &value, session.id()))
+                .await
+                .context("Failed to acquire key")?;
+            Ok(acquired(&res, session.id()))
+        }
+        .boxed()
+    }
+
+    /// Deletes the key, if it is attached to the lease of the session.
+    fn release_lock<'a>(
+        &'a self,
+        key: &'a str,
+        session: &'a ElectionSession,
+    ) -> BoxFuture<'a, Result<()>> {
+        async move {
+            let key = encode(key);
+            let txn = json!({
+                "compare": [{
+                    "key": key,
+                    "target": "LEASE",
+                    "result": "EQUAL",
+                    "lease": session.id(),
+                }],
+                "success": [{
+                    "request_delete_range": { "key": key }
+                }],
+            });
+            let _: Value = self
+                .post("/v3/kv/txn", &txn)
+                .await
+                .context("Failed to release key")?;
+            Ok(())
+        }
+        .boxed()
+    }
+
+    /// Returns the holder of the lock. The value of the key contains the
+    /// metadata the leader has written when acquiring the lock.
+    /// The create revision of the key is used as fencing token.
+    fn leader<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<Option<Leader>>> {
+        async move {
+            match self.get(key).await.context("Failed to get leader key")? {
+                Some(kv) => decode_leader(kv),
+                None => Ok(None),
+            }
+        }
+        .boxed()
+    }
+
+    /// Lists all keys below `prefix` that are attached to a lease.
+    fn list_locks<'a>(
+        &'a self,
+        prefix: &'a str,
+    ) -> BoxFuture<'a, Result<Vec<HashMap<String, String>>>> {
+        async move {
+            // all keys in [prefix, range_end) are returned
+            let res: RangeResponse = self
+                .post(
+                    "/v3/kv/range",
+                    &json!({
+                        "key": encode(prefix),
+                        "range_end": general_purpose::STANDARD.encode(range_end(prefix)),
+                    }),
+                )
+                .await
+                .context("Failed to list keys")?;
+            Ok(decode_locks(res.kvs))
+        }
+        .boxed()
+    }
+
+    /// Set etcd auth token.
+    fn set_token(&self, token: Option<&str>) -> Result<()> {
+        let mut headers = match self.headers.lock() {
+            Ok(h) => h,
+            Err(e) => {
+                bail!("Cannot get header lock: {}", e);
+            }
+        };
+        if let Some(token) = token {
+            headers.insert(
+                AUTHORIZATION,
+                HeaderValue::from_str(token).context("invalid etcd token")?,
+            );
+        } else {
+            headers.remove(AUTHORIZATION);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+fn key_value(value: &str, lease: &str) -> EtcdKeyValue {
+    EtcdKeyValue {
+        key: encode("kuutamod-leader/kuutamo.pool.f863973.m0"),
+        value: encode(value),
+        create_revision: "42".to_string(),
+        mod_revision: "43".to_string(),
+        lease: lease.to_string(),
+    }
+}
+
+#[test]
+fn test_lease_responses() {
+    let grant = |body: &str| serde_json::from_str::<HashMap<String, Value>>(body).unwrap();
+    assert_eq!(
+        granted_lease(&grant(r#"{"ID":"7587862072907486733","TTL":"30"}"#)).unwrap(),
+        "7587862072907486733"
+    );
+    assert_eq!(
+        granted_lease(&grant(r#"{"ID":12,"TTL":"30"}"#)).unwrap(),
+        "12"
+    );
+    assert!(granted_lease(&grant(r#"{"error":"etcdserver: too many requests"}"#)).is_err());
+
+    let keepalive = |body: &str| keepalive_ttl(&serde_json::from_str(body).unwrap());
+    assert_eq!(keepalive(r#"{"result":{"ID":"12","TTL":"30"}}"#), 30);
+    assert_eq!(keepalive(r#"{"result":{"ID":"12","TTL":29}}"#), 29);
+    // expired or unknown leases
+    assert_eq!(keepalive(r#"{"result":{"ID":"12"}}"#), 0);
+    assert_eq!(keepalive(r#"{"result":{"ID":"12","TTL":"-1"}}"#), -1);
+}
+
+#[test]
+fn test_acquire_txn() {
+    let txn = acquire_txn("kuutamod-leader/a", r#"{"NodeId":"node0"}"#, "12");
+    assert_eq!(txn["compare"][0]["key"], encode("kuutamod-leader/a"));
+    assert_eq!(txn["compare"][0]["target"], "CREATE");
+    assert_eq!(txn["compare"][0]["create_revision"], "0");
+    assert_eq!(txn["success"][0]["request_put"]["lease"], "12");
+    assert_eq!(
+        txn["success"][0]["request_put"]["value"],
+        encode(r#"{"NodeId":"node0"}"#)
+    );
+    assert_eq!(
+        txn["failure"][0]["request_range"]["key"],
+        encode("kuutamod-leader/a")
+    );
+
+    let response = |body: &str| serde_json::from_str::<Value>(body).unwrap();
+    assert!(acquired(&response(r#"{"succeeded":true}"#), "12"));
+    let held = |lease: &str| {
+        response(&format!(
+            r#"{{"succeeded":false,"responses":[{{"response_range":{{"kvs":[{{"key":"a2V5","value":"","create_revision":"42","lease":"{lease}"}}]}}}}]}}"#
+        ))
+    };
+    // we hold the key already, i.e. after a retry
+    assert!(acquired(&held("12"), "12"));
+    assert!(!acquired(&held("13"), "12"));
+    // etcd omits `succeeded` if it is false
+    assert!(!acquired(&response(r#"{"responses":[]}"#), "12"));
+}
+
+#[test]
+fn test_range_end() {
+    assert_eq!(
+        range_end("kuutamod-candidates/a/"),
+        b"kuutamod-candidates/a0"
+    );
+    assert_eq!(range_end("a"), b"b");
+    assert_eq!(range_end(""), vec![0]);
+}
+
+#[test]
+fn test_decode_keys() {
+    let leader = decode_leader(key_value(r#"{"NodeId":"node0","Hostname":"host0"}"#, "12"))
+        .unwrap()
+        .unwrap();
+    assert_eq!(leader.name, "node0");
+    assert_eq!(leader.node, "host0");
+    assert_eq!(leader.session, "12");
+    assert_eq!(leader.lock_index, 42);
+
+    // keys without lease are not held by anybody
+    assert!(decode_leader(key_value(r#"{"NodeId":"node0"}"#, "0"))
+        .unwrap()
+        .is_none());
+    assert!(decode_leader(key_value(r#"{"NodeId":"node0"}"#, ""))
+        .unwrap()
+        .is_none());
+    assert!(decode_leader(key_value("not json", "12")).is_err());
+    let mut kv = key_value(r#"{"NodeId":"node0"}"#, "12");
+    kv.value = "not base64!".to_string();
+    assert!(decode_leader(kv).is_err());
+    let mut kv = key_value(r#"{"NodeId":"node0"}"#, "12");
+    kv.create_revision = String::new();
+    assert!(decode_leader(kv).is_err());
+
+    let range: RangeResponse = serde_json::from_value(json!({
+        "kvs": [
+            { "key": encode("a"), "value": encode(r#"{"NodeId":"node0"}"#), "lease": "12" },
+            { "key": encode("b"), "value": encode(r#"{"NodeId":"node1"}"#) },
+            { "key": encode("c"), "value": encode("invalid"), "lease": "13" },
+            { "key": encode("d"), "value": encode(r#"{"NodeId":"node2"}"#), "lease": "14" },
+        ]
+    }))
+    .unwrap();
+    let nodes = decode_locks(range.kvs)
+        .into_iter()
+        .map(|m| m["NodeId"].clone())
+        .collect::<Vec<_>>();
+    assert_eq!(nodes, vec!["node0", "node2"]);
+    let empty: RangeResponse = serde_json::from_str("{}").unwrap();
+    assert!(empty.kvs.is_empty());
+}
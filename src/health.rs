@@ -0,0 +1,350 @@
+//! Evaluates the health of neard from several signals, so that an unhealthy
+//! node does not compete for becoming validator
+
+use crate::disk::DiskRules;
+use crate::near_client::NeardApi;
+use crate::settings::Settings;
+use lazy_static::lazy_static;
+use log::{info, warn};
+use near_primitives::views::StatusResponse;
+use prometheus::{register_int_gauge, IntGauge};
+use std::collections::HashMap;
+use std::fmt;
+use std::mem;
+use std::time::SystemTime;
+use tokio::time::Duration;
+
+lazy_static! {
+    static ref HEALTH_SCORE: IntGauge = register_int_gauge!(
+        "kuutamod_neard_health_score",
+        "Health of neard from 0 (unusable) to 100 (healthy)"
+    )
+    .unwrap();
+}
+
+/// Thresholds neard has to meet to be considered healthy. `None` disables a rule.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HealthRules {
+    /// Minimum number of peers neard is connected to
+    pub min_peers: usize,
+    /// Maximum age of the head block of neard
+    pub max_block_age: Option<Duration>,
+    /// Maximum time the head block is ahead of our clock
+    pub max_clock_drift: Option<Duration>,
+    /// Maximum average time neard takes to process a block
+    pub max_block_processing_time: Option<Duration>,
+    /// Usage thresholds of the filesystem neard stores its data in
+    pub disk: Option<DiskRules>,
+}
+
+impl HealthRules {
+    /// Returns the rules configured in the settings
+    pub fn from_settings(settings: &Settings) -> Self {
+        Self {
+            min_peers: settings.health_min_peers,
+            max_block_age: settings.health_max_block_age.map(Duration::from_secs),
+            max_clock_drift: settings.health_max_clock_drift.map(Duration::from_secs),
+            max_block_processing_time: settings
+                .health_max_block_processing_time
+                .map(Duration::from_millis),
+            disk: Some(DiskRules::from_settings(settings)),
+        }
+    }
+}
+
+/// A rule neard does not meet
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HealthIssue {
+    /// neard is connected to fewer peers than required
+    TooFewPeers(usize),
+    /// The peers of neard could not be queried
+    UnknownPeers,
+    /// The head block is older than allowed
+    BlockAge(Duration),
+    /// The head block is further ahead of our clock than allowed
+    ClockDrift(Duration),
+    /// neard took longer to process blocks on average than allowed
+    SlowBlockProcessing(Duration),
+    /// More percent of the space or inodes of neard's filesystem are used than allowed
+    DiskUsage(u8),
+}
+
+impl HealthIssue {
+    /// How many points the issue takes off the score
+    fn penalty(&self) -> u8 {
+        match self {
+            HealthIssue::TooFewPeers(0) => 100,
+            HealthIssue::TooFewPeers(_) | HealthIssue::UnknownPeers => 50,
+            HealthIssue::BlockAge(_) | HealthIssue::DiskUsage(_) => 40,
+            HealthIssue::ClockDrift(_) => 30,
+            HealthIssue::SlowBlockProcessing(_) => 20,
+        }
+    }
+}
+
+impl fmt::Display for HealthIssue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HealthIssue::TooFewPeers(peers) => write!(f, "connected to {peers} peers"),
+            HealthIssue::UnknownPeers => write!(f, "cannot get peers"),
+            HealthIssue::BlockAge(age) => write!(f, "head block is {}s old", age.as_secs()),
+            HealthIssue::ClockDrift(drift) => {
+                write!(f, "head block is {}s ahead of our clock", drift.as_secs())
+            }
+            HealthIssue::SlowBlockProcessing(d) => {
+                write!(f, "processing a block takes {}ms", d.as_millis())
+            }
+            HealthIssue::DiskUsage(percent) => write!(f, "{percent}% of the disk is used"),
+        }
+    }
+}
+
+/// Result of a health evaluation
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Health {
+    /// From 0 (unusable) to 100 (healthy)
+    pub score: u8,
+    /// Rules that neard does not meet
+    pub issues: Vec<HealthIssue>,
+}
+
+impl Health {
+    /// Whether neard meets all rules
+    pub fn is_healthy(&self) -> bool {
+        self.issues.is_empty()
+    }
+}
+
+impl fmt::Display for Health {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "score {}", self.score)?;
+        for (i, issue) in self.issues.iter().enumerate() {
+            write!(f, "{}{issue}", if i == 0 { ": " } else { ", " })?;
+        }
+        Ok(())
+    }
+}
+
+/// What was observed about neard
+#[derive(Debug, Default)]
+struct Signals {
+    /// Number of active peers, None if they could not be queried
+    peers: Option<usize>,
+    /// How much the head block is behind our clock
+    block_age: Duration,
+    /// How much the head block is ahead of our clock
+    clock_drift: Duration,
+    /// Average processing time of the blocks since the last observation
+    block_processing_time: Option<Duration>,
+    /// Usage of neard's filesystem in percent, None if it could not be queried
+    disk_usage: Option<u8>,
+}
+
+/// Evaluates the health rules against neard over time
+#[derive(Debug)]
+pub struct HealthMonitor {
+    rules: HealthRules,
+    /// Sum and count of the block processing time histogram of the last observation
+    last_processing: Option<(f64, f64)>,
+    health: Option<Health>,
+}
+
+impl HealthMonitor {
+    /// Returns a monitor without any observations
+    pub fn new(rules: &HealthRules) -> Self {
+        Self {
+            rules: rules.clone(),
+            last_processing: None,
+            health: None,
+        }
+    }
+
+    /// Whether neard was healthy on the last observation, false if it was never checked
+    pub fn is_healthy(&self) -> bool {
+        self.health.as_ref().is_some_and(Health::is_healthy)
+    }
+
+    /// Evaluates the rules against a status response of neard received at `now`
+    /// and the peers and metrics it currently reports
+    pub async fn check(&mut self, c: &dyn NeardApi, status: &StatusResponse, now: SystemTime) {
+        let block_time = SystemTime::UNIX_EPOCH
+            + Duration::from_millis(status.sync_info.latest_block_time.timestamp_millis() as u64);
+        let mut signals = Signals {
+            block_age: now.duration_since(block_time).unwrap_or_default(),
+            clock_drift: block_time.duration_since(now).unwrap_or_default(),
+            ..Signals::default()
+        };
+        if self.rules.min_peers > 0 {
+            signals.peers = match c.network_info().await {
+                Ok(info) => Some(info.active_peers.len()),
+                Err(e) => {
+                    warn!("Cannot get peers of neard: {:#}", e);
+                    None
+                }
+            };
+        }
+        if self.rules.max_block_processing_time.is_some() {
+            match c.metrics().await {
+                Ok(metrics) => signals.block_processing_time = self.block_processing_time(&metrics),
+                Err(e) => warn!("Cannot get metrics of neard: {:#}", e),
+            }
+        }
+        if let Some(ref disk) = self.rules.disk {
+            match disk.check() {
+                Ok(usage) => signals.disk_usage = Some(usage.percent()),
+                Err(e) => warn!("Cannot get disk usage: {:#}", e),
+            }
+        }
+
+        let health = self.evaluate(&signals);
+        HEALTH_SCORE.set(i64::from(health.score));
+        let kinds = |h: &Health| h.issues.iter().map(mem::discriminant).collect::<Vec<_>>();
+        let previous = self.health.as_ref().map(kinds);
+        if !health.is_healthy() && previous.as_ref() != Some(&kinds(&health)) {
+            warn!(
+                "neard is unhealthy ({}), not competing for validating",
+                health
+            );
+        } else if health.is_healthy() && previous.is_some_and(|p| !p.is_empty()) {
+            info!("neard is healthy again");
+        }
+        self.health = Some(health);
+    }
+
+    /// Average time neard took to process the blocks since the last call
+    fn block_processing_time(&mut self, metrics: &HashMap<String, String>) -> Option<Duration> {
+        let value = |key: &str| metrics.get(key).and_then(|v| v.parse::<f64>().ok());
+        let sum = value("near_block_processing_time_sum")?;
+        let count = value("near_block_processing_time_count")?;
+        let (last_sum, last_count) = self.last_processing.replace((sum, count))?;
+        if count <= last_count {
+            // no new blocks, which is covered by the block age
+            return None;
+        }
+        let average = (sum - last_sum) / (count - last_count);
+        Some(Duration::from_secs_f64(average.max(0.0)))
+    }
+
+    fn evaluate(&self, signals: &Signals) -> Health {
+        let exceeds = |value: Duration, limit: Option<Duration>| limit.is_some_and(|l| value > l);
+        let mut issues = vec![];
+        if self.rules.min_peers > 0 {
+            match signals.peers {
+                Some(peers) if peers < self.rules.min_peers => {
+                    issues.push(HealthIssue::TooFewPeers(peers))
+                }
+                Some(_) => {}
+                None => issues.push(HealthIssue::UnknownPeers),
+            }
+        }
+        if exceeds(signals.block_age, self.rules.max_block_age) {
+            issues.push(HealthIssue::BlockAge(signals.block_age));
+        }
+        if exceeds(signals.clock_drift, self.rules.max_clock_drift) {
+            issues.push(HealthIssue::ClockDrift(signals.clock_drift));
+        }
+        if let Some(time) = signals.block_processing_time {
+            if exceeds(time, self.rules.max_block_processing_time) {
+                issues.push(HealthIssue::SlowBlockProcessing(time));
+            }
+        }
+        if let (Some(disk), Some(usage)) = (&self.rules.disk, signals.disk_usage) {
+            if usage > disk.warning {
+                issues.push(HealthIssue::DiskUsage(usage));
+            }
+        }
+        let penalty = issues.iter().map(|i| u32::from(i.penalty())).sum::<u32>();
+        Health {
+            score: 100u32.saturating_sub(penalty) as u8,
+            issues,
+        }
+    }
+}
+
+#[test]
+fn test_health_rules() {
+    let rules = HealthRules {
+        min_peers: 2,
+        max_block_age: Some(Duration::from_secs(60)),
+        max_clock_drift: Some(Duration::from_secs(10)),
+        max_block_processing_time: Some(Duration::from_millis(500)),
+        disk: Some(DiskRules {
+            data_dir: "/var/lib/neard".into(),
+            warning: 90,
+            critical: 95,
+        }),
+    };
+    let mut monitor = HealthMonitor::new(&rules);
+    assert!(!monitor.is_healthy());
+
+    let healthy = Signals {
+        peers: Some(5),
+        block_age: Duration::from_secs(1),
+        ..Signals::default()
+    };
+    let health = monitor.evaluate(&healthy);
+    assert!(health.is_healthy());
+    assert_eq!(health.score, 100);
+
+    let no_peers = Signals {
+        peers: Some(0),
+        ..Signals::default()
+    };
+    let health = monitor.evaluate(&no_peers);
+    assert_eq!(health.issues, vec![HealthIssue::TooFewPeers(0)]);
+    assert_eq!(health.score, 0);
+    assert_eq!(health.to_string(), "score 0: connected to 0 peers");
+
+    let behind = Signals {
+        peers: None,
+        block_age: Duration::from_secs(61),
+        block_processing_time: Some(Duration::from_millis(600)),
+        ..Signals::default()
+    };
+    let health = monitor.evaluate(&behind);
+    assert_eq!(
+        health.issues,
+        vec![
+            HealthIssue::UnknownPeers,
+            HealthIssue::BlockAge(Duration::from_secs(61)),
+            HealthIssue::SlowBlockProcessing(Duration::from_millis(600)),
+        ]
+    );
+    assert_eq!(health.score, 0);
+
+    let ahead = Signals {
+        peers: Some(2),
+        clock_drift: Duration::from_secs(11),
+        ..Signals::default()
+    };
+    assert_eq!(monitor.evaluate(&ahead).score, 70);
+
+    let full = Signals {
+        peers: Some(2),
+        disk_usage: Some(91),
+        ..Signals::default()
+    };
+    let health = monitor.evaluate(&full);
+    assert_eq!(health.issues, vec![HealthIssue::DiskUsage(91)]);
+    assert_eq!(health.to_string(), "score 60: 91% of the disk is used");
+
+    // the processing time is averaged over the blocks between two observations
+    let metrics = |sum: &str, count: &str| {
+        HashMap::from([
+            (
+                "near_block_processing_time_sum".to_string(),
+                sum.to_string(),
+            ),
+            (
+                "near_block_processing_time_count".to_string(),
+                count.to_string(),
+            ),
+        ])
+    };
+    assert_eq!(monitor.block_processing_time(&metrics("100", "1000")), None);
+    assert_eq!(
+        monitor.block_processing_time(&metrics("101", "1004")),
+        Some(Duration::from_millis(250))
+    );
+    assert_eq!(monitor.block_processing_time(&metrics("101", "1004")), None);
+}
@@ -0,0 +1,254 @@
+//! Commands and webhooks that are run when the supervisor changes its state
+//!
+//! Hooks are configured in a toml file:
+//!
+//! ```toml
+//! [[hook]]
+//! on = "validating"
+//! command = ["/usr/local/bin/open-firewall"]
+//!
+//! [[hook]]
+//! on = "left_validating"
+//! url = "https://events.example.com/kneard"
+//! timeout = 5
+//! ```
+
+use crate::journal::TransitionReason;
+use anyhow::{bail, Context, Result};
+use log::warn;
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+use std::process::Stdio;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+use tokio::task::JoinHandle;
+use tokio::time::{timeout, Duration};
+
+/// How long a hook may run, if no timeout is configured
+const DEFAULT_HOOK_TIMEOUT: u64 = 10;
+
+/// Events that trigger a hook
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum HookEvent {
+    /// Entered the startup state
+    Startup,
+    /// Entered the syncing state
+    Syncing,
+    /// Entered the registering state
+    Registering,
+    /// Entered the voting state
+    Voting,
+    /// Became validator
+    Validating,
+    /// kneard is shutting down
+    Shutdown,
+    /// Was validator and changed to any other state
+    LeftValidating,
+}
+
+/// A command or url to call on an event
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Hook {
+    /// Event that triggers the hook
+    pub on: HookEvent,
+    /// Program and arguments to execute, the payload is passed on stdin
+    pub command: Option<Vec<String>>,
+    /// Url the payload is posted to
+    pub url: Option<Url>,
+    /// Seconds after which the hook is cancelled
+    pub timeout: Option<u64>,
+}
+
+/// All configured hooks
+#[derive(Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct Hooks {
+    /// Hooks in the order of the configuration file
+    #[serde(default, rename = "hook")]
+    pub hooks: Vec<Hook>,
+}
+
+/// Describes a state transition, passed as json to each hook
+#[derive(Serialize, Debug, Clone)]
+pub struct HookPayload {
+    /// Node id of this node
+    pub node_id: String,
+    /// Validator account
+    pub account_id: String,
+    /// Previous state
+    pub old_state: String,
+    /// New state
+    pub new_state: String,
+    /// Why the state changed
+    pub reason: TransitionReason,
+}
+
+impl Hooks {
+    /// Reads hooks from a toml file
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("cannot read hooks file {}", path.display()))?;
+        Self::from_toml(&content).with_context(|| format!("invalid hooks in {}", path.display()))
+    }
+
+    fn from_toml(content: &str) -> Result<Self> {
+        let hooks: Hooks = toml::from_str(content)?;
+        for hook in &hooks.hooks {
+            match (&hook.command, &hook.url) {
+                (Some(command), None) if command.is_empty() => {
+                    bail!("command of {:?} hook is empty", hook.on)
+                }
+                (Some(_), None) | (None, Some(_)) => {}
+                _ => bail!("{:?} hook needs either a command or an url", hook.on),
+            }
+        }
+        Ok(hooks)
+    }
+
+    /// Returns the hooks triggered by a change from `old_state` to `new_state`
+    fn triggered<'a>(&'a self, old_state: &str, new_state: &str) -> Vec<&'a Hook> {
+        self.hooks
+            .iter()
+            .filter(|hook| match hook.on {
+                HookEvent::LeftValidating => old_state == "Validating" && new_state != old_state,
+                event => format!("{event:?}") == new_state,
+            })
+            .collect()
+    }
+
+    /// Starts all hooks triggered by the payload in the background
+    pub fn run(&self, payload: &HookPayload) -> Vec<JoinHandle<()>> {
+        self.triggered(&payload.old_state, &payload.new_state)
+            .into_iter()
+            .map(|hook| {
+                let hook = hook.clone();
+                let payload = payload.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = hook.run(&payload).await {
+                        warn!("{:?} hook failed: {:#}", hook.on, e);
+                    }
+                })
+            })
+            .collect()
+    }
+}
+
+impl Hook {
+    async fn run(&self, payload: &HookPayload) -> Result<()> {
+        let body = serde_json::to_vec(payload).context("cannot serialize hook payload")?;
+        let limit = Duration::from_secs(self.timeout.unwrap_or(DEFAULT_HOOK_TIMEOUT));
+        let res = match (&self.command, &self.url) {
+            (Some(command), _) => timeout(limit, run_command(command, &body)).await,
+            (None, Some(url)) => timeout(limit, post_webhook(url, body)).await,
+            (None, None) => return Ok(()),
+        };
+        match res {
+            Ok(res) => res,
+            Err(_) => bail!("timed out after {}s", limit.as_secs()),
+        }
+    }
+}
+
+async fn run_command(command: &[String], payload: &[u8]) -> Result<()> {
+    // the process is killed, if we hit the timeout and this future is dropped
+    let mut child = Command::new(&command[0])
+        .args(&command[1..])
+        .stdin(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .with_context(|| format!("cannot execute {}", command[0]))?;
+    if let Some(mut stdin) = child.stdin.take() {
+        // the hook may not read its stdin at all
+        let _ = stdin.write_all(payload).await;
+    }
+    let status = child
+        .wait()
+        .await
+        .with_context(|| format!("cannot wait for {}", command[0]))?;
+    if !status.success() {
+        bail!("{} failed with {}", command[0], status);
+    }
+    Ok(())
+}
+
+async fn post_webhook(url: &Url, payload: Vec<u8>) -> Result<()> {
+    let res = reqwest::Client::new()
+        .post(url.clone())
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .body(payload)
+        .send()
+        .await
+        .with_context(|| format!("cannot post to {url}"))?;
+    if !res.status().is_success() {
+        bail!("{} returned {}", url, res.status());
+    }
+    Ok(())
+}
+
+#[test]
+fn test_triggered_hooks() {
+    let hooks = Hooks::from_toml(
+        r#"
+[[hook]]
+on = "validating"
+command = ["true"]
+
+[[hook]]
+on = "left_validating"
+url = "http://localhost:8080/hook"
+timeout = 5
+"#,
+    )
+    .unwrap();
+    assert_eq!(hooks.hooks.len(), 2);
+    assert_eq!(hooks.hooks[1].timeout, Some(5));
+    assert_eq!(
+        hooks.triggered("Voting", "Validating"),
+        vec![&hooks.hooks[0]]
+    );
+    assert_eq!(
+        hooks.triggered("Validating", "Startup"),
+        vec![&hooks.hooks[1]]
+    );
+    assert!(hooks.triggered("Startup", "Syncing").is_empty());
+
+    assert!(Hooks::from_toml("[[hook]]\non = \"voting\"\n").is_err());
+    assert!(Hooks::from_toml("[[hook]]\non = \"voting\"\ncommand = []\n").is_err());
+}
+
+#[tokio::test]
+async fn test_command_hook() {
+    let dir = tempfile::tempdir().unwrap();
+    let out = dir.path().join("payload.json");
+    let hook = Hook {
+        on: HookEvent::Validating,
+        command: Some(vec![
+            "sh".to_string(),
+            "-c".to_string(),
+            format!("cat > {}", out.display()),
+        ]),
+        url: None,
+        timeout: None,
+    };
+    let payload = HookPayload {
+        node_id: "node0".to_string(),
+        account_id: "kuutamo.pool.f863973.m0".to_string(),
+        old_state: "Voting".to_string(),
+        new_state: "Validating".to_string(),
+        reason: TransitionReason::LeaderAcquired,
+    };
+    hook.run(&payload).await.unwrap();
+    let written: serde_json::Value =
+        serde_json::from_str(&fs::read_to_string(&out).unwrap()).unwrap();
+    assert_eq!(written["reason"], "leader_acquired");
+    assert_eq!(written["new_state"], "Validating");
+
+    let slow = Hook {
+        command: Some(vec!["sleep".to_string(), "10".to_string()]),
+        timeout: Some(0),
+        ..hook
+    };
+    assert!(slow.run(&payload).await.is_err());
+}
@@ -1,15 +1,38 @@
 //! For sending messages to the supervisor
 
+use crate::neard_binary::NeardVersion;
+use crate::schedule::ScheduledOperation;
 use anyhow::Result;
-use near_primitives::types::BlockHeight;
+use std::path::PathBuf;
 use tokio::sync::mpsc;
 
 /// Response from the supervisor
 pub struct ScheduleRestartOperationResponse {
-    /// When to shutdown.
+    /// The scheduled operation.
     /// Error if shutdown could not be scheduled.
-    /// None if neard process is not a validator and an intermediate shutdown was requested
-    pub shutdown_at_blockheight: Result<Option<BlockHeight>>,
+    /// None if the schedule was cancelled, or if the node is not a validator
+    /// and an immediate shutdown was requested
+    pub scheduled: Result<Option<ScheduledOperation>>,
+}
+
+/// Response from the supervisor to a handover request
+pub struct HandoverResponse {
+    /// Error if the handover could not be scheduled
+    pub result: Result<()>,
+}
+
+/// Response from the supervisor to a drain request
+pub struct DrainResponse {
+    /// Error if the drain state could not be changed
+    pub result: Result<()>,
+}
+
+/// Response from the supervisor to a request for staging a neard binary
+pub struct StageNeardResponse {
+    /// Version of the staged binary and the restart that switches to it,
+    /// None if neard restarts right away.
+    /// Error if the binary was rejected.
+    pub result: Result<(NeardVersion, Option<ScheduledOperation>)>,
 }
 
 /// Request to send to the supervisor
@@ -18,11 +41,24 @@ pub enum Request {
     ///     * a maintainace window of given size,
     ///     * book a block height to shutdown at,
     ///     * cancel previous schedule
+    ///     * schedule even if the window is shorter than the expected db migration
     ///     + Channel where the supervisor will respond to once the request is finished
     ScheduleRestartOperation(
         Option<u64>,
         Option<u64>,
         bool,
+        bool,
         mpsc::Sender<ScheduleRestartOperationResponse>,
     ),
+    /// Hand over the leadership to the node with the given node id in the next maintenance window
+    ///     + Channel where the supervisor will respond to once the handover is scheduled
+    Handover(String, mpsc::Sender<HandoverResponse>),
+    /// Drain (true) or undrain (false) this node. A drained node never becomes validator
+    /// and gives up its leadership in the next maintenance window.
+    ///     + Channel where the supervisor will respond to once the state is persisted
+    Drain(bool, mpsc::Sender<DrainResponse>),
+    /// Switch to the given neard binary in the next maintenance window, or back to the previous one if None
+    ///     + Whether to schedule even if the window is shorter than the expected db migration
+    ///     + Channel where the supervisor will respond to once the binary is staged
+    StageNeard(Option<PathBuf>, bool, mpsc::Sender<StageNeardResponse>),
 }
@@ -0,0 +1,228 @@
+//! Bounded on-disk journal of the state transitions of the supervisor
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use log::warn;
+use near_primitives::types::BlockHeight;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+
+/// How many transitions are kept in the journal
+const MAX_JOURNAL_ENTRIES: usize = 1000;
+
+/// Why the supervisor changed its state
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TransitionReason {
+    /// The status api of neard became available
+    NeardStarted,
+    /// The neard process exited
+    NeardExited,
+    /// The status api of neard was not reachable
+    NeardUnreachable,
+    /// neard is synced with the network
+    Synced,
+    /// neard fell behind the network and is syncing again
+    Desync,
+    /// A session in the election backend was created
+    SessionCreated,
+    /// The session in the election backend expired or could not be renewed
+    SessionLost,
+    /// We acquired the leader lock
+    LeaderAcquired,
+    /// A step down rule fired
+    StepDown,
+    /// Leadership was handed over to another node
+    Handover,
+    /// Our session no longer holds the leader lock
+    StaleLeader,
+    /// Another node validates with our key
+    DoubleSign,
+    /// kneard received a signal to exit
+    Signal,
+    /// A request on the control socket
+    IpcRequest,
+    /// This node was drained
+    Drain,
+    /// The disk of neard is almost full
+    DiskFull,
+    /// neard exceeded a memory limit
+    MemoryPressure,
+}
+
+impl fmt::Display for TransitionReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = serde_json::to_value(self)
+            .ok()
+            .and_then(|v| v.as_str().map(str::to_string))
+            .unwrap_or_else(|| format!("{self:?}"));
+        write!(f, "{s}")
+    }
+}
+
+/// A state transition of the supervisor
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct TransitionEntry {
+    /// When the transition happened
+    pub timestamp: DateTime<Utc>,
+    /// Previous state
+    pub from: String,
+    /// New state
+    pub to: String,
+    /// Why the state changed
+    pub reason: TransitionReason,
+    /// Latest block height neard reported before the transition
+    pub block_height: Option<BlockHeight>,
+    /// Id of the session in the election backend, if we had one
+    pub session_id: Option<String>,
+}
+
+impl fmt::Display for TransitionEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} {} -> {} ({})",
+            self.timestamp.to_rfc3339(),
+            self.from,
+            self.to,
+            self.reason
+        )?;
+        if let Some(height) = self.block_height {
+            write!(f, " block_height={height}")?;
+        }
+        if let Some(ref session_id) = self.session_id {
+            write!(f, " session={session_id}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Journal stored as json lines in neard's home, by default of state transitions
+#[derive(Debug, Clone)]
+pub struct Journal<T = TransitionEntry> {
+    path: PathBuf,
+    max_entries: usize,
+    entry: PhantomData<T>,
+}
+
+impl Journal {
+    /// Returns the journal of state transitions stored in `neard_home`
+    pub fn new(neard_home: &Path) -> Self {
+        Self::open(
+            neard_home.join("kuutamod-transitions.jsonl"),
+            MAX_JOURNAL_ENTRIES,
+        )
+    }
+}
+
+impl<T: Serialize + DeserializeOwned + Clone> Journal<T> {
+    /// Returns the journal stored at `path` that keeps the latest `max_entries`
+    pub fn open(path: PathBuf, max_entries: usize) -> Self {
+        Self {
+            path,
+            max_entries,
+            entry: PhantomData,
+        }
+    }
+
+    fn lines(&self) -> Result<Vec<String>> {
+        match fs::read_to_string(&self.path) {
+            Ok(content) => Ok(content
+                .lines()
+                .filter(|line| !line.is_empty())
+                .map(str::to_string)
+                .collect()),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(vec![]),
+            Err(e) => Err(e).with_context(|| format!("cannot read {}", self.path.display())),
+        }
+    }
+
+    /// Returns all entries, oldest first. Invalid entries are skipped.
+    pub fn entries(&self) -> Result<Vec<T>> {
+        Ok(self
+            .lines()?
+            .iter()
+            .filter_map(|line| match serde_json::from_str(line) {
+                Ok(entry) => Some(entry),
+                Err(e) => {
+                    warn!("Skip invalid entry in {}: {}", self.path.display(), e);
+                    None
+                }
+            })
+            .collect())
+    }
+
+    /// Appends an entry and drops the oldest ones if the journal is full
+    pub fn append(&self, entry: &T) -> Result<()> {
+        let line = serde_json::to_string(entry).context("cannot serialize entry")?;
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .with_context(|| format!("cannot open {}", self.path.display()))?;
+        writeln!(file, "{line}")
+            .with_context(|| format!("cannot write {}", self.path.display()))?;
+
+        let lines = self.lines()?;
+        if lines.len() <= self.max_entries {
+            return Ok(());
+        }
+        // lines that are not valid entries are kept, they might be written by a newer kneard
+        let dir = self.path.parent().unwrap_or_else(|| Path::new("."));
+        let mut file = tempfile::NamedTempFile::new_in(dir)
+            .with_context(|| format!("cannot create temporary file in {}", dir.display()))?;
+        for line in &lines[lines.len() - self.max_entries..] {
+            writeln!(file, "{line}").context("cannot write journal")?;
+        }
+        file.persist(&self.path)
+            .with_context(|| format!("cannot write {}", self.path.display()))?;
+        Ok(())
+    }
+}
+
+#[test]
+fn test_journal() {
+    let dir = tempfile::tempdir().unwrap();
+    let journal = Journal::open(dir.path().join("journal.jsonl"), 3);
+    assert_eq!(journal.entries().unwrap(), vec![]);
+    let entry = |height| TransitionEntry {
+        timestamp: Utc::now(),
+        from: "Voting".to_string(),
+        to: "Validating".to_string(),
+        reason: TransitionReason::LeaderAcquired,
+        block_height: Some(height),
+        session_id: None,
+    };
+    for height in 0..5 {
+        journal.append(&entry(height)).unwrap();
+    }
+    let heights = journal
+        .entries()
+        .unwrap()
+        .iter()
+        .map(|e| e.block_height.unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(heights, vec![2, 3, 4]);
+
+    // an invalid line neither fails reading nor drops the other entries
+    let mut file = OpenOptions::new()
+        .append(true)
+        .open(dir.path().join("journal.jsonl"))
+        .unwrap();
+    writeln!(file, "{{\"truncated\":").unwrap();
+    journal.append(&entry(5)).unwrap();
+    let heights = journal
+        .entries()
+        .unwrap()
+        .iter()
+        .map(|e| e.block_height.unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(heights, vec![4, 5]);
+    let content = fs::read_to_string(dir.path().join("journal.jsonl")).unwrap();
+    assert!(content.contains("truncated"));
+    assert_eq!(TransitionReason::SessionLost.to_string(), "session_lost");
+}
@@ -1,6 +1,322 @@
 //! Interface used for leader election
 
-/// Consul key used for leadership election
-pub fn consul_leader_key(account_name: &str) -> String {
+use crate::consul_client::ConsulClient;
+use crate::etcd_client::EtcdClient;
+use crate::local_election::FileLockElection;
+use crate::settings::Settings;
+use anyhow::{bail, Context, Result};
+use futures_util::future::BoxFuture;
+use std::borrow::Borrow;
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::Hash;
+use std::time::SystemTime;
+use tokio::time::Duration;
+
+/// Key used for leadership election
+pub fn leader_key(account_name: &str) -> String {
     format!("kuutamod-leader/{account_name}")
 }
+
+/// Prefix of the keys that voting nodes lock to announce their candidacy
+pub fn candidates_prefix(account_name: &str) -> String {
+    format!("kuutamod-candidates/{account_name}/")
+}
+
+/// Key a voting node locks to announce its candidacy
+pub fn candidate_key(account_name: &str, node_id: &str) -> String {
+    format!("{}{node_id}", candidates_prefix(account_name))
+}
+
+/// Prefix of the keys the leader locks to hand over its leadership to a node
+pub fn handover_prefix(account_name: &str) -> String {
+    format!("kuutamod-handover/{account_name}/")
+}
+
+/// Keys used in the election of one validator account
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ElectionKeys {
+    /// Key locked by the leader
+    pub leader: String,
+    /// Key locked by this node while it is a candidate
+    pub candidate: String,
+    /// Prefix of the keys of all candidates
+    pub candidates: String,
+    /// Prefix of the keys announcing a handover
+    pub handover: String,
+}
+
+impl ElectionKeys {
+    /// Returns the keys for the given account and node
+    pub fn new(account_name: &str, node_id: &str) -> Self {
+        Self {
+            leader: leader_key(account_name),
+            candidate: candidate_key(account_name, node_id),
+            candidates: candidates_prefix(account_name),
+            handover: handover_prefix(account_name),
+        }
+    }
+
+    /// Key locked by the leader to announce a handover to `node_id`
+    pub fn handover_key(&self, node_id: &str) -> String {
+        format!("{}{node_id}", self.handover)
+    }
+}
+
+/// Priority a node announces in its metadata, nodes without priority have 0
+pub fn candidate_priority<K: Borrow<str> + Hash + Eq>(metadata: &HashMap<K, String>) -> u32 {
+    metadata
+        .get("Priority")
+        .and_then(|p| p.parse().ok())
+        .unwrap_or(0)
+}
+
+/// Returns node id and priority of the candidate with the highest priority,
+/// if it is higher than `priority`. The node `node_id` itself is not considered.
+pub fn preferred_candidate(
+    candidates: &[HashMap<String, String>],
+    node_id: &str,
+    priority: u32,
+) -> Option<(String, u32)> {
+    candidates
+        .iter()
+        .filter(|c| c.get("NodeId").map(String::as_str) != Some(node_id))
+        .map(|c| {
+            (
+                c.get("NodeId").cloned().unwrap_or_default(),
+                candidate_priority(c),
+            )
+        })
+        .filter(|(_, p)| *p > priority)
+        .max_by_key(|(_, p)| *p)
+}
+
+/// Returns the node id of the target of a handover that has not expired at `now`.
+/// The handover expires at the unix timestamp in `Expires`.
+pub fn handover_target(handovers: &[HashMap<String, String>], now: SystemTime) -> Option<String> {
+    let now = now
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    handovers
+        .iter()
+        .filter(|h| {
+            h.get("Expires")
+                .and_then(|e| e.parse::<u64>().ok())
+                .is_some_and(|e| e > now)
+        })
+        .find_map(|h| h.get("NodeId").cloned())
+}
+
+/// Backends that can be used for leader election
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElectionBackend {
+    /// Consul sessions and KV locks
+    Consul,
+    /// etcd v3 leases and transactions
+    Etcd,
+    /// `flock` on a file in neard's home, only suitable for single-host deployments
+    File,
+}
+
+#[derive(Debug)]
+/// Semantically errors returned by election backends
+pub enum ElectionError {
+    /// Session does not (longer) exists
+    SessionNotFound,
+}
+impl std::error::Error for ElectionError {}
+impl fmt::Display for ElectionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{self:?}")
+    }
+}
+
+/// A session in the election backend, i.e. a consul session or an etcd lease.
+/// The leader lock is bound to the session and released once it expires.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ElectionSession {
+    id: String,
+    name: String,
+}
+
+impl ElectionSession {
+    /// Returns a new session handle
+    pub fn new(id: String, name: String) -> Self {
+        Self { id, name }
+    }
+    /// Session ID
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+    /// Session Name
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+/// The node currently holding the leader lock
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Leader {
+    /// Node the leader is running on
+    pub node: String,
+    /// Name of the leader session (our node id)
+    pub name: String,
+    /// Id of the session holding the lock
+    pub session: String,
+    /// Fencing token, increases every time the lock is acquired
+    pub lock_index: u64,
+}
+
+/// Checks that `session` holds the lock of `leader` and returns the fencing token of the lock.
+/// If `expected` is set, the lock must not have been acquired again since we got this token.
+pub fn fencing_token(
+    leader: Option<&Leader>,
+    session: &ElectionSession,
+    expected: Option<u64>,
+) -> Result<u64> {
+    let leader = match leader {
+        Some(leader) => leader,
+        None => bail!("leader lock is not held by any session"),
+    };
+    if leader.session != session.id() {
+        bail!(
+            "leader lock is held by session {} of {}",
+            leader.session,
+            leader.name
+        );
+    }
+    match expected {
+        Some(token) if token != leader.lock_index => bail!(
+            "fencing token changed from {} to {}",
+            token,
+            leader.lock_index
+        ),
+        _ => Ok(leader.lock_index),
+    }
+}
+
+/// A backend that implements session based leader election.
+pub trait LeaderElection: fmt::Debug + Send + Sync {
+    /// Initializes and returns a new session that expires after `ttl` unless renewed.
+    fn create_session<'a>(
+        &'a self,
+        session_name: &'a str,
+        ttl: Duration,
+    ) -> BoxFuture<'a, Result<ElectionSession>>;
+
+    /// Extends the expiration of the session by its ttl.
+    /// Fails with `ElectionError::SessionNotFound` if the session has already expired.
+    fn renew_session<'a>(&'a self, session: &'a ElectionSession) -> BoxFuture<'a, Result<()>>;
+
+    /// Deletes the session and releases all locks held by it.
+    fn destroy_session<'a>(&'a self, session: &'a ElectionSession) -> BoxFuture<'a, Result<()>>;
+
+    /// Acquire a lock for the given key and hold it by the given session.
+    /// Returns true if the session holds the lock afterwards.
+    fn acquire_lock<'a>(
+        &'a self,
+        key: &'a str,
+        metadata: &'a HashMap<&'static str, String>,
+        session: &'a ElectionSession,
+    ) -> BoxFuture<'a, Result<bool>>;
+
+    /// Releases the lock for the given key, if it is held by the given session.
+    fn release_lock<'a>(
+        &'a self,
+        key: &'a str,
+        session: &'a ElectionSession,
+    ) -> BoxFuture<'a, Result<()>>;
+
+    /// Returns the current holder of the lock for the given key, if there is any.
+    fn leader<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<Option<Leader>>>;
+
+    /// Returns the metadata of all held locks whose key starts with `prefix`.
+    fn list_locks<'a>(
+        &'a self,
+        prefix: &'a str,
+    ) -> BoxFuture<'a, Result<Vec<HashMap<String, String>>>>;
+
+    /// Update the token used for authenticating against the backend.
+    fn set_token(&self, token: Option<&str>) -> Result<()>;
+}
+
+/// Returns the leader election backend configured in the settings
+pub fn leader_election(settings: &Settings) -> Result<Box<dyn LeaderElection>> {
+    let token = settings.election_token();
+    Ok(match settings.election_backend {
+        ElectionBackend::Consul => Box::new(
+            ConsulClient::new(&settings.consul_url, token)
+                .context("Failed to create consul client")?,
+        ),
+        ElectionBackend::Etcd => Box::new(
+            EtcdClient::new(&settings.etcd_url, token).context("Failed to create etcd client")?,
+        ),
+        ElectionBackend::File => Box::new(FileLockElection::new(&settings.neard_home)),
+    })
+}
+
+#[test]
+fn test_preferred_candidate() {
+    let candidate = |node_id: &str, priority: Option<&str>| {
+        let mut m = HashMap::from([("NodeId".to_string(), node_id.to_string())]);
+        if let Some(p) = priority {
+            m.insert("Priority".to_string(), p.to_string());
+        }
+        m
+    };
+    let candidates = vec![
+        candidate("node0", None),
+        candidate("node1", Some("10")),
+        candidate("node2", Some("5")),
+    ];
+    assert_eq!(
+        preferred_candidate(&candidates, "node0", 0),
+        Some(("node1".to_string(), 10))
+    );
+    assert_eq!(preferred_candidate(&candidates, "node1", 10), None);
+    assert_eq!(
+        preferred_candidate(&candidates, "node2", 5),
+        Some(("node1".to_string(), 10))
+    );
+    assert_eq!(preferred_candidate(&candidates, "node3", 10), None);
+}
+
+#[test]
+fn test_handover_target() {
+    let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1000);
+    let handover = |node_id: &str, expires: &str| {
+        HashMap::from([
+            ("NodeId".to_string(), node_id.to_string()),
+            ("Expires".to_string(), expires.to_string()),
+        ])
+    };
+    assert_eq!(handover_target(&[], now), None);
+    assert_eq!(handover_target(&[handover("node1", "1000")], now), None);
+    assert_eq!(
+        handover_target(&[handover("node1", "999"), handover("node2", "1030")], now),
+        Some("node2".to_string())
+    );
+}
+
+#[test]
+fn test_fencing_token() {
+    let session = ElectionSession::new("s1".to_string(), "node0".to_string());
+    let leader = |session: &str, lock_index| Leader {
+        node: "host".to_string(),
+        name: "node0".to_string(),
+        session: session.to_string(),
+        lock_index,
+    };
+    assert!(fencing_token(None, &session, None).is_err());
+    assert!(fencing_token(Some(&leader("s2", 3)), &session, None).is_err());
+    assert_eq!(
+        fencing_token(Some(&leader("s1", 3)), &session, None).unwrap(),
+        3
+    );
+    assert_eq!(
+        fencing_token(Some(&leader("s1", 3)), &session, Some(3)).unwrap(),
+        3
+    );
+    assert!(fencing_token(Some(&leader("s1", 4)), &session, Some(3)).is_err());
+}
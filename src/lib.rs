@@ -2,24 +2,43 @@
 
 //! a HA supervisor library for neard
 
+pub mod cgroup;
+pub mod clock;
 pub mod commands;
 pub mod consul_client;
+pub mod crash_report;
 pub mod deploy;
+pub mod disk;
+pub mod double_sign;
+pub mod drain;
+pub mod etcd_client;
 pub mod exit_signal_handler;
+pub mod health;
+pub mod hooks;
 pub mod ipc;
+pub mod journal;
 pub mod leader_protocol;
+pub mod local_election;
 pub mod log_fmt;
+pub mod maintenance;
+pub mod memory;
+pub mod migration;
 pub mod near_client;
 pub mod near_config;
+pub mod neard_binary;
+pub mod neard_logs;
 pub mod neard_process;
 pub mod oom_score;
 pub mod proc;
 pub mod prometheus;
 pub mod proxy;
-pub mod scoped_consul_session;
+pub mod schedule;
+pub mod scoped_session;
 pub mod settings;
 /// ssh utils to host
 pub mod ssh;
+pub mod step_down;
 pub mod supervisor;
 /// utils for knd
 pub mod utils;
+pub mod validator_stats;
@@ -0,0 +1,623 @@
+//! Leader election backends that do not need an external service.
+//!
+//! `FileLockElection` holds the leader lock as `flock` on a file in neard's home
+//! and is meant for single-host deployments. `MemoryElection` keeps all state in
+//! process and is meant for tests.
+
+use crate::leader_protocol::{ElectionError, ElectionSession, Leader, LeaderElection};
+use anyhow::{bail, Context, Result};
+use futures_util::future::BoxFuture;
+use futures_util::FutureExt;
+use log::warn;
+use nix::errno::Errno;
+use nix::fcntl::{flock, FlockArg};
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::time::{Duration, Instant};
+
+static SESSION_COUNTER: AtomicU64 = AtomicU64::new(0);
+/// How often a lock is retried, if it is busy. Readers hold a shared lock for a moment
+/// to check whether a lock is held, which must not make a free lock look taken.
+const LOCK_RETRIES: usize = 3;
+const LOCK_RETRY_DELAY: Duration = Duration::from_millis(10);
+
+fn new_session_id() -> String {
+    format!(
+        "{}-{}",
+        std::process::id(),
+        SESSION_COUNTER.fetch_add(1, Ordering::SeqCst)
+    )
+}
+
+fn leader_from_metadata(
+    metadata: &HashMap<String, String>,
+    session: &str,
+    lock_index: u64,
+) -> Leader {
+    Leader {
+        node: metadata.get("Hostname").cloned().unwrap_or_default(),
+        name: metadata.get("NodeId").cloned().unwrap_or_default(),
+        session: session.to_string(),
+        lock_index,
+    }
+}
+
+/// Leader election based on `flock(2)` on a file in neard's home.
+/// Locks are released by the kernel if kneard dies, hence sessions never expire.
+/// The metadata of the holder is kept in a separate data file next to the lock file,
+/// which is replaced atomically, so readers never see a partially written value.
+/// The lock file itself is never replaced, since `flock` locks its inode.
+#[derive(Debug)]
+pub struct FileLockElection {
+    directory: PathBuf,
+    /// Open lock files by session id. Dropping a file releases its lock.
+    sessions: Mutex<HashMap<String, HashMap<String, File>>>,
+}
+
+impl FileLockElection {
+    /// Returns a new election backend that stores lock files in `directory`
+    pub fn new(directory: &Path) -> Self {
+        Self {
+            directory: directory.to_owned(),
+            sessions: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn lock_path(&self, key: &str) -> PathBuf {
+        self.directory
+            .join(format!("{}.lock", key.replace('/', "_")))
+    }
+
+    /// Returns the file the metadata of the holder of `lock_path` is stored in
+    fn data_path(lock_path: &Path) -> PathBuf {
+        lock_path.with_extension("json")
+    }
+
+    /// Reads the metadata of the current or last holder of `lock_path`
+    fn read_data(lock_path: &Path) -> Result<Option<HashMap<String, String>>> {
+        let path = Self::data_path(lock_path);
+        let content = match fs::read(&path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).with_context(|| format!("cannot read {}", path.display())),
+        };
+        let metadata = serde_json::from_slice(&content)
+            .with_context(|| format!("{} is not valid json", path.display()))?;
+        Ok(Some(metadata))
+    }
+
+    /// Replaces the metadata of `lock_path` via a temporary file and rename
+    fn write_data(lock_path: &Path, value: &[u8]) -> Result<()> {
+        let path = Self::data_path(lock_path);
+        let tmp_path = path.with_extension("json.tmp");
+        let mut file = File::create(&tmp_path)
+            .with_context(|| format!("cannot create {}", tmp_path.display()))?;
+        file.write_all(value)
+            .and_then(|()| file.sync_all())
+            .with_context(|| format!("cannot write {}", tmp_path.display()))?;
+        fs::rename(&tmp_path, &path)
+            .with_context(|| format!("cannot rename {} to {}", tmp_path.display(), path.display()))
+    }
+
+    fn open_lock_file(&self, key: &str) -> Result<File> {
+        let path = self.lock_path(key);
+        OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .with_context(|| format!("cannot open lock file {}", path.display()))
+    }
+
+    fn acquire(
+        &self,
+        key: &str,
+        metadata: &HashMap<&'static str, String>,
+        session: &ElectionSession,
+    ) -> Result<bool> {
+        let mut sessions = match self.sessions.lock() {
+            Ok(s) => s,
+            Err(e) => bail!("Cannot get session lock: {}", e),
+        };
+        let locks = match sessions.get_mut(session.id()) {
+            Some(locks) => locks,
+            None => bail!(ElectionError::SessionNotFound),
+        };
+        if locks.contains_key(key) {
+            return Ok(true);
+        }
+        let path = self.lock_path(key);
+        let file = self.open_lock_file(key)?;
+        match flock(file.as_raw_fd(), FlockArg::LockExclusiveNonblock) {
+            Ok(()) => {}
+            Err(Errno::EWOULDBLOCK) => return Ok(false),
+            Err(e) => bail!("cannot lock {}: {}", path.display(), e),
+        }
+        // the lock index of the previous holder survives in the data file
+        let previous = Self::read_data(&path).unwrap_or_else(|e| {
+            warn!("Cannot read previous holder of {}: {:#}", path.display(), e);
+            None
+        });
+        let lock_index = previous
+            .and_then(|m| m.get("LockIndex").and_then(|i| i.parse::<u64>().ok()))
+            .unwrap_or(0)
+            + 1;
+        let mut value: HashMap<&str, String> = metadata.clone();
+        value.insert("Session", session.id().to_string());
+        value.insert("LockIndex", lock_index.to_string());
+        let value = serde_json::to_vec(&value).context("Failed to serialize metadata")?;
+        // closing the file on error releases the lock again
+        Self::write_data(&path, &value)?;
+        locks.insert(key.to_string(), file);
+        Ok(true)
+    }
+
+    /// Returns the metadata stored in the lock file, if the lock is currently held
+    fn read_held(path: &Path) -> Result<Option<HashMap<String, String>>> {
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).with_context(|| format!("cannot open {}", path.display())),
+        };
+        match flock(file.as_raw_fd(), FlockArg::LockSharedNonblock) {
+            // nobody holds the lock, closing the file releases our shared lock again
+            Ok(()) => return Ok(None),
+            Err(Errno::EWOULDBLOCK) => {}
+            Err(e) => bail!("cannot lock {}: {}", path.display(), e),
+        }
+        match Self::read_data(path)? {
+            Some(metadata) => Ok(Some(metadata)),
+            // the holder has not written its metadata yet
+            None => bail!("{} is locked, but has no data", path.display()),
+        }
+    }
+
+    fn holder(&self, key: &str) -> Result<Option<Leader>> {
+        Ok(Self::read_held(&self.lock_path(key))?.map(|metadata| {
+            let lock_index = metadata
+                .get("LockIndex")
+                .and_then(|i| i.parse().ok())
+                .unwrap_or_default();
+            let session = metadata.get("Session").cloned().unwrap_or_default();
+            leader_from_metadata(&metadata, &session, lock_index)
+        }))
+    }
+
+    fn held_locks(&self, prefix: &str) -> Result<Vec<HashMap<String, String>>> {
+        let file_prefix = prefix.replace('/', "_");
+        let entries = fs::read_dir(&self.directory)
+            .with_context(|| format!("cannot read {}", self.directory.display()))?;
+        let mut locks = vec![];
+        for entry in entries {
+            let entry = entry.context("cannot read directory entry")?;
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+            if name.starts_with(&file_prefix) && name.ends_with(".lock") {
+                // a single broken entry must not hide all other locks
+                match Self::read_held(&entry.path()) {
+                    Ok(Some(metadata)) => locks.push(metadata),
+                    Ok(None) => {}
+                    Err(e) => warn!("Skip lock {}: {:#}", name, e),
+                }
+            }
+        }
+        Ok(locks)
+    }
+}
+
+impl LeaderElection for FileLockElection {
+    fn create_session<'a>(
+        &'a self,
+        session_name: &'a str,
+        _ttl: Duration,
+    ) -> BoxFuture<'a, Result<ElectionSession>> {
+        async move {
+            let id = new_session_id();
+            match self.sessions.lock() {
+                Ok(mut s) => s.insert(id.clone(), HashMap::new()),
+                Err(e) => bail!("Cannot get session lock: {}", e),
+            };
+            Ok(ElectionSession::new(id, session_name.to_string()))
+        }
+        .boxed()
+    }
+
+    fn renew_session<'a>(&'a self, session: &'a ElectionSession) -> BoxFuture<'a, Result<()>> {
+        async move {
+            match self.sessions.lock() {
+                Ok(s) if s.contains_key(session.id()) => Ok(()),
+                Ok(_) => bail!(ElectionError::SessionNotFound),
+                Err(e) => bail!("Cannot get session lock: {}", e),
+            }
+        }
+        .boxed()
+    }
+
+    fn destroy_session<'a>(&'a self, session: &'a ElectionSession) -> BoxFuture<'a, Result<()>> {
+        async move {
+            match self.sessions.lock() {
+                // dropping the files releases the locks
+                Ok(mut s) => s.remove(session.id()),
+                Err(e) => bail!("Cannot get session lock: {}", e),
+            };
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn acquire_lock<'a>(
+        &'a self,
+        key: &'a str,
+        metadata: &'a HashMap<&'static str, String>,
+        session: &'a ElectionSession,
+    ) -> BoxFuture<'a, Result<bool>> {
+        async move {
+            for _ in 0..LOCK_RETRIES {
+                if self.acquire(key, metadata, session)? {
+                    return Ok(true);
+                }
+                tokio::time::sleep(LOCK_RETRY_DELAY).await;
+            }
+            self.acquire(key, metadata, session)
+        }
+        .boxed()
+    }
+
+    fn release_lock<'a>(
+        &'a self,
+        key: &'a str,
+        session: &'a ElectionSession,
+    ) -> BoxFuture<'a, Result<()>> {
+        async move {
+            match self.sessions.lock() {
+                // dropping the file releases the lock
+                Ok(mut s) => s.get_mut(session.id()).and_then(|locks| locks.remove(key)),
+                Err(e) => bail!("Cannot get session lock: {}", e),
+            };
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn leader<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<Option<Leader>>> {
+        async move { self.holder(key) }.boxed()
+    }
+
+    fn list_locks<'a>(
+        &'a self,
+        prefix: &'a str,
+    ) -> BoxFuture<'a, Result<Vec<HashMap<String, String>>>> {
+        async move { self.held_locks(prefix) }.boxed()
+    }
+
+    fn set_token(&self, _token: Option<&str>) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default)]
+struct MemoryState {
+    /// Expiry and ttl of sessions by id
+    sessions: HashMap<String, (Instant, Duration)>,
+    /// Lock holder and value by key
+    locks: HashMap<String, (String, HashMap<String, String>)>,
+    /// Number of times a key has been acquired
+    lock_indices: HashMap<String, u64>,
+}
+
+impl MemoryState {
+    /// Drops expired sessions together with the locks they hold
+    fn expire(&mut self) {
+        let now = Instant::now();
+        self.sessions.retain(|_, (expires, _)| *expires > now);
+        let sessions = &self.sessions;
+        self.locks
+            .retain(|_, (session, _)| sessions.contains_key(session));
+    }
+}
+
+/// In-process leader election. Clones share their state, so multiple
+/// supervisors in one process can compete for the same lock.
+#[derive(Debug, Default, Clone)]
+pub struct MemoryElection {
+    state: Arc<Mutex<MemoryState>>,
+}
+
+impl MemoryElection {
+    /// Returns a new election backend without any sessions
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn with_state<T>(&self, f: impl FnOnce(&mut MemoryState) -> Result<T>) -> Result<T> {
+        match self.state.lock() {
+            Ok(mut state) => {
+                state.expire();
+                f(&mut state)
+            }
+            Err(e) => bail!("Cannot get state lock: {}", e),
+        }
+    }
+}
+
+impl LeaderElection for MemoryElection {
+    fn create_session<'a>(
+        &'a self,
+        session_name: &'a str,
+        ttl: Duration,
+    ) -> BoxFuture<'a, Result<ElectionSession>> {
+        async move {
+            let id = new_session_id();
+            self.with_state(|state| {
+                state
+                    .sessions
+                    .insert(id.clone(), (Instant::now() + ttl, ttl));
+                Ok(())
+            })?;
+            Ok(ElectionSession::new(id, session_name.to_string()))
+        }
+        .boxed()
+    }
+
+    fn renew_session<'a>(&'a self, session: &'a ElectionSession) -> BoxFuture<'a, Result<()>> {
+        async move {
+            self.with_state(|state| match state.sessions.get_mut(session.id()) {
+                Some((expires, ttl)) => {
+                    *expires = Instant::now() + *ttl;
+                    Ok(())
+                }
+                None => bail!(ElectionError::SessionNotFound),
+            })
+        }
+        .boxed()
+    }
+
+    fn destroy_session<'a>(&'a self, session: &'a ElectionSession) -> BoxFuture<'a, Result<()>> {
+        async move {
+            self.with_state(|state| {
+                state.sessions.remove(session.id());
+                state.expire();
+                Ok(())
+            })
+        }
+        .boxed()
+    }
+
+    fn acquire_lock<'a>(
+        &'a self,
+        key: &'a str,
+        metadata: &'a HashMap<&'static str, String>,
+        session: &'a ElectionSession,
+    ) -> BoxFuture<'a, Result<bool>> {
+        async move {
+            self.with_state(|state| {
+                if !state.sessions.contains_key(session.id()) {
+                    bail!(ElectionError::SessionNotFound)
+                }
+                let value = metadata
+                    .iter()
+                    .map(|(k, v)| (k.to_string(), v.clone()))
+                    .collect();
+                match state.locks.get(key) {
+                    Some((holder, _)) if holder != session.id() => Ok(false),
+                    Some(_) => {
+                        state
+                            .locks
+                            .insert(key.to_string(), (session.id().to_string(), value));
+                        Ok(true)
+                    }
+                    None => {
+                        *state.lock_indices.entry(key.to_string()).or_default() += 1;
+                        state
+                            .locks
+                            .insert(key.to_string(), (session.id().to_string(), value));
+                        Ok(true)
+                    }
+                }
+            })
+        }
+        .boxed()
+    }
+
+    fn release_lock<'a>(
+        &'a self,
+        key: &'a str,
+        session: &'a ElectionSession,
+    ) -> BoxFuture<'a, Result<()>> {
+        async move {
+            self.with_state(|state| {
+                if state
+                    .locks
+                    .get(key)
+                    .is_some_and(|(holder, _)| holder == session.id())
+                {
+                    state.locks.remove(key);
+                }
+                Ok(())
+            })
+        }
+        .boxed()
+    }
+
+    fn leader<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<Option<Leader>>> {
+        async move {
+            self.with_state(|state| {
+                let lock_index = state.lock_indices.get(key).copied().unwrap_or_default();
+                Ok(state
+                    .locks
+                    .get(key)
+                    .map(|(session, metadata)| leader_from_metadata(metadata, session, lock_index)))
+            })
+        }
+        .boxed()
+    }
+
+    fn list_locks<'a>(
+        &'a self,
+        prefix: &'a str,
+    ) -> BoxFuture<'a, Result<Vec<HashMap<String, String>>>> {
+        async move {
+            self.with_state(|state| {
+                Ok(state
+                    .locks
+                    .iter()
+                    .filter(|(key, _)| key.starts_with(prefix))
+                    .map(|(_, (_, metadata))| metadata.clone())
+                    .collect())
+            })
+        }
+        .boxed()
+    }
+
+    fn set_token(&self, _token: Option<&str>) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+fn test_metadata(node_id: &str) -> HashMap<&'static str, String> {
+    HashMap::from([("Hostname", "host".to_string()), ("NodeId", node_id.into())])
+}
+
+#[tokio::test]
+async fn test_file_lock_election() {
+    let dir = tempfile::tempdir().unwrap();
+    let election = FileLockElection::new(dir.path());
+    let ttl = Duration::from_secs(30);
+    let s1 = election.create_session("node0", ttl).await.unwrap();
+    let s2 = election.create_session("node1", ttl).await.unwrap();
+
+    assert_eq!(election.leader("leader/pool").await.unwrap(), None);
+    assert!(election
+        .acquire_lock("leader/pool", &test_metadata("node0"), &s1)
+        .await
+        .unwrap());
+    // re-acquiring is fine, but a second session cannot take the lock
+    assert!(election
+        .acquire_lock("leader/pool", &test_metadata("node0"), &s1)
+        .await
+        .unwrap());
+    assert!(!election
+        .acquire_lock("leader/pool", &test_metadata("node1"), &s2)
+        .await
+        .unwrap());
+    let leader = election.leader("leader/pool").await.unwrap().unwrap();
+    assert_eq!(leader.name, "node0");
+    assert_eq!(leader.session, s1.id());
+    assert_eq!(leader.lock_index, 1);
+
+    assert!(election
+        .acquire_lock("candidates/pool/node1", &test_metadata("node1"), &s2)
+        .await
+        .unwrap());
+    let candidates = election.list_locks("candidates/pool/").await.unwrap();
+    assert_eq!(candidates.len(), 1);
+    assert_eq!(candidates[0]["NodeId"], "node1");
+    election
+        .release_lock("candidates/pool/node1", &s2)
+        .await
+        .unwrap();
+    assert_eq!(
+        election.list_locks("candidates/pool/").await.unwrap(),
+        vec![]
+    );
+    // only the holder can release a lock
+    election.release_lock("leader/pool", &s2).await.unwrap();
+    assert_eq!(
+        election.leader("leader/pool").await.unwrap().unwrap().name,
+        "node0"
+    );
+
+    // a broken entry is skipped when listing locks
+    assert!(election
+        .acquire_lock("candidates/pool/node0", &test_metadata("node0"), &s1)
+        .await
+        .unwrap());
+    assert!(election
+        .acquire_lock("candidates/pool/node1", &test_metadata("node1"), &s2)
+        .await
+        .unwrap());
+    fs::write(
+        dir.path().join("candidates_pool_node0.json"),
+        "{\"NodeId\":",
+    )
+    .unwrap();
+    let candidates = election.list_locks("candidates/pool/").await.unwrap();
+    assert_eq!(candidates.len(), 1);
+    assert_eq!(candidates[0]["NodeId"], "node1");
+
+    // a reader that checks the lock at the same time does not keep us from taking it
+    let s3 = election.create_session("node2", ttl).await.unwrap();
+    let reader = election.open_lock_file("candidates/pool/node2").unwrap();
+    flock(reader.as_raw_fd(), FlockArg::LockSharedNonblock).unwrap();
+    let release = std::thread::spawn(move || {
+        std::thread::sleep(std::time::Duration::from_millis(5));
+        drop(reader);
+    });
+    assert!(election
+        .acquire_lock("candidates/pool/node2", &test_metadata("node2"), &s3)
+        .await
+        .unwrap());
+    release.join().unwrap();
+
+    election.destroy_session(&s1).await.unwrap();
+    assert_eq!(election.leader("leader/pool").await.unwrap(), None);
+    assert!(election.renew_session(&s1).await.is_err());
+    assert!(election
+        .acquire_lock("leader/pool", &test_metadata("node1"), &s2)
+        .await
+        .unwrap());
+    // the fencing token increases with every new holder
+    assert_eq!(
+        election
+            .leader("leader/pool")
+            .await
+            .unwrap()
+            .unwrap()
+            .lock_index,
+        2
+    );
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_memory_election_expires_sessions() {
+    let election = MemoryElection::new();
+    let ttl = Duration::from_secs(30);
+    let s1 = election.create_session("node0", ttl).await.unwrap();
+    let s2 = election.create_session("node1", ttl).await.unwrap();
+
+    assert!(election
+        .acquire_lock("leader/pool", &test_metadata("node0"), &s1)
+        .await
+        .unwrap());
+    assert!(!election
+        .acquire_lock("leader/pool", &test_metadata("node1"), &s2)
+        .await
+        .unwrap());
+
+    tokio::time::advance(Duration::from_secs(20)).await;
+    election.renew_session(&s2).await.unwrap();
+    tokio::time::advance(Duration::from_secs(20)).await;
+
+    // s1 was not renewed and has expired together with its lock
+    let err = election.renew_session(&s1).await.unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<ElectionError>(),
+        Some(ElectionError::SessionNotFound)
+    ));
+    assert_eq!(election.leader("leader/pool").await.unwrap(), None);
+    assert!(election
+        .acquire_lock("leader/pool", &test_metadata("node1"), &s2)
+        .await
+        .unwrap());
+    assert_eq!(
+        election.leader("leader/pool").await.unwrap().unwrap().name,
+        "node1"
+    );
+}
@@ -0,0 +1,278 @@
+//! Lists the maintenance windows of our validator and picks one for a restart
+
+use crate::near_client::NeardApi;
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Utc};
+use near_primitives::types::{AccountId, BlockHeight, BlockHeightDelta};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::time::Duration;
+
+/// Blocks between scheduling a shutdown and the shutdown, so that neard picks up its new config in time
+const SHUTDOWN_MARGIN: BlockHeightDelta = 2;
+
+/// Epoch a maintenance window belongs to
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Epoch {
+    /// The current epoch
+    Current,
+    /// The epoch after the current one
+    Next,
+}
+
+impl fmt::Display for Epoch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Epoch::Current => write!(f, "current"),
+            Epoch::Next => write!(f, "next"),
+        }
+    }
+}
+
+/// Block heights in which our validator neither produces blocks nor chunks
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct MaintenanceWindow {
+    /// First block height of the window
+    pub start: BlockHeight,
+    /// First block height after the window
+    pub end: BlockHeight,
+    /// Epoch of the window
+    pub epoch: Epoch,
+    /// Estimated time of the start, None if the block rate is unknown
+    pub start_eta: Option<DateTime<Utc>>,
+    /// Estimated time of the end, None if the block rate is unknown
+    pub end_eta: Option<DateTime<Utc>>,
+}
+
+impl MaintenanceWindow {
+    /// Number of blocks in the window
+    pub fn length(&self) -> BlockHeightDelta {
+        self.end.saturating_sub(self.start)
+    }
+}
+
+/// Upcoming maintenance windows of our validator
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MaintenancePlan {
+    /// Latest block height of neard
+    pub head: BlockHeight,
+    /// Average seconds between two blocks, None if unknown
+    pub block_time: Option<f64>,
+    /// Windows of the current and the next epoch that did not end yet, ordered by their start
+    pub windows: Vec<MaintenanceWindow>,
+    /// Whether we validate in the next epoch. Its windows are only known once it started.
+    pub validating_next_epoch: bool,
+}
+
+impl MaintenancePlan {
+    /// Queries the maintenance windows of `account_id` from neard
+    pub async fn query(c: &dyn NeardApi, account_id: &AccountId) -> Result<Self> {
+        let status = c.status().await.context("failed to get status")?;
+        let windows = c
+            .maintenance_windows(account_id)
+            .await
+            .context("failed to get maintenance windows")?;
+        let validators = c.validators().await.context("failed to get validators")?;
+        let config = c
+            .protocol_config()
+            .await
+            .context("failed to get protocol config")?;
+
+        let sync = &status.sync_info;
+        // averaged over all blocks neard kept, usually several epochs
+        let block_time = match (sync.earliest_block_height, sync.earliest_block_time) {
+            (Some(height), Some(time)) if sync.latest_block_height > height => {
+                let millis = (sync.latest_block_time - time).num_milliseconds();
+                Some(millis as f64 / 1000.0 / (sync.latest_block_height - height) as f64)
+            }
+            _ => None,
+        };
+        let next_epoch = if validators
+            .next_validators
+            .iter()
+            .any(|v| &v.account_id == account_id)
+        {
+            None
+        } else {
+            let start = validators.epoch_start_height + config.epoch_length;
+            Some((start, start + config.epoch_length))
+        };
+        Ok(Self::new(
+            sync.latest_block_height,
+            sync.latest_block_time,
+            block_time,
+            windows.0,
+            next_epoch,
+        ))
+    }
+
+    fn new(
+        head: BlockHeight,
+        head_time: DateTime<Utc>,
+        block_time: Option<f64>,
+        current: Vec<(BlockHeight, BlockHeight)>,
+        next_epoch: Option<(BlockHeight, BlockHeight)>,
+    ) -> Self {
+        let eta = |height: BlockHeight| {
+            block_time.map(|t| {
+                let blocks = height as f64 - head as f64;
+                head_time + chrono::Duration::milliseconds((blocks * t * 1000.0) as i64)
+            })
+        };
+        let mut windows = current
+            .into_iter()
+            .map(|(start, end)| (start, end, Epoch::Current))
+            .chain(next_epoch.map(|(start, end)| (start, end, Epoch::Next)))
+            .filter(|(_, end, _)| *end > head)
+            .map(|(start, end, epoch)| MaintenanceWindow {
+                start,
+                end,
+                epoch,
+                start_eta: eta(start),
+                end_eta: eta(end),
+            })
+            .collect::<Vec<_>>();
+        windows.sort_by_key(|w| w.start);
+        Self {
+            head,
+            block_time,
+            windows,
+            validating_next_epoch: next_epoch.is_none(),
+        }
+    }
+
+    /// Returns the block height to shut down at for a restart. This is the earliest
+    /// window that leaves at least `minimum_length` blocks after the shutdown, or the
+    /// longest window of the current epoch if no minimum length is given.
+    pub fn shutdown_height(&self, minimum_length: Option<BlockHeightDelta>) -> Result<BlockHeight> {
+        let mut candidates = self.windows.iter().filter_map(|w| {
+            let at = w.start.max(self.head) + SHUTDOWN_MARGIN;
+            (at < w.end).then_some((at, w.end - at, w.epoch))
+        });
+        let chosen = match minimum_length.filter(|l| *l > 0) {
+            Some(minimum_length) => match candidates.find(|(_, length, _)| *length >= minimum_length) {
+                Some(c) => c,
+                None => bail!(
+                    "Neard has no maintenance window of size ({}) in the current or next epoch, please wait",
+                    minimum_length
+                ),
+            },
+            // the earliest one of the longest windows. If we do not validate in the next epoch,
+            // it is a single window that is usually hours away, so it is not considered.
+            None => match candidates
+                .filter(|(_, _, epoch)| *epoch == Epoch::Current)
+                .reduce(|best, c| if c.1 > best.1 { c } else { best })
+            {
+                Some(c) => c,
+                None => bail!("Neard has no maintenance window in the current epoch, please wait"),
+            },
+        };
+        Ok(chosen.0)
+    }
+
+    /// Number of blocks the network produces in `duration`, None if the block rate is unknown
+    pub fn blocks_in(&self, duration: Duration) -> Option<BlockHeightDelta> {
+        self.block_time
+            .filter(|t| *t > 0.0)
+            .map(|t| (duration.as_secs_f64() / t).ceil() as BlockHeightDelta)
+    }
+}
+
+impl fmt::Display for MaintenancePlan {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let eta = |t: Option<DateTime<Utc>>| {
+            t.map_or("unknown".to_string(), |t| {
+                t.format("%Y-%m-%d %H:%M:%S UTC").to_string()
+            })
+        };
+        let mut lines = vec![match self.block_time {
+            Some(t) => format!("Head: {} ({t:.2}s per block)", self.head),
+            None => format!("Head: {} (block rate unknown)", self.head),
+        }];
+        if self.windows.is_empty() {
+            lines.push("No maintenance windows".to_string());
+        }
+        lines.extend(self.windows.iter().map(|w| {
+            format!(
+                "{}..{} ({} blocks, {} epoch): {} - {}",
+                w.start,
+                w.end,
+                w.length(),
+                w.epoch,
+                eta(w.start_eta),
+                eta(w.end_eta)
+            )
+        }));
+        if self.validating_next_epoch {
+            lines.push(
+                "Windows of the next epoch are known once it started, since we validate in it"
+                    .to_string(),
+            );
+        }
+        write!(f, "{}", lines.join("\n"))
+    }
+}
+
+#[test]
+fn test_maintenance_plan() {
+    let head_time = "2023-01-01T00:00:00Z".parse::<DateTime<Utc>>().unwrap();
+    let plan = MaintenancePlan::new(
+        1000,
+        head_time,
+        Some(1.5),
+        vec![(1100, 1150), (900, 1010), (800, 900), (1200, 1300)],
+        Some((2000, 3000)),
+    );
+    let windows = plan
+        .windows
+        .iter()
+        .map(|w| (w.start, w.end, w.epoch))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        windows,
+        vec![
+            (900, 1010, Epoch::Current),
+            (1100, 1150, Epoch::Current),
+            (1200, 1300, Epoch::Current),
+            (2000, 3000, Epoch::Next),
+        ]
+    );
+    assert_eq!(
+        plan.windows[1].start_eta,
+        Some("2023-01-01T00:02:30Z".parse().unwrap())
+    );
+    assert!(!plan.validating_next_epoch);
+
+    // the running window leaves 8 blocks after the shutdown
+    assert_eq!(plan.shutdown_height(Some(8)).unwrap(), 1002);
+    assert_eq!(plan.shutdown_height(Some(9)).unwrap(), 1102);
+    assert_eq!(plan.shutdown_height(Some(98)).unwrap(), 1202);
+    assert_eq!(plan.shutdown_height(Some(99)).unwrap(), 2002);
+    assert!(plan.shutdown_height(Some(1000)).is_err());
+    // we do not validate in the next epoch, which is longer than any window of the
+    // current epoch, but without a minimum length we restart in the current epoch
+    assert_eq!(plan.shutdown_height(None).unwrap(), 1202);
+    assert_eq!(plan.shutdown_height(Some(0)).unwrap(), 1202);
+    assert_eq!(plan.blocks_in(Duration::from_secs(60)), Some(40));
+
+    // the earliest of equally long windows is used
+    let plan = MaintenancePlan::new(
+        1000,
+        head_time,
+        None,
+        vec![(1200, 1300), (1100, 1200)],
+        None,
+    );
+    assert_eq!(plan.shutdown_height(None).unwrap(), 1102);
+    assert_eq!(plan.windows[0].start_eta, None);
+    assert_eq!(plan.blocks_in(Duration::from_secs(60)), None);
+    assert!(plan.validating_next_epoch);
+
+    let plan = MaintenancePlan::new(1000, head_time, None, vec![], None);
+    assert!(plan.shutdown_height(None).is_err());
+    // only the next epoch is left
+    let plan = MaintenancePlan::new(1000, head_time, None, vec![(900, 1000)], Some((2000, 3000)));
+    assert!(plan.shutdown_height(None).is_err());
+    assert_eq!(plan.shutdown_height(Some(10)).unwrap(), 2002);
+}
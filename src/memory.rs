@@ -0,0 +1,197 @@
+//! Watches the memory pressure of the host and the memory neard uses, so that the
+//! validator restarts or gives up its leadership before neard is killed by the OOM killer
+
+use crate::settings::Settings;
+use anyhow::{Context, Result};
+use lazy_static::lazy_static;
+use log::warn;
+use nix::unistd::Pid;
+use prometheus::{register_gauge_vec, register_int_gauge, GaugeVec, IntGauge};
+use std::fmt;
+use std::fs;
+
+lazy_static! {
+    static ref MEMORY_PRESSURE: GaugeVec = register_gauge_vec!(
+        "kuutamod_memory_pressure",
+        "Percentage of time in the last 10s some or all (type) tasks were stalled on memory",
+        &["type"],
+    )
+    .unwrap();
+    static ref NEARD_RSS: IntGauge = register_int_gauge!(
+        "kuutamod_neard_rss_bytes",
+        "Resident memory of the neard process"
+    )
+    .unwrap();
+}
+
+/// How the validator reacts if neard exceeds the memory rules
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryAction {
+    /// Restart neard in the next maintenance window
+    Restart,
+    /// Give up leadership right away, so that a standby takes over
+    StepDown,
+}
+
+/// Memory thresholds for neard. `None` disables a rule.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MemoryRules {
+    /// Maximum resident memory of neard in bytes
+    pub max_rss: Option<u64>,
+    /// Maximum percentage of time all tasks were stalled on memory in the last 10s
+    pub max_pressure: Option<f64>,
+    /// What the validator does if a rule is exceeded
+    pub action: MemoryAction,
+}
+
+impl MemoryRules {
+    /// Returns the rules configured in the settings
+    pub fn from_settings(settings: &Settings) -> Self {
+        Self {
+            max_rss: settings.memory_max_rss.map(|mib| mib * 1024 * 1024),
+            max_pressure: settings.memory_max_pressure,
+            action: settings.memory_action,
+        }
+    }
+
+    /// Queries the memory pressure and the memory of neard with `pid`, exports them as
+    /// metrics and returns the rule that is exceeded, if any
+    pub fn check(&self, pid: Option<Pid>) -> Option<MemoryIssue> {
+        let mut issue = None;
+        match MemoryPressure::query() {
+            Ok(pressure) => {
+                pressure.export();
+                if self.max_pressure.is_some_and(|max| pressure.full > max) {
+                    issue = Some(MemoryIssue::Pressure(pressure.full));
+                }
+            }
+            // i.e. kernels without CONFIG_PSI
+            Err(e) if self.max_pressure.is_some() => warn!("Cannot get memory pressure: {:#}", e),
+            Err(_) => {}
+        }
+        if let Some(pid) = pid {
+            match neard_rss(pid) {
+                Ok(rss) => {
+                    NEARD_RSS.set(rss as i64);
+                    if self.max_rss.is_some_and(|max| rss > max) {
+                        issue = Some(MemoryIssue::Rss(rss));
+                    }
+                }
+                Err(e) => warn!("Cannot get memory of neard: {:#}", e),
+            }
+        }
+        issue
+    }
+}
+
+/// A memory rule neard exceeds
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MemoryIssue {
+    /// Resident memory of neard in bytes
+    Rss(u64),
+    /// Percentage of time all tasks were stalled on memory
+    Pressure(f64),
+}
+
+impl fmt::Display for MemoryIssue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MemoryIssue::Rss(rss) => write!(f, "neard uses {} MiB of memory", rss / 1024 / 1024),
+            MemoryIssue::Pressure(full) => {
+                write!(f, "all tasks were stalled on memory {full:.2}% of the time")
+            }
+        }
+    }
+}
+
+/// Memory pressure of the host as reported in `/proc/pressure/memory`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MemoryPressure {
+    /// Percentage of time in the last 10s at least one task was stalled on memory
+    pub some: f64,
+    /// Percentage of time in the last 10s all tasks were stalled on memory
+    pub full: f64,
+}
+
+fn parse_avg10(line: &str) -> Option<f64> {
+    line.split_whitespace()
+        .find_map(|field| field.strip_prefix("avg10="))
+        .and_then(|avg| avg.parse().ok())
+}
+
+impl MemoryPressure {
+    /// Reads the memory pressure of the host
+    pub fn query() -> Result<Self> {
+        let content = fs::read_to_string("/proc/pressure/memory")
+            .context("cannot read /proc/pressure/memory")?;
+        Self::parse(&content).context("invalid /proc/pressure/memory")
+    }
+
+    fn parse(content: &str) -> Option<Self> {
+        let mut some = None;
+        let mut full = None;
+        for line in content.lines() {
+            match line.split_once(' ') {
+                Some(("some", _)) => some = parse_avg10(line),
+                Some(("full", _)) => full = parse_avg10(line),
+                _ => {}
+            }
+        }
+        Some(Self {
+            some: some?,
+            // older kernels only report `some`
+            full: full.unwrap_or(0.0),
+        })
+    }
+
+    fn export(&self) {
+        MEMORY_PRESSURE.with_label_values(&["some"]).set(self.some);
+        MEMORY_PRESSURE.with_label_values(&["full"]).set(self.full);
+    }
+}
+
+fn parse_rss(status: &str) -> Option<u64> {
+    let line = status.lines().find_map(|l| l.strip_prefix("VmRSS:"))?;
+    let kib = line.trim().strip_suffix("kB")?.trim().parse::<u64>().ok()?;
+    Some(kib * 1024)
+}
+
+/// Returns the resident memory of the process with `pid` in bytes
+pub fn neard_rss(pid: Pid) -> Result<u64> {
+    let path = format!("/proc/{pid}/status");
+    let status = fs::read_to_string(&path).with_context(|| format!("cannot read {path}"))?;
+    parse_rss(&status).with_context(|| format!("no VmRSS in {path}"))
+}
+
+#[test]
+fn test_memory_rules() {
+    let pressure = MemoryPressure::parse(
+        "some avg10=12.50 avg60=3.10 avg300=0.80 total=123456\nfull avg10=4.25 avg60=1.00 avg300=0.20 total=23456\n",
+    )
+    .unwrap();
+    assert_eq!(
+        pressure,
+        MemoryPressure {
+            some: 12.5,
+            full: 4.25
+        }
+    );
+    assert_eq!(MemoryPressure::parse(""), None);
+
+    let status = "Name:\tneard\nVmPeak:\t 9000000 kB\nVmRSS:\t 2048 kB\nThreads:\t42\n";
+    assert_eq!(parse_rss(status), Some(2 * 1024 * 1024));
+    assert_eq!(parse_rss("Name:\tkthreadd\n"), None);
+
+    // kneard itself always has some resident memory
+    let rss = neard_rss(Pid::this()).unwrap();
+    assert!(rss > 0);
+    let rules = MemoryRules {
+        max_rss: Some(rss / 2),
+        max_pressure: None,
+        action: MemoryAction::Restart,
+    };
+    match rules.check(Some(Pid::this())) {
+        Some(issue @ MemoryIssue::Rss(_)) => assert!(issue.to_string().starts_with("neard uses")),
+        issue => panic!("unexpected {issue:?}"),
+    }
+}
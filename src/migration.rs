@@ -0,0 +1,178 @@
+//! Records the db version neard runs with and how long neard took to migrate its
+//! database to a new version, so that restarts into a new db version can be planned
+
+use crate::neard_binary::{NeardBinaries, NeardVersion};
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs;
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+/// How many migrations are kept for the estimate
+const MAX_MIGRATIONS: usize = 10;
+
+/// A migration of the database that neard ran on startup
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Migration {
+    /// Db version before the migration
+    pub from: u32,
+    /// Db version after the migration
+    pub to: u32,
+    /// Seconds from starting neard until its status api was reachable
+    pub duration: u64,
+    /// When neard was reachable again
+    pub finished_at: DateTime<Utc>,
+}
+
+/// A migration neard runs on its next start
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ExpectedMigration {
+    /// Db version of the database in neard's home
+    pub from: u32,
+    /// Db version of the binary neard is started with next
+    pub to: u32,
+    /// Estimated seconds the migration takes, None if neard never migrated its database
+    pub estimate: Option<u64>,
+}
+
+impl fmt::Display for ExpectedMigration {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "db migration from version {} to {}", self.from, self.to)?;
+        match self.estimate {
+            Some(estimate) => write!(f, " (estimated {estimate}s)"),
+            None => write!(f, " (duration unknown)"),
+        }
+    }
+}
+
+/// Db version of the database in neard's home and the migrations neard ran
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct DbVersions {
+    /// Db version of the binary that last started neard, None if unknown
+    pub db: Option<u32>,
+    /// Latest migrations, oldest first
+    pub migrations: Vec<Migration>,
+}
+
+fn db_versions_file(neard_home: &Path) -> PathBuf {
+    neard_home.join("kuutamod-db-versions.json")
+}
+
+impl DbVersions {
+    /// Returns the db versions recorded in `neard_home`
+    pub fn load(neard_home: &Path) -> Result<Self> {
+        let path = db_versions_file(neard_home);
+        let content = match fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => return Err(e).with_context(|| format!("cannot read {}", path.display())),
+        };
+        serde_json::from_str(&content).with_context(|| format!("invalid {}", path.display()))
+    }
+
+    /// Stores the db versions in `neard_home`
+    pub fn save(&self, neard_home: &Path) -> Result<()> {
+        let path = db_versions_file(neard_home);
+        let mut file = tempfile::NamedTempFile::new_in(neard_home)
+            .with_context(|| format!("cannot create temporary file in {}", neard_home.display()))?;
+        let content = serde_json::to_string(self).context("cannot serialize db versions")?;
+        file.write_all(content.as_bytes())
+            .context("cannot write db versions")?;
+        file.persist(&path)
+            .with_context(|| format!("cannot write {}", path.display()))?;
+        Ok(())
+    }
+
+    /// Records that neard with db version `db` became reachable `startup` after it was started.
+    /// Returns the migration, if neard had to migrate its database.
+    pub fn record_startup(
+        &mut self,
+        db: u32,
+        startup: Duration,
+        now: SystemTime,
+    ) -> Option<&Migration> {
+        let from = self.db.replace(db)?;
+        if from == db {
+            return None;
+        }
+        self.migrations.push(Migration {
+            from,
+            to: db,
+            duration: startup.as_secs(),
+            finished_at: DateTime::<Utc>::from(now),
+        });
+        let skip = self.migrations.len().saturating_sub(MAX_MIGRATIONS);
+        self.migrations.drain(..skip);
+        self.migrations.last()
+    }
+
+    /// Returns the migration neard runs when it is started with db version `db`
+    pub fn expected_migration(&self, db: u32) -> Option<ExpectedMigration> {
+        let from = self.db.filter(|from| *from != db)?;
+        Some(ExpectedMigration {
+            from,
+            to: db,
+            // migrations differ a lot, so we plan with the longest one
+            estimate: self.migrations.iter().map(|m| m.duration).max(),
+        })
+    }
+}
+
+/// Returns the migration neard runs when it is started with the staged binary,
+/// or with the current one if none is staged
+pub async fn next_migration(neard_home: &Path) -> Result<Option<ExpectedMigration>> {
+    let binaries = NeardBinaries::load(neard_home)?;
+    let next = binaries
+        .staged
+        .as_deref()
+        .unwrap_or_else(|| binaries.current_binary());
+    let version = NeardVersion::query(next).await?;
+    Ok(DbVersions::load(neard_home)?.expected_migration(version.db))
+}
+
+#[test]
+fn test_db_versions() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut versions = DbVersions::load(dir.path()).unwrap();
+    let now = SystemTime::UNIX_EPOCH;
+    assert_eq!(versions.expected_migration(34), None);
+    assert_eq!(
+        versions.record_startup(34, Duration::from_secs(5), now),
+        None
+    );
+    assert_eq!(
+        versions.record_startup(34, Duration::from_secs(5), now),
+        None
+    );
+    assert_eq!(
+        versions.expected_migration(35),
+        Some(ExpectedMigration {
+            from: 34,
+            to: 35,
+            estimate: None
+        })
+    );
+
+    let migration = versions
+        .record_startup(35, Duration::from_secs(600), now)
+        .unwrap();
+    assert_eq!(
+        (migration.from, migration.to, migration.duration),
+        (34, 35, 600)
+    );
+    versions.record_startup(36, Duration::from_secs(300), now);
+    versions.save(dir.path()).unwrap();
+
+    let versions = DbVersions::load(dir.path()).unwrap();
+    assert_eq!(versions.db, Some(36));
+    assert_eq!(versions.expected_migration(36), None);
+    let expected = versions.expected_migration(37).unwrap();
+    assert_eq!(expected.estimate, Some(600));
+    assert_eq!(
+        expected.to_string(),
+        "db migration from version 36 to 37 (estimated 600s)"
+    );
+}
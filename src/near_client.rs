@@ -1,11 +1,18 @@
 //! Module to interact with the neard daemon
 
-use anyhow::{Context, Result};
-use near_primitives::{account::id::AccountId, types::BlockHeight, views::StatusResponse};
+use anyhow::{bail, Context, Result};
+use futures_util::future::BoxFuture;
+use futures_util::FutureExt;
+use near_primitives::{
+    account::id::AccountId,
+    types::{BlockHeight, BlockHeightDelta},
+    views::{EpochValidatorInfo, StatusResponse},
+};
 use reqwest::{Client, Url};
-use serde::{Deserialize, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use serde_json::json;
 use std::collections::HashMap;
+use std::fmt;
 use tokio::time::{sleep_until, Duration, Instant};
 
 /// The result for maintenance windows rpc
@@ -34,6 +41,81 @@ pub struct BlockDetailJsonRpcStatusResponse {
     pub result: serde_json::Value,
 }
 
+/// A peer of neard as returned by the `network_info` rpc
+#[derive(Deserialize, Clone, Debug)]
+pub struct NetworkInfoPeer {
+    /// Public key of the node key of the peer
+    pub id: String,
+    /// Address of the peer
+    #[serde(default)]
+    pub addr: Option<String>,
+    /// Validator account of the peer, if it announced one
+    #[serde(default)]
+    pub account_id: Option<AccountId>,
+}
+
+/// A block producer that announced its account as returned by the `network_info` rpc
+#[derive(Deserialize, Clone, Debug)]
+pub struct NetworkInfoProducer {
+    /// Validator account
+    pub account_id: AccountId,
+    /// Public key of the node key that announced the account
+    pub peer_id: String,
+}
+
+/// The result of the `network_info` rpc
+#[derive(Deserialize, Clone, Debug, Default)]
+pub struct NetworkInfo {
+    /// Peers neard is connected to
+    #[serde(default)]
+    pub active_peers: Vec<NetworkInfoPeer>,
+    /// Block producers known to neard
+    #[serde(default)]
+    pub known_producers: Vec<NetworkInfoProducer>,
+}
+
+/// The parts of the `EXPERIMENTAL_protocol_config` rpc we use
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ProtocolConfig {
+    /// Percentage of expected blocks a validator has to produce to not be kicked out
+    pub block_producer_kickout_threshold: u8,
+    /// Percentage of expected chunks a validator has to produce to not be kicked out
+    pub chunk_producer_kickout_threshold: u8,
+    /// Number of blocks in an epoch
+    pub epoch_length: BlockHeightDelta,
+}
+
+/// The response of a json rpc call
+#[derive(Deserialize)]
+struct JsonRpcResponse<T> {
+    result: Option<T>,
+    error: Option<serde_json::Value>,
+}
+
+/// The parts of the neard api the supervisor depends on
+pub trait NeardApi: fmt::Debug + Send + Sync {
+    /// Request neard status
+    fn status(&self) -> BoxFuture<'_, Result<StatusResponse>>;
+
+    /// Request maintenance windows of the given account
+    fn maintenance_windows<'a>(
+        &'a self,
+        account_id: &'a AccountId,
+    ) -> BoxFuture<'a, Result<MaintenanceWindowRPCResult>>;
+
+    /// Request peers and known block producers of neard
+    fn network_info(&self) -> BoxFuture<'_, Result<NetworkInfo>>;
+
+    /// Request validators of the current epoch
+    fn validators(&self) -> BoxFuture<'_, Result<EpochValidatorInfo>>;
+
+    /// Request the protocol config of the final block
+    fn protocol_config(&self) -> BoxFuture<'_, Result<ProtocolConfig>>;
+
+    /// Request metrics
+    fn metrics(&self) -> BoxFuture<'_, Result<HashMap<String, String>>>;
+}
+
 /// A client implementing the neard status api
 #[derive(Debug)]
 pub struct NeardClient {
@@ -80,6 +162,49 @@ impl NeardClient {
         })
     }
 
+    async fn rpc<T: DeserializeOwned>(&self, method: &str, params: serde_json::Value) -> Result<T> {
+        let res = self
+            .client
+            .post(self.url.clone())
+            .json(&json!({
+                 "jsonrpc": "2.0",
+                 "method": method,
+                 "id": "dontcare",
+                 "params": params,
+            }))
+            .send()
+            .await
+            .with_context(|| format!("Failed to request {method}"))?;
+        let r: JsonRpcResponse<T> = res
+            .json()
+            .await
+            .with_context(|| format!("Failed to decode {method} response"))?;
+        match (r.result, r.error) {
+            (Some(result), _) => Ok(result),
+            (None, Some(error)) => bail!("{} failed: {}", method, error),
+            (None, None) => bail!("{} returned no result", method),
+        }
+    }
+
+    /// Request peers and known block producers of neard
+    pub async fn network_info(&self) -> Result<NetworkInfo> {
+        self.rpc("network_info", json!([])).await
+    }
+
+    /// Request validators of the current epoch
+    pub async fn validators(&self) -> Result<EpochValidatorInfo> {
+        self.rpc("validators", json!([null])).await
+    }
+
+    /// Request the protocol config of the final block
+    pub async fn protocol_config(&self) -> Result<ProtocolConfig> {
+        self.rpc(
+            "EXPERIMENTAL_protocol_config",
+            json!({ "finality": "final" }),
+        )
+        .await
+    }
+
     /// Request final block details
     pub async fn final_block(&self) -> Result<BlockHeight> {
         let mut params = HashMap::<String, serde_json::Value>::new();
@@ -163,3 +288,32 @@ impl NeardClient {
         Ok(metrics)
     }
 }
+
+impl NeardApi for NeardClient {
+    fn status(&self) -> BoxFuture<'_, Result<StatusResponse>> {
+        NeardClient::status(self).boxed()
+    }
+
+    fn maintenance_windows<'a>(
+        &'a self,
+        account_id: &'a AccountId,
+    ) -> BoxFuture<'a, Result<MaintenanceWindowRPCResult>> {
+        NeardClient::maintenance_windows(self, account_id).boxed()
+    }
+
+    fn network_info(&self) -> BoxFuture<'_, Result<NetworkInfo>> {
+        NeardClient::network_info(self).boxed()
+    }
+
+    fn validators(&self) -> BoxFuture<'_, Result<EpochValidatorInfo>> {
+        NeardClient::validators(self).boxed()
+    }
+
+    fn protocol_config(&self) -> BoxFuture<'_, Result<ProtocolConfig>> {
+        NeardClient::protocol_config(self).boxed()
+    }
+
+    fn metrics(&self) -> BoxFuture<'_, Result<HashMap<String, String>>> {
+        NeardClient::metrics(self).boxed()
+    }
+}
@@ -0,0 +1,206 @@
+//! Keeps track of the neard binary kneard runs, so that a new binary can be staged,
+//! switched to with a restart in a maintenance window and rolled back afterwards
+
+use crate::commands::system_info::parse_neard_version;
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs;
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use tokio::process::Command;
+
+/// Version of a neard binary as reported by `neard -V`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct NeardVersion {
+    /// Build version, `develop` for trunk builds
+    pub version: String,
+    /// Latest protocol version the binary supports
+    pub protocol: u32,
+    /// Version of the database layout the binary uses
+    pub db: u32,
+}
+
+impl NeardVersion {
+    /// Runs `neard -V` of the given binary
+    pub async fn query(neard: &Path) -> Result<Self> {
+        let output = Command::new(neard)
+            .arg("-V")
+            .output()
+            .await
+            .with_context(|| format!("failed to run `{} -V`", neard.display()))?;
+        if !output.status.success() {
+            bail!("`{} -V` failed with {}", neard.display(), output.status);
+        }
+        let output = std::str::from_utf8(&output.stdout).context("neard version is not utf-8")?;
+        Self::parse(output).with_context(|| format!("cannot parse version of {}", neard.display()))
+    }
+
+    fn parse(raw_version: &str) -> Result<Self> {
+        let (version, protocol, db) = parse_neard_version(raw_version)?;
+        Ok(Self {
+            version,
+            protocol: protocol
+                .parse()
+                .with_context(|| format!("invalid protocol version in '{raw_version}'"))?,
+            db: db
+                .parse()
+                .with_context(|| format!("invalid db version in '{raw_version}'"))?,
+        })
+    }
+
+    /// Checks that a binary of this version can replace a binary of the `current`
+    /// version on a network that uses `network_protocol`
+    pub fn check_replaces(&self, current: &NeardVersion, network_protocol: u32) -> Result<()> {
+        if self.protocol < network_protocol {
+            bail!(
+                "neard {} supports protocol {}, but the network already uses protocol {}",
+                self.version,
+                self.protocol,
+                network_protocol
+            );
+        }
+        if self.db < current.db {
+            bail!(
+                "neard {} uses db version {}, but the database was already migrated to version {} by neard {}",
+                self.version,
+                self.db,
+                current.db,
+                current.version
+            );
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for NeardVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} (protocol {}, db {})",
+            self.version, self.protocol, self.db
+        )
+    }
+}
+
+/// The neard binaries of a node, kept in neard's home. Without a switch, `neard` from PATH is used.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct NeardBinaries {
+    /// Binary neard is started with
+    pub current: Option<PathBuf>,
+    /// Binary neard was started with before the last switch
+    pub previous: Option<PathBuf>,
+    /// Binary to switch to with the next scheduled restart
+    pub staged: Option<PathBuf>,
+}
+
+fn binaries_file(neard_home: &Path) -> PathBuf {
+    neard_home.join("kuutamod-neard-binaries.json")
+}
+
+impl NeardBinaries {
+    /// Returns the binaries of the node using `neard_home`
+    pub fn load(neard_home: &Path) -> Result<Self> {
+        let path = binaries_file(neard_home);
+        let content = match fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => return Err(e).with_context(|| format!("cannot read {}", path.display())),
+        };
+        serde_json::from_str(&content).with_context(|| format!("invalid {}", path.display()))
+    }
+
+    /// Stores the binaries in `neard_home`
+    pub fn save(&self, neard_home: &Path) -> Result<()> {
+        let path = binaries_file(neard_home);
+        let mut file = tempfile::NamedTempFile::new_in(neard_home)
+            .with_context(|| format!("cannot create temporary file in {}", neard_home.display()))?;
+        let content = serde_json::to_string(self).context("cannot serialize neard binaries")?;
+        file.write_all(content.as_bytes())
+            .context("cannot write neard binaries")?;
+        file.persist(&path)
+            .with_context(|| format!("cannot write {}", path.display()))?;
+        Ok(())
+    }
+
+    /// Returns the binary neard is started with
+    pub fn current_binary(&self) -> &Path {
+        self.current
+            .as_deref()
+            .unwrap_or_else(|| Path::new("neard"))
+    }
+
+    /// Returns the binary that was used before the last switch
+    pub fn rollback_binary(&self) -> Result<PathBuf> {
+        match self.previous {
+            Some(ref previous) => Ok(previous.clone()),
+            None if self.current.is_some() => Ok(PathBuf::from("neard")),
+            None => bail!("no previous neard binary to roll back to"),
+        }
+    }
+
+    /// Uses the staged binary from now on and keeps the current one for a rollback.
+    /// Returns false if no binary was staged.
+    pub fn switch(&mut self) -> bool {
+        let staged = match self.staged.take() {
+            Some(staged) => staged,
+            None => return false,
+        };
+        let previous = self.current.replace(staged);
+        self.previous = Some(previous.unwrap_or_else(|| PathBuf::from("neard")));
+        true
+    }
+}
+
+/// Validates `binary` against the neard binary currently in use and the protocol
+/// version of the network and stages it for the next restart
+pub async fn stage_neard(
+    neard_home: &Path,
+    binary: &Path,
+    network_protocol: u32,
+) -> Result<NeardVersion> {
+    let mut binaries = NeardBinaries::load(neard_home)?;
+    let current = NeardVersion::query(binaries.current_binary())
+        .await
+        .context("cannot get version of the current neard binary")?;
+    let staged = NeardVersion::query(binary).await?;
+    staged.check_replaces(&current, network_protocol)?;
+    binaries.staged = Some(binary.to_path_buf());
+    binaries.save(neard_home)?;
+    Ok(staged)
+}
+
+#[test]
+fn test_neard_binaries() {
+    let current = NeardVersion::parse(
+        "neard (release 1.32.2) (build 1.32.2) (rustc 1.68.0) (protocol 59) (db 34)",
+    )
+    .unwrap();
+    assert_eq!(current.protocol, 59);
+    assert_eq!(current.db, 34);
+    let staged = NeardVersion::parse(
+        "neard (release 1.33.0) (build 1.33.0) (rustc 1.69.0) (protocol 60) (db 35)",
+    )
+    .unwrap();
+    assert!(staged.check_replaces(&current, 59).is_ok());
+    // the database cannot be downgraded and the network protocol cannot be ignored
+    assert!(current.check_replaces(&staged, 59).is_err());
+    assert!(current.check_replaces(&current, 60).is_err());
+
+    let dir = tempfile::tempdir().unwrap();
+    let mut binaries = NeardBinaries::load(dir.path()).unwrap();
+    assert_eq!(binaries.current_binary(), Path::new("neard"));
+    assert!(binaries.rollback_binary().is_err());
+    assert!(!binaries.switch());
+
+    binaries.staged = Some(PathBuf::from("/nix/store/neard-1.33.0/bin/neard"));
+    assert!(binaries.switch());
+    binaries.save(dir.path()).unwrap();
+    let binaries = NeardBinaries::load(dir.path()).unwrap();
+    assert_eq!(
+        binaries.current_binary(),
+        Path::new("/nix/store/neard-1.33.0/bin/neard")
+    );
+    assert_eq!(binaries.rollback_binary().unwrap(), PathBuf::from("neard"));
+    assert_eq!(binaries.staged, None);
+}
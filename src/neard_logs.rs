@@ -0,0 +1,104 @@
+//! Keeps the latest output of neard in memory, so that it can be inspected
+//! through the control socket, i.e. after neard crashed.
+
+use lazy_static::lazy_static;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use tokio::sync::broadcast;
+
+/// How many bytes of neard output are kept
+const NEARD_LOGS_SIZE: usize = 4 * 1024 * 1024;
+/// How many lines a follower may fall behind before it misses lines
+const FOLLOW_CAPACITY: usize = 1024;
+
+lazy_static! {
+    static ref NEARD_LOGS: LogBuffer = LogBuffer::new(NEARD_LOGS_SIZE);
+}
+
+/// Returns the output of all neard processes started by kneard
+pub fn neard_logs() -> &'static LogBuffer {
+    &NEARD_LOGS
+}
+
+#[derive(Debug, Default)]
+struct Lines {
+    lines: VecDeque<String>,
+    size: usize,
+}
+
+impl Lines {
+    fn last(&self, n: usize) -> Vec<String> {
+        let skip = self.lines.len().saturating_sub(n);
+        self.lines.iter().skip(skip).cloned().collect()
+    }
+}
+
+/// Ring buffer of log lines limited by their total size
+#[derive(Debug)]
+pub struct LogBuffer {
+    max_size: usize,
+    lines: Mutex<Lines>,
+    follow: broadcast::Sender<String>,
+}
+
+impl LogBuffer {
+    /// Returns an empty buffer that keeps at most `max_size` bytes
+    pub fn new(max_size: usize) -> Self {
+        Self {
+            max_size,
+            lines: Mutex::new(Lines::default()),
+            follow: broadcast::channel(FOLLOW_CAPACITY).0,
+        }
+    }
+
+    /// Appends a line and drops the oldest lines if the buffer is full
+    pub fn push(&self, line: String) {
+        let mut lines = match self.lines.lock() {
+            Ok(lines) => lines,
+            Err(_) => return,
+        };
+        lines.size += line.len();
+        lines.lines.push_back(line.clone());
+        while lines.size > self.max_size {
+            match lines.lines.pop_front() {
+                Some(old) => lines.size -= old.len(),
+                None => break,
+            }
+        }
+        // fails if nobody follows, which is the common case
+        let _ = self.follow.send(line);
+    }
+
+    /// Returns the last `n` lines, oldest first
+    pub fn tail(&self, n: usize) -> Vec<String> {
+        match self.lines.lock() {
+            Ok(lines) => lines.last(n),
+            Err(_) => vec![],
+        }
+    }
+
+    /// Returns the last `n` lines and a receiver for all lines that follow them
+    pub fn follow(&self, n: usize) -> (Vec<String>, broadcast::Receiver<String>) {
+        // lines are sent while holding the lock, so that we neither miss nor repeat one
+        match self.lines.lock() {
+            Ok(lines) => (lines.last(n), self.follow.subscribe()),
+            Err(_) => (vec![], self.follow.subscribe()),
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_log_buffer() {
+    let buffer = LogBuffer::new(10);
+    buffer.push("1234".to_string());
+    buffer.push("5678".to_string());
+    assert_eq!(buffer.tail(1), vec!["5678"]);
+    assert_eq!(buffer.tail(10), vec!["1234", "5678"]);
+
+    let (tail, mut rx) = buffer.follow(10);
+    assert_eq!(tail, vec!["1234", "5678"]);
+    // exceeds the size, so the oldest line is dropped
+    buffer.push("abcd".to_string());
+    assert_eq!(buffer.tail(10), vec!["5678", "abcd"]);
+    assert_eq!(rx.recv().await.unwrap(), "abcd");
+}
@@ -2,15 +2,19 @@
 
 use crate::near_client::NeardClient;
 use crate::near_config::update_neard_config;
-use crate::proc::{graceful_stop_neard, run_neard};
+use crate::neard_binary::{NeardBinaries, NeardVersion};
+use crate::proc::{graceful_stop_neard, run_neard, StderrTail};
 use crate::settings::Settings;
 use anyhow::{Context, Result};
+use futures_util::future::BoxFuture;
+use futures_util::FutureExt;
 use log::{error, warn};
 use near_primitives::types::BlockHeight;
 use nix::sys::signal::{self, Signal};
 use nix::unistd::Pid;
+use std::fmt;
 use std::fs::remove_file;
-use std::io::ErrorKind;
+use std::io::{self, ErrorKind};
 use std::os::unix::fs::symlink;
 use std::path::Path;
 use std::process::ExitStatus;
@@ -23,6 +27,60 @@ use tokio::time::{sleep, sleep_until, Duration, Instant};
 pub struct NeardProcess {
     process: Child,
     sent_kill: bool,
+    started_at: Instant,
+    stderr: StderrTail,
+}
+
+/// A running neard process as seen by the supervisor
+pub trait Neard: fmt::Debug + Send {
+    /// Wait for the process to stop
+    fn wait(&mut self) -> BoxFuture<'_, io::Result<ExitStatus>>;
+
+    /// Get Pid of neard
+    fn pid(&self) -> Option<Pid>;
+
+    /// When the process was started
+    fn started_at(&self) -> Instant;
+
+    /// The last lines the process wrote to stderr
+    fn stderr_tail(&self) -> Vec<String>;
+
+    /// Stops the process, dropping it also stops the process
+    fn graceful_stop(self: Box<Self>) -> Result<()>;
+}
+
+/// Starts neard in voter or validator mode
+pub trait NeardLauncher: fmt::Debug + Send + Sync {
+    /// Start neard without the validator key
+    fn start_voter(&self, settings: &Settings) -> Result<Box<dyn Neard>>;
+
+    /// Start neard with the validator key
+    fn start_validator(&self, settings: &Settings) -> Result<Box<dyn Neard>>;
+
+    /// Version of the neard binary neard is started with
+    fn version<'a>(&'a self, settings: &'a Settings) -> BoxFuture<'a, Result<NeardVersion>>;
+}
+
+/// Launches neard processes with `setup_voter` and `setup_validator`
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NeardProcessLauncher;
+
+impl NeardLauncher for NeardProcessLauncher {
+    fn start_voter(&self, settings: &Settings) -> Result<Box<dyn Neard>> {
+        Ok(Box::new(setup_voter(settings)?))
+    }
+
+    fn start_validator(&self, settings: &Settings) -> Result<Box<dyn Neard>> {
+        Ok(Box::new(setup_validator(settings)?))
+    }
+
+    fn version<'a>(&'a self, settings: &'a Settings) -> BoxFuture<'a, Result<NeardVersion>> {
+        async move {
+            let binaries = NeardBinaries::load(&settings.neard_home)?;
+            NeardVersion::query(binaries.current_binary()).await
+        }
+        .boxed()
+    }
 }
 
 // ignores non-existing files
@@ -71,12 +129,21 @@ pub fn setup_validator(settings: &Settings) -> Result<NeardProcess> {
     )
     .context("failed to update network addr in near config")?;
 
-    let process = run_neard(&settings.neard_home, &settings.near_boot_nodes)
-        .context("Cannot start validator neard")?;
+    let binaries = NeardBinaries::load(&settings.neard_home)?;
+    let log_prefix = format!("validator {}", settings.node_id);
+    let (process, stderr) = run_neard(
+        binaries.current_binary(),
+        &settings.neard_home,
+        &settings.near_boot_nodes,
+        &log_prefix,
+    )
+    .context("Cannot start validator neard")?;
 
     Ok(NeardProcess {
         process,
         sent_kill: false,
+        started_at: Instant::now(),
+        stderr,
     })
 }
 
@@ -102,12 +169,21 @@ pub fn setup_voter(settings: &Settings) -> Result<NeardProcess> {
     )
     .context("failed to update network addr in near config")?;
 
-    let process = run_neard(&settings.neard_home, &settings.near_boot_nodes)
-        .context("Cannot start voter neard")?;
+    let binaries = NeardBinaries::load(&settings.neard_home)?;
+    let log_prefix = format!("voter {}", settings.node_id);
+    let (process, stderr) = run_neard(
+        binaries.current_binary(),
+        &settings.neard_home,
+        &settings.near_boot_nodes,
+        &log_prefix,
+    )
+    .context("Cannot start voter neard")?;
 
     Ok(NeardProcess {
         process,
         sent_kill: false,
+        started_at: Instant::now(),
+        stderr,
     })
 }
 
@@ -223,6 +299,28 @@ impl NeardProcess {
     }
 }
 
+impl Neard for NeardProcess {
+    fn wait(&mut self) -> BoxFuture<'_, io::Result<ExitStatus>> {
+        NeardProcess::wait(self).boxed()
+    }
+
+    fn pid(&self) -> Option<Pid> {
+        NeardProcess::pid(self)
+    }
+
+    fn started_at(&self) -> Instant {
+        self.started_at
+    }
+
+    fn stderr_tail(&self) -> Vec<String> {
+        self.stderr.lines()
+    }
+
+    fn graceful_stop(self: Box<Self>) -> Result<()> {
+        NeardProcess::graceful_stop(*self)
+    }
+}
+
 impl Drop for NeardProcess {
     fn drop(&mut self) {
         if !self.sent_kill {
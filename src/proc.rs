@@ -10,18 +10,26 @@ use nix::sys::signalfd::SigSet;
 use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
 use nix::unistd::Pid;
 use prometheus::{register_int_counter, IntCounter};
+use std::collections::VecDeque;
 use std::ffi::OsStr;
+use std::os::fd::AsRawFd;
 use std::path::Path;
-use std::sync::Mutex;
+use std::process::Stdio;
+use std::sync::{Arc, Mutex};
 use std::{io, ptr};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
 use tokio::process::Child;
 use tokio::process::Command;
 use tokio::time::Duration;
 
+use crate::cgroup::{join_cgroup, NeardCgroup};
+use crate::neard_logs::neard_logs;
 use crate::oom_score;
 
 /// How much time we give neard to exit. We give it some time to sync rocksdb to disk.
 const NEARD_STOP_TIMEOUT: Duration = Duration::from_secs(60);
+/// How many lines of the stderr of neard we keep for crash reports
+const STDERR_TAIL_LINES: usize = 50;
 
 lazy_static! {
     static ref NEARD_RESTARTS: IntCounter = register_int_counter!(
@@ -64,8 +72,67 @@ fn reset_oom_score() -> io::Result<()> {
     r
 }
 
-/// Starts a neard daemon for the given home
-pub fn run_neard(neard_home: &Path, boot_nodes: &Option<String>) -> Result<Child> {
+/// The last lines neard wrote to stderr
+#[derive(Debug, Clone, Default)]
+pub struct StderrTail(Arc<Mutex<VecDeque<String>>>);
+
+impl StderrTail {
+    /// Returns the captured lines, oldest first
+    pub fn lines(&self) -> Vec<String> {
+        match self.0.lock() {
+            Ok(lines) => lines.iter().cloned().collect(),
+            Err(_) => vec![],
+        }
+    }
+
+    fn push(&self, line: &str) {
+        if let Ok(mut lines) = self.0.lock() {
+            if lines.len() == STDERR_TAIL_LINES {
+                lines.pop_front();
+            }
+            lines.push_back(line.to_string());
+        }
+    }
+}
+
+/// Forwards output of neard to `out` with `prefix` in front of every line and keeps
+/// it in the neard logs. Lines are also kept in `tail`, if given.
+fn forward_output(
+    output: impl AsyncRead + Unpin + Send + 'static,
+    mut out: impl AsyncWrite + Unpin + Send + 'static,
+    prefix: String,
+    tail: Option<StderrTail>,
+) {
+    tokio::spawn(async move {
+        let mut reader = BufReader::new(output);
+        let mut buf = vec![];
+        // neard would get SIGPIPE if we stopped reading, so we also read non-utf8 output
+        while let Ok(n) = reader.read_until(b'\n', &mut buf).await {
+            if n == 0 {
+                break;
+            }
+            let line = String::from_utf8_lossy(&buf);
+            let line = line.trim_end();
+            let _ = out
+                .write_all(format!("[{prefix}] {line}\n").as_bytes())
+                .await;
+            if let Some(ref tail) = tail {
+                tail.push(line);
+            }
+            neard_logs().push(format!("[{prefix}] {line}"));
+            buf.clear();
+        }
+    });
+}
+
+/// Starts the `neard` binary as daemon for the given home. Its output is prefixed with `log_prefix`
+/// and its stderr is captured in the returned tail. neard runs in its own cgroup, if one was set up.
+pub fn run_neard(
+    neard: &Path,
+    neard_home: &Path,
+    boot_nodes: &Option<String>,
+    log_prefix: &str,
+) -> Result<(Child, StderrTail)> {
     let mut args = vec![
         OsStr::new("--home"),
         neard_home.as_os_str(),
@@ -75,22 +142,62 @@ pub fn run_neard(neard_home: &Path, boot_nodes: &Option<String>) -> Result<Child
         args.push(OsStr::new("--boot-nodes"));
         args.push(OsStr::new(v.as_str()));
     };
+    let cgroup = NeardCgroup::get();
+    // opened before the fork, the child only writes to it
+    let procs = cgroup
+        .as_ref()
+        .and_then(|cgroup| match cgroup.open_procs() {
+            Ok(procs) => Some(procs),
+            Err(e) => {
+                warn!("Cannot run neard in its cgroup: {:#}", e);
+                None
+            }
+        });
+    let procs_fd = procs.as_ref().map(|p| p.as_raw_fd());
     let proc = unsafe {
-        Command::new("neard")
+        Command::new(neard)
             .args(args)
             .pre_exec(reset_oom_score)
+            .pre_exec(move || {
+                // neard still runs in the cgroup of kneard, it just cannot be limited
+                if let Some(fd) = procs_fd {
+                    let _ = join_cgroup(fd);
+                }
+                Ok(())
+            })
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
             .spawn()
             .with_context(|| {
                 format!(
-                    "failed to spawn `neard --home {} run`",
+                    "failed to spawn `{} --home {} run`",
+                    neard.display(),
                     neard_home.display()
                 )
             })
     };
-    if let Ok(ref p) = proc {
-        set_neard_pid(p.id().map(|id| Pid::from_raw(id as i32)));
+    let mut proc = proc?;
+    let pid = proc.id().map(|id| Pid::from_raw(id as i32));
+    set_neard_pid(pid);
+    drop(procs);
+    if let (Some(cgroup), Some(pid)) = (cgroup, pid) {
+        if procs_fd.is_some() && !cgroup.contains(pid) {
+            warn!("Failed to move neard to {}", cgroup.path().display());
+        }
+    }
+    let tail = StderrTail::default();
+    if let Some(stdout) = proc.stdout.take() {
+        forward_output(stdout, tokio::io::stdout(), log_prefix.to_string(), None);
+    }
+    if let Some(stderr) = proc.stderr.take() {
+        forward_output(
+            stderr,
+            tokio::io::stderr(),
+            log_prefix.to_string(),
+            Some(tail.clone()),
+        );
     }
-    proc
+    Ok((proc, tail))
 }
 
 /// Stops a process by first sending SIGTERM and than after `NEARD_STOP_TIMEOUT`
@@ -9,6 +9,7 @@ use lazy_static::lazy_static;
 use log::warn;
 use prometheus::{self, register_gauge, Encoder, Gauge, TextEncoder};
 
+use crate::cgroup::export_neard_cgroup;
 use crate::proc::get_neard_pid;
 
 lazy_static! {
@@ -33,6 +34,7 @@ async fn response_examples(req: Request<Body>) -> hyper::Result<Response<Body>>
         },
         (&Method::GET, "/metrics") => {
             UPTIME.set(START.elapsed().as_millis() as f64);
+            export_neard_cgroup();
 
             let metric_families = prometheus::gather();
             let mut buffer = vec![];
@@ -0,0 +1,166 @@
+//! Keeps the scheduled restart or shutdown of neard in a state file, so that it is
+//! applied again after neard or kneard restarted
+
+use crate::migration::ExpectedMigration;
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use near_primitives::types::BlockHeight;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs;
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// What happens once neard stopped at the scheduled block height
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum OperationMode {
+    /// kneard starts neard again
+    Restart,
+    /// kneard exits together with neard, i.e. to be restarted by systemd
+    Shutdown,
+}
+
+impl fmt::Display for OperationMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            OperationMode::Restart => write!(f, "restart"),
+            OperationMode::Shutdown => write!(f, "shutdown"),
+        }
+    }
+}
+
+/// A restart or shutdown of neard at a block height
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ScheduledOperation {
+    /// Identifies the operation, milliseconds since the unix epoch when it was requested
+    pub id: u64,
+    /// Block height neard stops at
+    pub height: BlockHeight,
+    /// What happens once neard stopped
+    pub mode: OperationMode,
+    /// State of kneard the operation was requested in, i.e. Validating
+    pub origin: String,
+    /// When the operation was requested
+    pub requested_at: DateTime<Utc>,
+    /// Db migration neard runs when it starts again
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub migration: Option<ExpectedMigration>,
+}
+
+impl ScheduledOperation {
+    /// Returns an operation requested at `now`
+    pub fn new(height: BlockHeight, mode: OperationMode, origin: &str, now: SystemTime) -> Self {
+        let requested_at = DateTime::<Utc>::from(now);
+        Self {
+            id: requested_at.timestamp_millis() as u64,
+            height,
+            mode,
+            origin: origin.to_string(),
+            requested_at,
+            migration: None,
+        }
+    }
+
+    /// Whether neard already passed the block height of the operation
+    pub fn is_past_due(&self, head: BlockHeight) -> bool {
+        head >= self.height
+    }
+}
+
+impl fmt::Display for ScheduledOperation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} at block height {} (id {}, requested while {} at {})",
+            self.mode,
+            self.height,
+            self.id,
+            self.origin,
+            self.requested_at.to_rfc3339()
+        )?;
+        if let Some(ref migration) = self.migration {
+            write!(f, ", followed by a {migration}")?;
+        }
+        Ok(())
+    }
+}
+
+/// State file in neard's home with the operation scheduled for neard
+#[derive(Debug, Clone)]
+pub struct Schedule {
+    path: PathBuf,
+}
+
+impl Schedule {
+    /// Returns the schedule stored in `neard_home`
+    pub fn new(neard_home: &Path) -> Self {
+        Self {
+            path: neard_home.join("kuutamod-schedule.json"),
+        }
+    }
+
+    /// Returns the scheduled operation, None if there is none
+    pub fn get(&self) -> Result<Option<ScheduledOperation>> {
+        let content = match fs::read_to_string(&self.path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => {
+                return Err(e).with_context(|| format!("cannot read {}", self.path.display()))
+            }
+        };
+        serde_json::from_str(&content)
+            .map(Some)
+            .with_context(|| format!("invalid operation in {}", self.path.display()))
+    }
+
+    /// Replaces the scheduled operation
+    pub fn set(&self, operation: &ScheduledOperation) -> Result<()> {
+        let dir = self.path.parent().unwrap_or_else(|| Path::new("."));
+        let mut file = tempfile::NamedTempFile::new_in(dir)
+            .with_context(|| format!("cannot create temporary file in {}", dir.display()))?;
+        let content = serde_json::to_string(operation).context("cannot serialize operation")?;
+        file.write_all(content.as_bytes())
+            .context("cannot write operation")?;
+        file.persist(&self.path)
+            .with_context(|| format!("cannot write {}", self.path.display()))?;
+        Ok(())
+    }
+
+    /// Removes the scheduled operation, if there is one
+    pub fn clear(&self) -> Result<()> {
+        match fs::remove_file(&self.path) {
+            Err(e) if e.kind() != ErrorKind::NotFound => {
+                Err(e).with_context(|| format!("cannot remove {}", self.path.display()))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[test]
+fn test_schedule() {
+    let dir = tempfile::tempdir().unwrap();
+    let schedule = Schedule::new(dir.path());
+    assert_eq!(schedule.get().unwrap(), None);
+    schedule.clear().unwrap();
+
+    let now = SystemTime::UNIX_EPOCH + std::time::Duration::from_millis(1_672_531_200_123);
+    let operation = ScheduledOperation::new(1002, OperationMode::Shutdown, "Validating", now);
+    assert_eq!(operation.id, 1_672_531_200_123);
+    assert!(!operation.is_past_due(1001));
+    assert!(operation.is_past_due(1002));
+    assert_eq!(
+        operation.to_string(),
+        "shutdown at block height 1002 (id 1672531200123, requested while Validating at 2023-01-01T00:00:00.123+00:00)"
+    );
+
+    schedule.set(&operation).unwrap();
+    assert_eq!(schedule.get().unwrap(), Some(operation));
+    let content = fs::read_to_string(dir.path().join("kuutamod-schedule.json")).unwrap();
+    assert!(content.contains("\"mode\":\"shutdown\""));
+
+    schedule.clear().unwrap();
+    assert_eq!(schedule.get().unwrap(), None);
+}
@@ -1,41 +1,41 @@
-//! A consul session that can be manually deleted
+//! An election session that can be manually deleted
 
-use crate::consul_client::{ConsulClient, ConsulSession};
+use crate::leader_protocol::{ElectionSession, LeaderElection};
 use log::warn;
 use std::borrow::Borrow;
 use std::time::Duration;
 use std::time::Instant;
 
-/// A wrapper around ConsulSession that also deletes the underlying consul session
+/// A wrapper around ElectionSession that also deletes the underlying session in the election backend
 /// The user needs to manually call `destroy` to destroy the session
-pub struct ScopedConsulSession<'a> {
-    inner: ConsulSession,
-    client: &'a ConsulClient,
+pub struct ScopedSession<'a> {
+    inner: ElectionSession,
+    client: &'a dyn LeaderElection,
 }
 
-impl<'a> ScopedConsulSession<'a> {
-    /// Returns a new ScopedConsulSession
-    pub fn new(c: &ConsulClient, s: ConsulSession) -> ScopedConsulSession {
-        ScopedConsulSession {
+impl<'a> ScopedSession<'a> {
+    /// Returns a new ScopedSession
+    pub fn new(c: &'a dyn LeaderElection, s: ElectionSession) -> ScopedSession<'a> {
+        ScopedSession {
             inner: s,
             client: c,
         }
     }
 
-    /// Consumes and deletes consul session,
+    /// Consumes and deletes the session,
     /// We used to have this in `Drop` but we cannot do async stuff in their easily
     pub async fn destroy(self) {
         let mut wait = 1000;
         let total_wait = Instant::now();
         // 1 + 2 + 4 + 5 + 5 + 5 + 5 == ~27s
         for _ in 0..7 {
-            let res = self.client.delete_session(&self.inner).await;
+            let res = self.client.destroy_session(&self.inner).await;
             let e = match res {
                 Ok(()) => return,
                 Err(e) => e,
             };
             warn!(
-                "Failed to deregister consul session {}: {} (wait: {}, elapsed: {})",
+                "Failed to deregister session {}: {} (wait: {}, elapsed: {})",
                 self.inner.id(),
                 e,
                 wait,
@@ -48,15 +48,15 @@ impl<'a> ScopedConsulSession<'a> {
     }
 }
 
-impl Borrow<ConsulSession> for ScopedConsulSession<'_> {
+impl Borrow<ElectionSession> for ScopedSession<'_> {
     /// Returns a reference to the actual
-    fn borrow(&self) -> &ConsulSession {
+    fn borrow(&self) -> &ElectionSession {
         &self.inner
     }
 }
 
-impl From<ScopedConsulSession<'_>> for ConsulSession {
-    fn from(s: ScopedConsulSession) -> ConsulSession {
+impl From<ScopedSession<'_>> for ElectionSession {
+    fn from(s: ScopedSession) -> ElectionSession {
         s.inner
     }
 }
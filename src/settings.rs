@@ -1,5 +1,9 @@
 //! Read settings for kneard
 
+use crate::disk::DiskRules;
+use crate::hooks::Hooks;
+use crate::leader_protocol::ElectionBackend;
+use crate::memory::MemoryAction;
 use crate::near_config::{read_near_config, NearKey};
 use anyhow::{bail, Context, Result};
 use clap::Parser;
@@ -7,6 +11,7 @@ use near_primitives::types::AccountId;
 use std::fs;
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 // set by systemd LoadCredential
 const CREDENTIALS_DIRECTORY: &str = "CREDENTIALS_DIRECTORY";
@@ -15,6 +20,21 @@ const CREDENTIALS_DIRECTORY: &str = "CREDENTIALS_DIRECTORY";
 #[derive(Parser, Debug, Clone)]
 #[clap(author, version, about, long_about = None)]
 pub struct Settings {
+    /// Backend used for leader election
+    #[clap(
+        long,
+        value_enum,
+        default_value_t = ElectionBackend::Consul,
+        env = "KUUTAMO_ELECTION_BACKEND"
+    )]
+    pub election_backend: ElectionBackend,
+    /// The etcd endpoint url, used if the election backend is `etcd`
+    #[clap(
+        long,
+        default_value = "http://localhost:2379",
+        env = "KUUTAMO_ETCD_URL"
+    )]
+    pub etcd_url: String,
     /// The consul agent url
     #[clap(
         long,
@@ -22,12 +42,18 @@ pub struct Settings {
         env = "KUUTAMO_CONSUL_URL"
     )]
     pub consul_url: String,
-    /// Consul token used for authentication, also see `https://www.consul.io/docs/security/acl/acl-tokens`
+    /// Consul token used for authentication, also see `https://www.consul.io/docs/security/acl/acl-tokens`.
     #[clap(long, env = "KUUTAMO_CONSUL_TOKEN_FILE")]
     pub consul_token_file: Option<PathBuf>,
     /// Contains the content of `consul_token_file`
     #[clap(skip = None)]
     pub consul_token: Option<String>,
+    /// etcd auth token, passed as `Authorization` header, also see `https://etcd.io/docs/v3.5/dev-guide/api_grpc_gateway/#authentication`
+    #[clap(long, env = "KUUTAMO_ETCD_TOKEN_FILE")]
+    pub etcd_token_file: Option<PathBuf>,
+    /// Contains the content of `etcd_token_file`
+    #[clap(skip = None)]
+    pub etcd_token: Option<String>,
 
     /// Node id of the kuutamo instance
     #[clap(long, default_value = "node", env = "KUUTAMO_NODE_ID")]
@@ -37,6 +63,10 @@ pub struct Settings {
     /// same validator key
     #[clap(long, default_value = "default", env = "KUUTAMO_ACCOUNT_ID")]
     pub account_id: AccountId,
+    /// Priority of this node in the leader election. A validator hands over its
+    /// leadership to a synced node with higher priority during a maintenance window
+    #[clap(long, default_value_t = 0, env = "KUUTAMO_PRIORITY")]
+    pub priority: u32,
     /// The exporter address, that kneard will listen to: format: ip:host
     #[clap(
         long,
@@ -97,6 +127,304 @@ pub struct Settings {
         env = "KUUTAMO_CONTROL_SOCKET"
     )]
     pub control_socket: PathBuf,
+
+    /// Step down from validating if the head block of neard is older than this many seconds
+    #[clap(long, env = "KUUTAMO_STEP_DOWN_MAX_BLOCK_AGE")]
+    pub step_down_max_block_age: Option<u64>,
+    /// Step down from validating if the block height of neard did not increase for this many seconds
+    #[clap(long, env = "KUUTAMO_STEP_DOWN_STALLED_HEIGHT")]
+    pub step_down_stalled_height: Option<u64>,
+    /// Step down from validating if neard is syncing for longer than this many seconds
+    #[clap(long, env = "KUUTAMO_STEP_DOWN_MAX_SYNCING")]
+    pub step_down_max_syncing: Option<u64>,
+    /// Seconds a node that stepped down does not try to become validator again
+    #[clap(long, default_value_t = 300, env = "KUUTAMO_STEP_DOWN_COOLDOWN")]
+    pub step_down_cooldown: u64,
+
+    /// Do not try to become validator while neard is connected to fewer peers, 0 disables the rule
+    #[clap(long, default_value_t = 1, env = "KUUTAMO_HEALTH_MIN_PEERS")]
+    pub health_min_peers: usize,
+    /// Do not try to become validator while the head block of neard is older than this many seconds
+    #[clap(long, env = "KUUTAMO_HEALTH_MAX_BLOCK_AGE")]
+    pub health_max_block_age: Option<u64>,
+    /// Do not try to become validator while the head block of neard is this many seconds ahead of our clock
+    #[clap(long, env = "KUUTAMO_HEALTH_MAX_CLOCK_DRIFT")]
+    pub health_max_clock_drift: Option<u64>,
+    /// Do not try to become validator while neard takes longer than this many milliseconds to process a block
+    #[clap(long, env = "KUUTAMO_HEALTH_MAX_BLOCK_PROCESSING_TIME")]
+    pub health_max_block_processing_time: Option<u64>,
+    /// Do not try to become validator while more than this percentage of the space or inodes
+    /// of the filesystem of neard's home is used
+    #[clap(long, default_value_t = 90, env = "KUUTAMO_DISK_WARNING_USAGE")]
+    pub disk_warning_usage: u8,
+    /// Give up validating in the next maintenance window once more than this percentage of the
+    /// space or inodes of the filesystem of neard's home is used
+    #[clap(long, default_value_t = 95, env = "KUUTAMO_DISK_CRITICAL_USAGE")]
+    pub disk_critical_usage: u8,
+    /// Maximum resident memory of neard in MiB, before the validator reacts with `memory_action`
+    #[clap(long, env = "KUUTAMO_MEMORY_MAX_RSS")]
+    pub memory_max_rss: Option<u64>,
+    /// Maximum percentage of time all tasks of the host were stalled on memory in the last 10s
+    /// (`full avg10` in /proc/pressure/memory), before the validator reacts with `memory_action`
+    #[clap(long, env = "KUUTAMO_MEMORY_MAX_PRESSURE")]
+    pub memory_max_pressure: Option<f64>,
+    /// How the validator reacts if neard exceeds a memory limit
+    #[clap(
+        long,
+        value_enum,
+        default_value_t = MemoryAction::Restart,
+        env = "KUUTAMO_MEMORY_ACTION"
+    )]
+    pub memory_action: MemoryAction,
+
+    /// Seconds after which a session in the election backend expires unless it is renewed
+    #[clap(long, default_value_t = 30, env = "KUUTAMO_SESSION_TTL")]
+    pub session_ttl: u64,
+    /// Seconds between renewals of the session
+    #[clap(long, default_value_t = 10, env = "KUUTAMO_SESSION_RENEWAL")]
+    pub session_renewal: u64,
+    /// Seconds after which a failed renewal of the session is retried
+    #[clap(long, default_value_t = 5, env = "KUUTAMO_SESSION_RENEWAL_RETRY")]
+    pub session_renewal_retry: u64,
+    /// Seconds a validator keeps validating while it cannot renew its session.
+    /// Must be smaller than the session ttl, so it stops before another node can take over.
+    #[clap(long, default_value_t = 25, env = "KUUTAMO_LEADER_TIMEOUT")]
+    pub leader_timeout: u64,
+    /// Seconds between attempts of a voting node to become validator
+    #[clap(long, default_value_t = 1, env = "KUUTAMO_ACQUIRE_LEADER_FREQUENCY")]
+    pub acquire_leader_frequency: u64,
+    /// Seconds neard gets to make its `/status` endpoint available after it was started
+    #[clap(long, default_value_t = 120, env = "KUUTAMO_NEARD_STARTUP_TIMEOUT")]
+    pub neard_startup_timeout: u64,
+    /// Seconds between requests to neard's `/status` endpoint
+    #[clap(long, default_value_t = 1, env = "KUUTAMO_NEARD_STATUS_FREQUENCY")]
+    pub neard_status_frequency: u64,
+    /// Number of failed requests to neard's `/status` endpoint in a row, after which neard is restarted
+    #[clap(long, default_value_t = 3, env = "KUUTAMO_NEARD_MAX_STATUS_ERRORS")]
+    pub neard_max_status_errors: u32,
+    /// Same as `neard_max_status_errors`, but for a validator neard that has not answered yet since it was started
+    #[clap(
+        long,
+        default_value_t = 120,
+        env = "KUUTAMO_VALIDATOR_STARTUP_MAX_STATUS_ERRORS"
+    )]
+    pub validator_startup_max_status_errors: u32,
+    /// Seconds a voting node defers acquiring leadership in favour of a candidate with higher priority
+    #[clap(long, default_value_t = 10, env = "KUUTAMO_CANDIDATE_GRACE")]
+    pub candidate_grace: u64,
+    /// Seconds other nodes leave the leadership to the target of a handover.
+    /// Consul requires at least 10.
+    #[clap(long, default_value_t = 30, env = "KUUTAMO_HANDOVER_TIMEOUT")]
+    pub handover_timeout: u64,
+    /// Seconds between checks of the validator for candidates with a higher priority to hand over to
+    #[clap(long, default_value_t = 10, env = "KUUTAMO_FAILBACK_CHECK_FREQUENCY")]
+    pub failback_check_frequency: u64,
+    /// Seconds between checks of the validator that it still holds the leader lock
+    #[clap(long, default_value_t = 5, env = "KUUTAMO_FENCING_CHECK_FREQUENCY")]
+    pub fencing_check_frequency: u64,
+    /// Seconds between checks of the validator for other nodes validating with our key
+    #[clap(
+        long,
+        default_value_t = 10,
+        env = "KUUTAMO_DOUBLE_SIGN_CHECK_FREQUENCY"
+    )]
+    pub double_sign_check_frequency: u64,
+    /// Seconds between exports of the produced blocks and chunks of the validator
+    #[clap(long, default_value_t = 30, env = "KUUTAMO_VALIDATOR_STATS_FREQUENCY")]
+    pub validator_stats_frequency: u64,
+    /// Seconds between checks of the memory of neard and the memory pressure of the host
+    #[clap(long, default_value_t = 10, env = "KUUTAMO_MEMORY_CHECK_FREQUENCY")]
+    pub memory_check_frequency: u64,
+    /// How often neard may fail in a row before kneard gives up, 0 to never give up
+    #[clap(long, default_value_t = 10, env = "KUUTAMO_NEARD_RESTART_BUDGET")]
+    pub neard_restart_budget: u32,
+    /// MiB of memory above which neard is throttled, requires cgroup v2 delegated to kneard
+    #[clap(long, env = "KUUTAMO_NEARD_MEMORY_HIGH")]
+    pub neard_memory_high: Option<u64>,
+    /// MiB of memory above which neard is killed by the OOM killer, requires cgroup v2 delegated to kneard
+    #[clap(long, env = "KUUTAMO_NEARD_MEMORY_MAX")]
+    pub neard_memory_max: Option<u64>,
+    /// Cpu weight of neard relative to kneard (1-10000, default 100)
+    #[clap(long, value_parser = clap::value_parser!(u16).range(1..=10000), env = "KUUTAMO_NEARD_CPU_WEIGHT")]
+    pub neard_cpu_weight: Option<u16>,
+    /// Io weight of neard relative to kneard (1-10000, default 100)
+    #[clap(long, value_parser = clap::value_parser!(u16).range(1..=10000), env = "KUUTAMO_NEARD_IO_WEIGHT")]
+    pub neard_io_weight: Option<u16>,
+
+    /// Toml file with commands and webhooks to run on state changes
+    #[clap(long, env = "KUUTAMO_HOOKS_FILE")]
+    pub hooks_file: Option<PathBuf>,
+    /// Contains the hooks of `hooks_file`
+    #[clap(skip)]
+    pub hooks: Hooks,
+}
+
+/// Timeouts and intervals of the supervisor
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timings {
+    /// How long a session is valid
+    pub session_ttl: Duration,
+    /// How often to renew a session
+    pub session_renewal: Duration,
+    /// How soon to retry a failed renewal
+    pub session_renewal_retry: Duration,
+    /// How long a leader will wait when it cannot update its session until steps down and stop doing validation
+    pub leader_timeout: Duration,
+    /// How often we try to become leader (validator)
+    pub acquire_leader_frequency: Duration,
+    /// How much time we give neard to make it's `/status` endpoint available
+    pub neard_startup_timeout: Duration,
+    /// How often we query neard's `/status` endpoint
+    pub neard_status_frequency: Duration,
+    /// After how many failed status requests in a row neard is considered dead
+    pub max_status_errors: u32,
+    /// Same as `max_status_errors` for a validator neard that is still starting
+    pub validator_startup_max_status_errors: u32,
+    /// How long a node defers acquiring leadership in favour of a candidate with higher priority
+    pub candidate_grace: Duration,
+    /// How long other nodes leave the leadership to the target of a handover
+    pub handover_timeout: Duration,
+    /// How often the leader checks for candidates with a higher priority to hand over to
+    pub failback_check_frequency: Duration,
+    /// How often the validator checks that it still holds the leader lock
+    pub fencing_check_frequency: Duration,
+    /// How often the validator looks for other nodes validating with our key
+    pub double_sign_check_frequency: Duration,
+    /// How often the validator exports its produced blocks and chunks
+    pub validator_stats_frequency: Duration,
+    /// How often the memory of neard and the memory pressure of the host are checked
+    pub memory_check_frequency: Duration,
+}
+
+impl Timings {
+    /// Checks that the timings are safe to use, i.e. a validator stops before its session expires
+    pub fn validate(&self) -> Result<()> {
+        let zero = Duration::ZERO;
+        if self.session_renewal == zero
+            || self.session_renewal_retry == zero
+            || self.acquire_leader_frequency == zero
+            || self.neard_status_frequency == zero
+            || self.failback_check_frequency == zero
+            || self.fencing_check_frequency == zero
+            || self.double_sign_check_frequency == zero
+            || self.validator_stats_frequency == zero
+            || self.memory_check_frequency == zero
+        {
+            bail!("intervals must be at least one second");
+        }
+        if self.leader_timeout >= self.session_ttl {
+            bail!(
+                "leader timeout ({}s) must be smaller than the session ttl ({}s), otherwise two nodes may validate at the same time",
+                self.leader_timeout.as_secs(),
+                self.session_ttl.as_secs()
+            );
+        }
+        if self.session_renewal >= self.leader_timeout {
+            bail!(
+                "session renewal ({}s) must be smaller than the leader timeout ({}s)",
+                self.session_renewal.as_secs(),
+                self.leader_timeout.as_secs()
+            );
+        }
+        if self.session_renewal_retry >= self.leader_timeout {
+            bail!(
+                "session renewal retry ({}s) must be smaller than the leader timeout ({}s)",
+                self.session_renewal_retry.as_secs(),
+                self.leader_timeout.as_secs()
+            );
+        }
+        if self.neard_startup_timeout <= self.neard_status_frequency {
+            bail!(
+                "neard startup timeout ({}s) must be larger than the status frequency ({}s)",
+                self.neard_startup_timeout.as_secs(),
+                self.neard_status_frequency.as_secs()
+            );
+        }
+        if self.fencing_check_frequency >= self.leader_timeout {
+            bail!(
+                "fencing check frequency ({}s) must be smaller than the leader timeout ({}s)",
+                self.fencing_check_frequency.as_secs(),
+                self.leader_timeout.as_secs()
+            );
+        }
+        if self.candidate_grace <= self.acquire_leader_frequency {
+            bail!(
+                "candidate grace ({}s) must be larger than the acquire leader frequency ({}s), otherwise the candidate cannot take over",
+                self.candidate_grace.as_secs(),
+                self.acquire_leader_frequency.as_secs()
+            );
+        }
+        if self.handover_timeout <= self.acquire_leader_frequency {
+            bail!(
+                "handover timeout ({}s) must be larger than the acquire leader frequency ({}s), otherwise the target cannot take over",
+                self.handover_timeout.as_secs(),
+                self.acquire_leader_frequency.as_secs()
+            );
+        }
+        if self.max_status_errors == 0 {
+            bail!("neard max status errors must be at least 1");
+        }
+        if self.validator_startup_max_status_errors < self.max_status_errors {
+            bail!(
+                "validator startup max status errors ({}) must not be smaller than neard max status errors ({})",
+                self.validator_startup_max_status_errors,
+                self.max_status_errors
+            );
+        }
+        Ok(())
+    }
+}
+
+impl Settings {
+    /// (Re-)reads the tokens of the election backends from their files
+    pub fn read_tokens(&mut self) -> Result<()> {
+        self.consul_token = read_token_file(self.consul_token_file.as_deref(), "consul")?;
+        self.etcd_token = read_token_file(self.etcd_token_file.as_deref(), "etcd")?;
+        Ok(())
+    }
+
+    /// Returns the token of the configured election backend, if any
+    pub fn election_token(&self) -> Option<&str> {
+        match self.election_backend {
+            ElectionBackend::Consul => self.consul_token.as_deref(),
+            ElectionBackend::Etcd => self.etcd_token.as_deref(),
+            ElectionBackend::File => None,
+        }
+    }
+
+    /// Returns the timeouts and intervals of the supervisor
+    pub fn timings(&self) -> Timings {
+        Timings {
+            session_ttl: Duration::from_secs(self.session_ttl),
+            session_renewal: Duration::from_secs(self.session_renewal),
+            session_renewal_retry: Duration::from_secs(self.session_renewal_retry),
+            leader_timeout: Duration::from_secs(self.leader_timeout),
+            acquire_leader_frequency: Duration::from_secs(self.acquire_leader_frequency),
+            neard_startup_timeout: Duration::from_secs(self.neard_startup_timeout),
+            neard_status_frequency: Duration::from_secs(self.neard_status_frequency),
+            max_status_errors: self.neard_max_status_errors,
+            validator_startup_max_status_errors: self.validator_startup_max_status_errors,
+            candidate_grace: Duration::from_secs(self.candidate_grace),
+            handover_timeout: Duration::from_secs(self.handover_timeout),
+            failback_check_frequency: Duration::from_secs(self.failback_check_frequency),
+            fencing_check_frequency: Duration::from_secs(self.fencing_check_frequency),
+            double_sign_check_frequency: Duration::from_secs(self.double_sign_check_frequency),
+            validator_stats_frequency: Duration::from_secs(self.validator_stats_frequency),
+            memory_check_frequency: Duration::from_secs(self.memory_check_frequency),
+        }
+    }
+}
+
+fn read_token_file(file: Option<&Path>, backend: &str) -> Result<Option<String>> {
+    match file {
+        Some(file) => {
+            let s = fs::read_to_string(file).with_context(|| {
+                format!("cannot read {} token file {}", backend, file.display())
+            })?;
+            Ok(Some(s.trim_end().to_string()))
+        }
+        None => Ok(None),
+    }
 }
 
 fn get_near_key(val: &mut PathBuf, credential_filename: &str) -> Result<NearKey> {
@@ -127,6 +455,23 @@ fn get_near_key(val: &mut PathBuf, credential_filename: &str) -> Result<NearKey>
 pub fn parse_settings() -> Result<Settings> {
     let mut settings = Settings::parse();
 
+    settings
+        .timings()
+        .validate()
+        .context("invalid timing settings")?;
+    DiskRules::from_settings(&settings)
+        .validate()
+        .context("invalid disk settings")?;
+    if settings.election_backend == ElectionBackend::Consul {
+        // consul rejects sessions with a shorter ttl, handovers are announced with a session as well
+        if settings.session_ttl < 10 {
+            bail!("consul requires a session ttl of at least 10s");
+        }
+        if settings.handover_timeout < 10 {
+            bail!("consul requires a handover timeout of at least 10s");
+        }
+    }
+
     settings.account_id = AccountId::try_from(
         get_near_key(&mut settings.validator_key, "validator_key.json")?.account_id,
     )
@@ -137,14 +482,11 @@ pub fn parse_settings() -> Result<Settings> {
 
     get_near_key(&mut settings.voter_node_key, "voter_node_key.json")?;
 
-    settings.consul_token = match settings.consul_token_file {
-        Some(ref file) => {
-            let s = fs::read_to_string(file)
-                .with_context(|| format!("cannot read consul token file {}", file.display()))?;
-            Some(s.trim_end().to_string())
-        }
-        None => None,
-    };
+    settings.read_tokens()?;
+
+    if let Some(ref file) = settings.hooks_file {
+        settings.hooks = Hooks::from_file(file)?;
+    }
 
     let config_path = &settings.neard_home.join("config.json");
     let config = read_near_config(config_path).context("failed to parse near config")?;
@@ -153,3 +495,62 @@ pub fn parse_settings() -> Result<Settings> {
 
     Ok(settings)
 }
+
+/// Returns settings with the default values, regardless of `KUUTAMO_*` variables
+/// in the environment of the tests
+#[cfg(test)]
+pub fn default_settings() -> Settings {
+    use clap::CommandFactory;
+    // arguments take precedence over the environment
+    let args = Settings::command()
+        .get_arguments()
+        .filter_map(|arg| {
+            let default = arg.get_default_values().first()?.to_str()?;
+            Some(format!("--{}={}", arg.get_long()?, default))
+        })
+        .collect::<Vec<_>>();
+    Settings::try_parse_from(std::iter::once("kneard".to_string()).chain(args))
+        .expect("default settings should parse")
+}
+
+#[test]
+fn test_validate_timings() {
+    let settings = default_settings();
+    let timings = settings.timings();
+    timings.validate().unwrap();
+    assert_eq!(timings.session_ttl, Duration::from_secs(30));
+
+    let invalid = [
+        Timings {
+            leader_timeout: Duration::from_secs(30),
+            ..timings
+        },
+        Timings {
+            session_renewal: Duration::from_secs(25),
+            ..timings
+        },
+        Timings {
+            neard_status_frequency: Duration::ZERO,
+            ..timings
+        },
+        Timings {
+            validator_startup_max_status_errors: 2,
+            ..timings
+        },
+        Timings {
+            fencing_check_frequency: Duration::from_secs(25),
+            ..timings
+        },
+        Timings {
+            handover_timeout: Duration::from_secs(1),
+            ..timings
+        },
+        Timings {
+            memory_check_frequency: Duration::ZERO,
+            ..timings
+        },
+    ];
+    for t in invalid {
+        assert!(t.validate().is_err(), "{t:?} should be invalid");
+    }
+}
@@ -0,0 +1,182 @@
+//! Rules that make an active validator step down when neard falls behind the chain
+
+use crate::settings::Settings;
+use near_primitives::types::BlockHeight;
+use near_primitives::views::StatusResponse;
+use std::fmt;
+use std::time::SystemTime;
+use tokio::time::Duration;
+
+/// Thresholds after which a validator gives up its leadership. `None` disables a rule.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StepDownRules {
+    /// Maximum age of the head block of neard
+    pub max_block_age: Option<Duration>,
+    /// Maximum time without an increase of the block height
+    pub stalled_height: Option<Duration>,
+    /// Maximum time neard can report to be syncing
+    pub max_syncing: Option<Duration>,
+    /// How long a node that stepped down does not try to become validator again
+    pub cooldown: Duration,
+}
+
+impl StepDownRules {
+    /// Returns the rules configured in the settings
+    pub fn from_settings(settings: &Settings) -> Self {
+        Self {
+            max_block_age: settings.step_down_max_block_age.map(Duration::from_secs),
+            stalled_height: settings.step_down_stalled_height.map(Duration::from_secs),
+            max_syncing: settings.step_down_max_syncing.map(Duration::from_secs),
+            cooldown: Duration::from_secs(settings.step_down_cooldown),
+        }
+    }
+}
+
+/// The rule that fired
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StepDownReason {
+    /// The head block is older than allowed
+    BlockAge(Duration),
+    /// The block height has not increased for the given time
+    StalledHeight(BlockHeight, Duration),
+    /// neard is syncing for the given time
+    Syncing(Duration),
+}
+
+impl fmt::Display for StepDownReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StepDownReason::BlockAge(age) => {
+                write!(f, "head block is {}s old", age.as_secs())
+            }
+            StepDownReason::StalledHeight(height, d) => {
+                write!(f, "block height stuck at {height} for {}s", d.as_secs())
+            }
+            StepDownReason::Syncing(d) => write!(f, "neard is syncing for {}s", d.as_secs()),
+        }
+    }
+}
+
+/// Tracks the status of neard over time and evaluates the step down rules against it
+#[derive(Debug)]
+pub struct StepDownMonitor {
+    rules: StepDownRules,
+    last_height: BlockHeight,
+    height_since: Option<SystemTime>,
+    syncing_since: Option<SystemTime>,
+    firing: Option<StepDownReason>,
+}
+
+impl StepDownMonitor {
+    /// Returns a monitor without any observations
+    pub fn new(rules: &StepDownRules) -> Self {
+        Self {
+            rules: rules.clone(),
+            last_height: 0,
+            height_since: None,
+            syncing_since: None,
+            firing: None,
+        }
+    }
+
+    /// The rule that fired on the last observation, if any
+    pub fn firing(&self) -> Option<&StepDownReason> {
+        self.firing.as_ref()
+    }
+
+    /// Records a status response of neard received at `now` and returns the rule that fired, if any
+    pub fn check(&mut self, status: &StatusResponse, now: SystemTime) -> Option<StepDownReason> {
+        let block_time = SystemTime::UNIX_EPOCH
+            + Duration::from_millis(status.sync_info.latest_block_time.timestamp_millis() as u64);
+        let block_age = now.duration_since(block_time).unwrap_or_default();
+        self.observe(
+            status.sync_info.latest_block_height,
+            block_age,
+            status.sync_info.syncing,
+            now,
+        )
+    }
+
+    fn observe(
+        &mut self,
+        height: BlockHeight,
+        block_age: Duration,
+        syncing: bool,
+        now: SystemTime,
+    ) -> Option<StepDownReason> {
+        if height > self.last_height || self.height_since.is_none() {
+            self.last_height = height;
+            self.height_since = Some(now);
+        }
+        self.syncing_since = match (syncing, self.syncing_since) {
+            (true, None) => Some(now),
+            (true, since) => since,
+            (false, _) => None,
+        };
+
+        let since = |t: Option<SystemTime>| t.and_then(|t| now.duration_since(t).ok());
+        let stalled_for = since(self.height_since).unwrap_or_default();
+        let exceeds = |value: Duration, limit: Option<Duration>| limit.is_some_and(|l| value > l);
+        self.firing = if exceeds(block_age, self.rules.max_block_age) {
+            Some(StepDownReason::BlockAge(block_age))
+        } else if exceeds(stalled_for, self.rules.stalled_height) {
+            Some(StepDownReason::StalledHeight(self.last_height, stalled_for))
+        } else {
+            since(self.syncing_since)
+                .filter(|d| exceeds(*d, self.rules.max_syncing))
+                .map(StepDownReason::Syncing)
+        };
+        self.firing.clone()
+    }
+}
+
+#[test]
+fn test_step_down_rules() {
+    let rules = StepDownRules {
+        max_block_age: Some(Duration::from_secs(60)),
+        stalled_height: Some(Duration::from_secs(30)),
+        max_syncing: Some(Duration::from_secs(120)),
+        cooldown: Duration::from_secs(300),
+    };
+    let mut monitor = StepDownMonitor::new(&rules);
+    let start = SystemTime::UNIX_EPOCH;
+    let at = |secs| start + Duration::from_secs(secs);
+    let fresh = Duration::from_secs(1);
+
+    assert_eq!(monitor.observe(10, fresh, false, at(0)), None);
+    assert_eq!(monitor.observe(11, fresh, false, at(20)), None);
+    assert_eq!(
+        monitor.observe(11, fresh, false, at(51)),
+        Some(StepDownReason::StalledHeight(11, Duration::from_secs(31)))
+    );
+    assert_eq!(monitor.observe(12, fresh, false, at(52)), None);
+    assert_eq!(monitor.firing(), None);
+    assert_eq!(
+        monitor.observe(13, Duration::from_secs(61), false, at(53)),
+        Some(StepDownReason::BlockAge(Duration::from_secs(61)))
+    );
+
+    // syncing is only reported once it lasts longer than the threshold
+    let mut height = 13;
+    for secs in (60..=180).step_by(10) {
+        height += 1;
+        assert_eq!(monitor.observe(height, fresh, true, at(secs)), None);
+    }
+    assert_eq!(
+        monitor.observe(height + 1, fresh, true, at(181)),
+        Some(StepDownReason::Syncing(Duration::from_secs(121)))
+    );
+    assert_eq!(monitor.observe(height + 2, fresh, false, at(182)), None);
+}
+
+#[test]
+fn test_step_down_rules_disabled() {
+    let mut monitor = StepDownMonitor::new(&StepDownRules::default());
+    let start = SystemTime::UNIX_EPOCH;
+    let stale = Duration::from_secs(3600);
+    assert_eq!(monitor.observe(10, stale, true, start), None);
+    assert_eq!(
+        monitor.observe(10, stale, true, start + Duration::from_secs(3600)),
+        None
+    );
+}
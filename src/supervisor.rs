@@ -1,18 +1,37 @@
-//! Supervises neard and participate in consul leader election.
+//! Supervises neard and participate in leader election.
 //! The neard process of leader will get the validator key.
 
 //use crate::commands::CommandHandler;
-use crate::consul_client::{ConsulClient, ConsulError, ConsulSession};
+use crate::cgroup::{CgroupLimits, NeardCgroup};
+use crate::clock::{Clock, SystemClock};
+use crate::crash_report::{CrashRecord, CrashReports, ExitReason};
+use crate::disk::{DiskRules, DiskUsage};
+use crate::double_sign::DoubleSignGuard;
+use crate::drain::{is_drained, set_drained};
 use crate::exit_signal_handler::ExitSignalHandler;
+use crate::health::{HealthMonitor, HealthRules};
+use crate::hooks::HookPayload;
 use crate::ipc::Request;
-use crate::leader_protocol::consul_leader_key;
-use crate::near_client::NeardClient;
-use crate::neard_process::{apply_dynamic_config, setup_validator, setup_voter, NeardProcess};
-use crate::scoped_consul_session::ScopedConsulSession;
-use crate::settings::Settings;
+use crate::journal::{Journal, TransitionEntry, TransitionReason};
+use crate::leader_protocol::{
+    candidate_priority, fencing_token, handover_target, leader_election, preferred_candidate,
+    ElectionError, ElectionKeys, ElectionSession, LeaderElection,
+};
+use crate::maintenance::MaintenancePlan;
+use crate::memory::{MemoryAction, MemoryIssue, MemoryRules};
+use crate::migration::{next_migration, DbVersions, ExpectedMigration};
+use crate::near_client::{NeardApi, NeardClient};
+use crate::neard_binary::{stage_neard, NeardBinaries, NeardVersion};
+use crate::neard_process::{apply_dynamic_config, Neard, NeardLauncher, NeardProcessLauncher};
+use crate::schedule::{OperationMode, Schedule, ScheduledOperation};
+use crate::scoped_session::ScopedSession;
+use crate::settings::{Settings, Timings};
+use crate::step_down::{StepDownMonitor, StepDownRules};
+use crate::validator_stats::ValidatorStats;
 use crate::{ipc, oom_score};
-use anyhow::bail;
+use anyhow::{anyhow, bail};
 use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
 use futures_util::FutureExt;
 use lazy_static::lazy_static;
 use log::{info, warn};
@@ -22,17 +41,23 @@ use nix::unistd::{self, Pid};
 use prometheus::{register_int_gauge_vec, IntGaugeVec};
 use std::borrow::Borrow;
 use std::collections::HashMap;
+use std::fmt;
+use std::io;
 use std::ops::Add;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::process::ExitStatus;
 use std::sync::{
-    atomic::{AtomicBool, Ordering},
+    atomic::{AtomicU64, Ordering},
     Arc,
 };
-use std::{fmt, fs};
+use std::time::SystemTime;
 use tokio::signal::unix::{signal, Signal, SignalKind};
 use tokio::sync::mpsc::Receiver;
 use tokio::time::{self, Duration, Instant};
 
+#[cfg(test)]
+mod simulation;
+
 lazy_static! {
     static ref STATE: IntGaugeVec = register_int_gauge_vec!(
         "kuutamod_state",
@@ -42,21 +67,11 @@ lazy_static! {
     .unwrap();
 }
 
-/// How long a session is valid
-const CONSUL_SESSION_TTL: Duration = Duration::from_secs(30);
-/// How often to renew a consul session
-const CONSUL_SESSION_RENEWAL: Duration = Duration::from_secs(10);
-const CONSUL_SESSION_RENEWAL_ERROR: Duration = Duration::from_secs(5);
-/// How much time we give neard to make it's `/status` endpoint available
-const NEARD_STARTUP_TIMEOUT: Duration = Duration::from_secs(120);
-/// How often we try to become consul leader (validator)
-const CONSUL_ACQUIRE_LEADER_FREQUENCY: Duration = Duration::from_secs(1);
-/// How long a leader will wait when it cannot update its consul session until steps down and stop doing validation
-const CONSUL_LEADER_TIMEOUT: Duration = Duration::from_secs(25);
-/// How often we query neard's `/status` endpoint
-const NEARD_STATUS_FREQUENCY: Duration = Duration::from_secs(1);
-/// Shutdown kneard if neard shutdown as expected
-pub static SHUTDOWN_WITH_NEARD: AtomicBool = AtomicBool::new(false);
+/// neard stops right after it reached the block height of a scheduled operation, usually
+/// before we saw that height in `/status`. A few blocks cover the time between two requests.
+const OPERATION_HEIGHT_TOLERANCE: BlockHeight = 5;
+/// Latest block height reported by neard's `/status` endpoint, 0 if unknown
+static LATEST_BLOCK_HEIGHT: AtomicU64 = AtomicU64::new(0);
 
 // When adding states also update `initialize_state_gauge`
 #[derive(PartialEq, Debug, Clone, Copy)]
@@ -90,28 +105,86 @@ fn initialize_state_gauge() {
         .set(0);
 }
 
+/// Exports the drain state next to the states of the supervisor
+fn export_drained(drained: bool) {
+    STATE
+        .with_label_values(&["Drained"])
+        .set(i64::from(drained));
+}
+
 impl fmt::Display for StateType {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "{self:?}")
     }
 }
 
+/// The next state of the supervisor and why it changes
+struct Transition {
+    state: StateType,
+    reason: TransitionReason,
+}
+
+impl Transition {
+    fn new(state: StateType, reason: TransitionReason) -> Self {
+        Self { state, reason }
+    }
+}
+
 #[derive(Debug)]
 struct StateMachine {
     inner: StateType,
     settings: Settings,
-    neard_process: Option<NeardProcess>,
-    neard_client: NeardClient,
-    consul_client: ConsulClient,
-    consul_session: Option<ConsulSession>,
+    launcher: Box<dyn NeardLauncher>,
+    neard_process: Option<Box<dyn Neard>>,
+    neard_client: Box<dyn NeardApi>,
+    election: Box<dyn LeaderElection>,
+    clock: Box<dyn Clock>,
+    session: Option<ElectionSession>,
     exit_signal_handler: ExitSignalHandler,
     reload_signal: Signal,
     leader_metadata: HashMap<&'static str, String>,
-    leader_key: String,
+    keys: ElectionKeys,
+    /// Session that announces our handover and when the announcement expires
+    handover_session: Option<(ElectionSession, Instant)>,
     request_chan: Receiver<ipc::Request>,
+    step_down_rules: StepDownRules,
+    /// We do not compete for leadership before, after we stepped down
+    step_down_until: Option<Instant>,
+    health_rules: HealthRules,
+    memory_rules: MemoryRules,
+    timings: Timings,
+    double_sign: DoubleSignGuard,
+    journal: Journal,
+    crashes: CrashReports,
+    schedule: Schedule,
 }
 
-fn get_leader_metadata(node_id: &str) -> Result<HashMap<&'static str, String>> {
+/// The processes and services the supervisor interacts with
+#[derive(Debug)]
+struct Environment {
+    launcher: Box<dyn NeardLauncher>,
+    neard_client: Box<dyn NeardApi>,
+    election: Box<dyn LeaderElection>,
+    clock: Box<dyn Clock>,
+}
+
+impl Environment {
+    /// Returns neard, its api and the election backend configured in the settings
+    fn from_settings(settings: &Settings) -> Result<Self> {
+        Ok(Self {
+            launcher: Box::new(NeardProcessLauncher),
+            neard_client: Box::new(NeardClient::new(&format!(
+                "http://localhost:{}",
+                settings.near_rpc_addr.port()
+            ))?),
+            election: leader_election(settings)
+                .context("Failed to create leader election backend")?,
+            clock: Box::new(SystemClock),
+        })
+    }
+}
+
+fn get_leader_metadata(node_id: &str, priority: u32) -> Result<HashMap<&'static str, String>> {
     let hostname_cstr = unistd::gethostname().context("Failed getting hostname")?;
     let hostname = match hostname_cstr.into_string() {
         Ok(v) => v,
@@ -123,85 +196,157 @@ fn get_leader_metadata(node_id: &str) -> Result<HashMap<&'static str, String>> {
     let mut metadata: HashMap<&str, String> = HashMap::new();
     metadata.insert("Hostname", hostname);
     metadata.insert("NodeId", node_id.into());
+    metadata.insert("Priority", priority.to_string());
     Ok(metadata)
 }
 
 impl StateMachine {
-    pub fn new(settings: &Settings, request_chan: Receiver<ipc::Request>) -> Result<StateMachine> {
+    fn new(
+        settings: &Settings,
+        request_chan: Receiver<ipc::Request>,
+        env: Environment,
+    ) -> Result<StateMachine> {
         Ok(StateMachine {
             inner: StateType::Startup,
             settings: settings.clone(),
+            launcher: env.launcher,
             neard_process: None,
-            neard_client: NeardClient::new(&format!(
-                "http://localhost:{}",
-                settings.near_rpc_addr.port()
-            ))?,
-            consul_client: ConsulClient::new(
-                &settings.consul_url,
-                settings.consul_token.as_deref(),
-            )
-            .context("Failed to create consul client")?,
-            consul_session: None,
+            neard_client: env.neard_client,
+            election: env.election,
+            clock: env.clock,
+            session: None,
             exit_signal_handler: ExitSignalHandler::new()
                 .context("Failed to setup signal handler")?,
             reload_signal: signal(SignalKind::user_defined1())
                 .context("Cannot register SIGUSR1 handler")?,
-            leader_metadata: get_leader_metadata(&settings.node_id)
+            leader_metadata: get_leader_metadata(&settings.node_id, settings.priority)
                 .context("Failed to construct leader metadata")?,
-            leader_key: consul_leader_key(&settings.account_id),
+            keys: ElectionKeys::new(&settings.account_id, &settings.node_id),
+            handover_session: None,
             request_chan,
+            step_down_rules: StepDownRules::from_settings(settings),
+            step_down_until: None,
+            health_rules: HealthRules::from_settings(settings),
+            memory_rules: MemoryRules::from_settings(settings),
+            timings: settings.timings(),
+            double_sign: DoubleSignGuard::new(settings),
+            journal: Journal::new(&settings.neard_home),
+            crashes: CrashReports::new(&settings.neard_home, settings.neard_restart_budget),
+            schedule: Schedule::new(&settings.neard_home),
         })
     }
 }
 
 struct NeardStatus {
     next_try: Instant,
-    continuous_errors: u8,
+    frequency: Duration,
+    continuous_errors: u32,
+    max_errors: u32,
+    step_down: StepDownMonitor,
+    health: HealthMonitor,
+    /// Latest block height neard reported in this state
+    head: Option<BlockHeight>,
+    /// Status errors are not counted before, so that a freshly started neard can open its status api
+    starting_until: Instant,
 }
 
 impl NeardStatus {
-    fn new() -> Self {
+    fn new(rules: &StepDownRules, health_rules: &HealthRules, timings: &Timings) -> Self {
         Self {
             next_try: Instant::now(),
+            frequency: timings.neard_status_frequency,
             continuous_errors: 0,
+            max_errors: timings.max_status_errors,
+            step_down: StepDownMonitor::new(rules),
+            health: HealthMonitor::new(health_rules),
+            head: None,
+            starting_until: Instant::now(),
         }
     }
 
-    async fn query(&mut self, c: &NeardClient) -> Result<StatusResponse> {
+    async fn query(&mut self, c: &dyn NeardApi) -> Result<StatusResponse> {
         time::sleep_until(self.next_try).await;
         let res = c.status().await;
-        self.next_try = Instant::now().add(NEARD_STATUS_FREQUENCY);
+        if let Ok(ref status) = res {
+            LATEST_BLOCK_HEIGHT.store(status.sync_info.latest_block_height, Ordering::SeqCst);
+            self.head = Some(status.sync_info.latest_block_height);
+        }
+        self.next_try = Instant::now().add(self.frequency);
         res
     }
 
-    async fn handle_neard_desyncs(&mut self, c: &NeardClient) -> StateType {
-        while self.continuous_errors < 3 {
-            let status = self.query(c).await;
-            match status {
-                Ok(status) => {
-                    self.continuous_errors = 0;
-                    if status.sync_info.syncing {
-                        // node is synced fully with the network
-                        return StateType::Syncing;
-                    }
+    async fn handle_neard_desyncs(&mut self, c: &dyn NeardApi, clock: &dyn Clock) -> Transition {
+        loop {
+            if let Some(transition) = self.check_neard(c, clock).await {
+                return transition;
+            }
+        }
+    }
+
+    /// Like `handle_neard_desyncs`, but also returns None once neard became healthy or
+    /// unhealthy or a step down rule started or stopped firing, so that the caller can
+    /// reconsider competing for leadership
+    async fn handle_neard_desyncs_and_health(
+        &mut self,
+        c: &dyn NeardApi,
+        clock: &dyn Clock,
+    ) -> Option<Transition> {
+        let usable = self.is_usable();
+        loop {
+            let transition = self.check_neard(c, clock).await;
+            if transition.is_some() || self.is_usable() != usable {
+                return transition;
+            }
+        }
+    }
+
+    /// Whether neard is healthy and no step down rule fires
+    fn is_usable(&self) -> bool {
+        self.health.is_healthy() && self.step_down.firing().is_none()
+    }
+
+    /// Queries neard once, returns a transition if neard is no longer usable
+    async fn check_neard(&mut self, c: &dyn NeardApi, clock: &dyn Clock) -> Option<Transition> {
+        if self.continuous_errors >= self.max_errors {
+            return Some(Transition::new(
+                StateType::Startup,
+                TransitionReason::NeardUnreachable,
+            ));
+        }
+        match self.query(c).await {
+            Ok(status) => {
+                self.continuous_errors = 0;
+                self.step_down.check(&status, clock.now());
+                self.health.check(c, &status, clock.now()).await;
+                if status.sync_info.syncing {
+                    // node is synced fully with the network
+                    return Some(Transition::new(
+                        StateType::Syncing,
+                        TransitionReason::Desync,
+                    ));
                 }
-                Err(err) => {
+            }
+            Err(err) => {
+                if Instant::now() >= self.starting_until {
                     self.continuous_errors += 1;
-                    warn!("Cannot reach neard status api: {}", err);
                 }
+                warn!("Cannot reach neard status api: {}", err);
             }
         }
-        StateType::Startup
+        None
     }
 }
 
-async fn wait_for_neard_exit(neard_process: Option<&mut NeardProcess>) {
-    if let Some(p) = neard_process {
-        match p.wait().await {
-            Ok(res) => warn!("Neard finished unexpectedly with {}. Check the logs above for potential error or panic messages from neard.", res),
-            Err(err) => warn!("Cannot get status of neard process {}", err),
-        }
+/// Waits for neard to exit, returns right away if it is not running
+async fn wait_for_neard_exit(
+    neard_process: Option<&mut Box<dyn Neard>>,
+) -> Option<io::Result<ExitStatus>> {
+    let res = neard_process?.wait().await;
+    match res {
+        Ok(ref res) => warn!("Neard finished unexpectedly with {}. Check the logs above for potential error or panic messages from neard.", res),
+        Err(ref err) => warn!("Cannot get status of neard process {}", err),
     }
+    Some(res)
 }
 
 struct CreateSession {
@@ -217,15 +362,17 @@ impl CreateSession {
             backoff: 1,
         }
     }
-    async fn run(&mut self, c: &ConsulClient, node_id: &str) -> Option<ConsulSession> {
+    async fn run(
+        &mut self,
+        c: &dyn LeaderElection,
+        node_id: &str,
+        ttl: Duration,
+    ) -> Option<ElectionSession> {
         time::sleep_until(self.next_try).await;
-        match c
-            .create_session(node_id, CONSUL_SESSION_TTL.as_secs())
-            .await
-        {
+        match c.create_session(node_id, ttl).await {
             Ok(s) => Some(s),
             Err(e) => {
-                warn!("Cannot reach consul: {}", e);
+                warn!("Cannot reach election backend: {}", e);
                 self.backoff = std::cmp::min(self.backoff * 2, 5000);
                 self.next_try = Instant::now().add(Duration::from_millis(self.backoff));
                 None
@@ -239,16 +386,111 @@ enum ChorumResult {
     IsMaster,
 }
 
+/// Candidacy of a voting node
+struct Candidacy {
+    registered: bool,
+    deferred_since: Option<Instant>,
+    /// How long we defer to a candidate with higher priority
+    grace: Duration,
+}
+
+impl Candidacy {
+    fn new(grace: Duration) -> Self {
+        Self {
+            registered: false,
+            deferred_since: None,
+            grace,
+        }
+    }
+
+    /// Returns true, if we should leave the leadership to a candidate with higher priority.
+    /// If that candidate does not become leader within `grace`, we compete again.
+    async fn defer(
+        &mut self,
+        c: &dyn LeaderElection,
+        keys: &ElectionKeys,
+        metadata: &HashMap<&'static str, String>,
+        now: SystemTime,
+    ) -> bool {
+        match c.list_locks(&keys.handover).await {
+            Ok(handovers) => match handover_target(&handovers, now) {
+                Some(target) if target == metadata["NodeId"] => return false,
+                Some(target) => {
+                    info!("Leadership is handed over to {}", target);
+                    return true;
+                }
+                None => {}
+            },
+            Err(e) => warn!("failed to list handovers: {}", e),
+        }
+        let candidates = match c.list_locks(&keys.candidates).await {
+            Ok(candidates) => candidates,
+            Err(e) => {
+                warn!("failed to list candidates: {}", e);
+                return false;
+            }
+        };
+        match preferred_candidate(
+            &candidates,
+            &metadata["NodeId"],
+            candidate_priority(metadata),
+        ) {
+            Some((node, priority)) => {
+                let since = match self.deferred_since {
+                    Some(since) => since,
+                    None => {
+                        info!("Defer leadership to {} with priority {}", node, priority);
+                        *self.deferred_since.insert(Instant::now())
+                    }
+                };
+                since.elapsed() < self.grace
+            }
+            None => {
+                self.deferred_since = None;
+                false
+            }
+        }
+    }
+
+    /// Withdraws our candidacy, so that no leader hands over to us
+    async fn withdraw(
+        &mut self,
+        c: &dyn LeaderElection,
+        keys: &ElectionKeys,
+        session: &ElectionSession,
+    ) {
+        match c.release_lock(&keys.candidate, session).await {
+            Ok(()) => {
+                self.registered = false;
+                self.deferred_since = None;
+            }
+            Err(e) => warn!("failed to withdraw candidacy: {}", e),
+        }
+    }
+}
+
 async fn acquire_key(
-    c: &ConsulClient,
-    leader_key: &str,
+    c: &dyn LeaderElection,
+    keys: &ElectionKeys,
     metadata: &HashMap<&'static str, String>,
-    session: &ConsulSession,
+    session: &ElectionSession,
+    candidacy: &mut Candidacy,
+    clock: &dyn Clock,
 ) -> ChorumResult {
-    let res = c.acquire_key(leader_key, metadata, session);
+    // announce ourselves, so that a leader with lower priority can hand over to us
+    if !candidacy.registered {
+        match c.acquire_lock(&keys.candidate, metadata, session).await {
+            Ok(registered) => candidacy.registered = registered,
+            Err(e) => warn!("failed to register as candidate: {}", e),
+        }
+    }
+    if candidacy.defer(c, keys, metadata, clock.now()).await {
+        return ChorumResult::IsFollower;
+    }
+    let res = c.acquire_lock(&keys.leader, metadata, session);
     match res.await {
         // FIXME this could spam logs quite a bit (every second) -> add a rate limit for prints
-        Err(e) => warn!("failed to contact consul: {}", e),
+        Err(e) => warn!("failed to contact election backend: {}", e),
         Ok(is_master) => {
             if is_master {
                 return ChorumResult::IsMaster;
@@ -258,19 +500,177 @@ async fn acquire_key(
     ChorumResult::IsFollower
 }
 
-fn reload_configuration(settings: &mut Settings, consul_client: &ConsulClient) -> Result<()> {
-    settings.consul_token = match settings.consul_token_file {
-        Some(ref file) => {
-            let s = fs::read_to_string(file)
-                .with_context(|| format!("cannot read consul token file {}", file.display()))?;
-            info!("Update consul token");
-            Some(s.trim_end().to_string())
+/// Returns true, if `block_height` lies in one of our maintenance windows
+async fn in_maintenance_window(
+    neard_client: &dyn NeardApi,
+    account_id: &AccountId,
+    block_height: BlockHeight,
+) -> bool {
+    match neard_client.maintenance_windows(account_id).await {
+        Ok(windows) => windows
+            .0
+            .iter()
+            .any(|(start, end)| (*start..*end).contains(&block_height)),
+        Err(e) => {
+            warn!("failed to get maintenance windows: {}", e);
+            false
+        }
+    }
+}
+
+/// Returns the usage of neard's filesystem, if it exceeds the critical threshold
+fn critical_disk_usage(rules: Option<&DiskRules>) -> Option<DiskUsage> {
+    let rules = rules?;
+    match rules.check() {
+        Ok(usage) => Some(usage).filter(|usage| rules.is_critical(usage)),
+        Err(e) => {
+            warn!("Cannot get disk usage: {:#}", e);
+            None
+        }
+    }
+}
+
+/// Returns the node id of a candidate with higher priority, if we should
+/// hand over our leadership to it.
+async fn failback_candidate(
+    c: &dyn LeaderElection,
+    keys: &ElectionKeys,
+    metadata: &HashMap<&'static str, String>,
+) -> Option<String> {
+    let candidates = match c.list_locks(&keys.candidates).await {
+        Ok(candidates) => candidates,
+        Err(e) => {
+            warn!("failed to list candidates: {}", e);
+            return None;
         }
-        None => None,
     };
-    consul_client
-        .set_token(settings.consul_token.as_deref())
-        .context("failed to update consul token")?;
+    preferred_candidate(
+        &candidates,
+        &metadata["NodeId"],
+        candidate_priority(metadata),
+    )
+    .map(|(node, _)| node)
+}
+
+/// Returns the node id of the candidate with the highest priority other than `node_id`,
+/// i.e. a synced and healthy node that can take over our leadership
+async fn takeover_candidate(
+    c: &dyn LeaderElection,
+    keys: &ElectionKeys,
+    node_id: &str,
+) -> Option<String> {
+    match c.list_locks(&keys.candidates).await {
+        Ok(candidates) => candidates
+            .iter()
+            .filter_map(|c| Some((c.get("NodeId")?, candidate_priority(c))))
+            .filter(|(node, _)| node.as_str() != node_id)
+            .max_by_key(|(_, priority)| *priority)
+            .map(|(node, _)| node.clone()),
+        Err(e) => {
+            warn!("failed to list candidates: {}", e);
+            None
+        }
+    }
+}
+
+/// Returns true, if `node_id` is registered as candidate, i.e. it is synced and voting
+async fn is_candidate(c: &dyn LeaderElection, keys: &ElectionKeys, node_id: &str) -> Result<bool> {
+    let candidates = c
+        .list_locks(&keys.candidates)
+        .await
+        .context("failed to list candidates")?;
+    Ok(candidates
+        .iter()
+        .any(|c| c.get("NodeId").map(String::as_str) == Some(node_id)))
+}
+
+/// Returns the node we should hand over our leadership to now. This is either
+/// the target of a requested handover or a candidate with higher priority.
+/// Both only happen during a maintenance window.
+async fn handover_candidate(
+    c: &dyn LeaderElection,
+    neard_client: &dyn NeardApi,
+    keys: &ElectionKeys,
+    metadata: &HashMap<&'static str, String>,
+    account_id: &AccountId,
+    block_height: Option<BlockHeight>,
+    requested: &mut Option<String>,
+) -> Option<String> {
+    if !in_maintenance_window(neard_client, account_id, block_height?).await {
+        return None;
+    }
+    if let Some(target) = requested.take() {
+        match is_candidate(c, keys, &target).await {
+            Ok(true) => return Some(target),
+            Ok(false) => warn!("Cancel handover: {} is no longer voting", target),
+            Err(e) => {
+                warn!("Cannot check handover target: {}", e);
+                *requested = Some(target);
+                return None;
+            }
+        }
+    }
+    failback_candidate(c, keys, metadata).await
+}
+
+/// Announces to all nodes that `target` should become the next leader
+async fn announce_handover(
+    c: &dyn LeaderElection,
+    keys: &ElectionKeys,
+    node_id: &str,
+    target: &str,
+    timeout: Duration,
+    now: SystemTime,
+) -> Result<ElectionSession> {
+    let session = c
+        .create_session(node_id, timeout)
+        .await
+        .context("failed to create handover session")?;
+    let expires = now
+        .add(timeout)
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default();
+    let metadata = HashMap::from([
+        ("NodeId", target.to_string()),
+        ("Expires", expires.as_secs().to_string()),
+    ]);
+    match c
+        .acquire_lock(&keys.handover_key(target), &metadata, &session)
+        .await
+    {
+        Ok(true) => Ok(session),
+        res => {
+            if let Err(e) = c.destroy_session(&session).await {
+                warn!("failed to destroy handover session: {}", e);
+            }
+            match res {
+                Err(e) => Err(e).context("failed to announce handover"),
+                _ => bail!("handover to {} is already announced", target),
+            }
+        }
+    }
+}
+
+/// Destroys the session that announces our handover, i.e. once the handover expired
+async fn withdraw_handover(
+    c: &dyn LeaderElection,
+    handover_session: &mut Option<(ElectionSession, Instant)>,
+) {
+    if let Some((s, _)) = handover_session.take() {
+        if let Err(e) = c.destroy_session(&s).await {
+            warn!("failed to destroy handover session: {}", e);
+        }
+    }
+}
+
+fn reload_configuration(settings: &mut Settings, election: &dyn LeaderElection) -> Result<()> {
+    settings.read_tokens()?;
+    if settings.election_token().is_some() {
+        info!("Update election backend token");
+    }
+    election
+        .set_token(settings.election_token())
+        .context("failed to update election backend token")?;
     Ok(())
 }
 
@@ -281,71 +681,261 @@ async fn schedule_maintenance_shutdown(
     account_id: &AccountId,
     minimum_length: Option<u64>,
     shutdown_window: Option<u64>,
-) -> Result<Option<BlockHeight>> {
+    force: bool,
+) -> Result<(BlockHeight, Option<ExpectedMigration>)> {
     let neard_client = NeardClient::new(&format!("http://127.0.0.1:{near_rpc_port}"))?;
+    let migration = match next_migration(near_home).await {
+        Ok(migration) => migration,
+        Err(e) => {
+            warn!("Cannot check whether neard migrates its database: {:#}", e);
+            None
+        }
+    };
+    if let Some(ref migration) = migration {
+        warn!("neard will run a {} on its next start", migration);
+    }
     let expect_shutdown_at = match (minimum_length, shutdown_window) {
         (Some(_), Some(_)) => {
             bail!("We can not guarantee minimum maintenance window for a specified shutdown block height");
         }
-        (None, Some(w)) => Some(w),
-        (Some(minimum_length), None) if minimum_length > 0 => match neard_client
-            .maintenance_windows(account_id)
-            .await?
-            .0
-            .iter()
-            .find(|window| window.1 - window.0 > minimum_length)
-        {
-            Some(window) => Some(window.0 + 2),
-            None => {
-                bail!(
-                    "Neard has no maintenance window of size ({}) in current epoch, please wait",
-                    minimum_length
-                );
-            }
+        (None, Some(w)) => match migration.as_ref().filter(|_| !force) {
+            // we cannot tell whether the migration finishes in time
+            Some(m) => bail!("neard will run a {m} after the shutdown at block {w}, use --force to schedule anyway"),
+            None => w,
         },
-        // If minimum_length is 0 or no requirement specified, we will try to use the largest window in current epoch
-        (Some(_), None) | (None, None) => {
-            let mut windows_size = 0;
-            let mut windows_start = 0;
-            for (start, end) in neard_client
-                .maintenance_windows(account_id)
-                .await?
-                .0
-                .into_iter()
-            {
-                if start - end > windows_size {
-                    windows_size = start - end;
-                    windows_start = start;
+        (minimum_length, None) => {
+            let plan = MaintenancePlan::query(&neard_client, account_id).await?;
+            match migration.as_ref().filter(|_| !force) {
+                // the window has to fit the migration
+                Some(
+                    m @ ExpectedMigration {
+                        estimate: Some(estimate),
+                        ..
+                    },
+                ) => {
+                    let blocks = plan
+                        .blocks_in(Duration::from_secs(*estimate))
+                        .with_context(|| format!("cannot plan the {m} without knowing the block rate, use --force to schedule anyway"))?;
+                    plan.shutdown_height(Some(blocks.max(minimum_length.unwrap_or(0))))
+                        .with_context(|| format!("no maintenance window fits the {m}, use --force to schedule anyway"))?
                 }
+                Some(m) => bail!("cannot tell whether a maintenance window fits the {m}, use --force to schedule anyway"),
+                // without a minimum length, we use the longest window
+                _ => plan.shutdown_height(minimum_length)?,
             }
-            if windows_start > 0 {
-                Some(windows_start + 2)
-            } else {
-                bail!("Neard has no maintenance window of in current epoch, please wait");
-            }
         }
     };
-    apply_dynamic_config(&neard_client, pid, near_home, expect_shutdown_at).await?;
-    Ok(expect_shutdown_at)
+    apply_dynamic_config(&neard_client, pid, near_home, Some(expect_shutdown_at)).await?;
+    Ok((expect_shutdown_at, migration))
+}
+
+async fn cancel_maintenance_shutdown(near_rpc_port: u16, pid: Pid, near_home: &Path) -> Result<()> {
+    let neard_client = NeardClient::new(&format!("http://127.0.0.1:{near_rpc_port}"))?;
+    apply_dynamic_config(&neard_client, pid, near_home, None).await
+}
+
+/// Drops the scheduled operation, if neard already passed its block height
+fn expire_scheduled_operation(
+    schedule: &Schedule,
+    head: BlockHeight,
+) -> Option<ScheduledOperation> {
+    let operation = match schedule.get() {
+        Ok(operation) => operation?,
+        Err(e) => {
+            warn!("Cannot read scheduled operation: {:#}", e);
+            return None;
+        }
+    };
+    if !operation.is_past_due(head) {
+        return Some(operation);
+    }
+    warn!(
+        "Drop scheduled {}, neard is already at block height {}",
+        operation, head
+    );
+    if let Err(e) = schedule.clear() {
+        warn!("Cannot drop scheduled operation: {:#}", e);
+    }
+    None
 }
 
-async fn cancel_maintenance_shutdown(
+/// Applies the scheduled operation to neard again, since neard forgets it when it restarts
+async fn reapply_scheduled_operation(
+    schedule: &Schedule,
+    near_rpc_port: u16,
+    near_home: &Path,
+    pid: Option<Pid>,
+    head: BlockHeight,
+) {
+    let (operation, pid) = match (expire_scheduled_operation(schedule, head), pid) {
+        (Some(operation), Some(pid)) => (operation, pid),
+        _ => return,
+    };
+    let res = match NeardClient::new(&format!("http://127.0.0.1:{near_rpc_port}")) {
+        Ok(client) => apply_dynamic_config(&client, pid, near_home, Some(operation.height)).await,
+        Err(e) => Err(e),
+    };
+    match res {
+        Ok(()) => info!("Applied scheduled {}", operation),
+        Err(e) => warn!("Cannot apply scheduled {}: {:#}", operation, e),
+    }
+}
+
+/// Switches to the staged neard binary, if there is one
+fn switch_neard_binary(near_home: &Path) -> Result<()> {
+    let mut binaries = NeardBinaries::load(near_home)?;
+    if binaries.switch() {
+        binaries.save(near_home)?;
+        info!(
+            "Switch to neard binary {}",
+            binaries.current_binary().display()
+        );
+    }
+    Ok(())
+}
+
+/// Whether neard stopped for the scheduled operation. A neard that failed or stopped before
+/// it reached the block height of the operation crashed, so the operation is kept for the
+/// restarted neard. `head` is the latest block height neard reported.
+fn stopped_for_operation(
+    schedule: &Schedule,
+    status: &ExitStatus,
+    head: Option<BlockHeight>,
+) -> bool {
+    if !status.success() {
+        return false;
+    }
+    let operation = match schedule.get() {
+        Ok(operation) => operation,
+        Err(e) => {
+            warn!("Cannot read scheduled operation: {:#}", e);
+            return false;
+        }
+    };
+    match (operation, head) {
+        (Some(operation), Some(head)) => {
+            operation.is_past_due(head.saturating_add(OPERATION_HEIGHT_TOLERANCE))
+        }
+        _ => false,
+    }
+}
+
+/// Drops the operation neard executed by stopping and returns what has to happen next.
+/// A restart switches to the staged neard binary.
+fn finish_scheduled_operation(schedule: &Schedule, near_home: &Path) -> Option<OperationMode> {
+    let operation = match schedule.get() {
+        Ok(operation) => operation?,
+        Err(e) => {
+            warn!("Cannot read scheduled operation: {:#}", e);
+            return None;
+        }
+    };
+    info!("Neard stopped for scheduled {}", operation);
+    if let Err(e) = schedule.clear() {
+        warn!("Cannot drop scheduled operation: {:#}", e);
+    }
+    if operation.mode == OperationMode::Restart {
+        if let Err(e) = switch_neard_binary(near_home) {
+            warn!("Cannot switch to the staged neard binary: {:#}", e);
+        }
+    }
+    Some(operation.mode)
+}
+
+/// Schedules a restart of the validator in the next maintenance window, unless another
+/// operation is scheduled already
+async fn schedule_memory_restart(
+    schedule: &Schedule,
     near_rpc_port: u16,
     pid: Pid,
     near_home: &Path,
-) -> Result<Option<BlockHeight>> {
+    account_id: &AccountId,
+    now: SystemTime,
+) -> Result<Option<ScheduledOperation>> {
+    if schedule.get()?.is_some() {
+        return Ok(None);
+    }
+    // the earliest window that leaves at least one block for the restart
+    let (height, migration) = schedule_maintenance_shutdown(
+        near_rpc_port,
+        pid,
+        near_home,
+        account_id,
+        Some(1),
+        None,
+        false,
+    )
+    .await?;
+    let mut operation = ScheduledOperation::new(
+        height,
+        OperationMode::Restart,
+        &StateType::Validating.to_string(),
+        now,
+    );
+    operation.migration = migration;
+    schedule
+        .set(&operation)
+        .context("failed to persist the scheduled restart")?;
+    Ok(Some(operation))
+}
+
+/// Stages a neard binary, or the previous one if `binary` is None. The validator switches
+/// to it with a restart in the longest maintenance window, voters switch right away.
+async fn stage_neard_binary(
+    near_rpc_port: u16,
+    near_home: &Path,
+    account_id: &AccountId,
+    binary: Option<PathBuf>,
+    force: bool,
+    validator_pid: Option<Pid>,
+    now: SystemTime,
+) -> Result<(NeardVersion, Option<ScheduledOperation>)> {
+    let binary = match binary {
+        Some(binary) => binary,
+        None => NeardBinaries::load(near_home)?.rollback_binary()?,
+    };
     let neard_client = NeardClient::new(&format!("http://127.0.0.1:{near_rpc_port}"))?;
-    apply_dynamic_config(&neard_client, pid, near_home, None).await?;
-    Ok(None)
+    let status = neard_client
+        .status()
+        .await
+        .context("failed to get status")?;
+    let version = stage_neard(near_home, &binary, status.protocol_version).await?;
+    let pid = match validator_pid {
+        Some(pid) => pid,
+        None => {
+            if let Ok(Some(migration)) = next_migration(near_home).await {
+                warn!("neard will run a {} on its next start", migration);
+            }
+            switch_neard_binary(near_home)?;
+            return Ok((version, None));
+        }
+    };
+    let (height, migration) =
+        schedule_maintenance_shutdown(near_rpc_port, pid, near_home, account_id, None, None, force)
+            .await?;
+    let mut operation = ScheduledOperation::new(
+        height,
+        OperationMode::Restart,
+        &StateType::Validating.to_string(),
+        now,
+    );
+    operation.migration = migration;
+    info!("Schedule {} to switch to neard {}", operation, version);
+    Schedule::new(near_home)
+        .set(&operation)
+        .context("failed to persist the scheduled restart")?;
+    Ok((version, Some(operation)))
 }
 
-// validator_pid should be only set if we are in validator state
+// neard_pid is the pid of the neard process of the current state, if there is one
 async fn handle_request(
     req: Option<Request>,
     near_rpc_port: u16,
     near_home: &Path,
     account_id: &AccountId,
-    validator_pid: Option<Pid>,
+    neard_pid: Option<Pid>,
+    state: StateType,
+    now: SystemTime,
 ) -> Option<StateType> {
     let req = match req {
         None => {
@@ -354,26 +944,39 @@ async fn handle_request(
         }
         Some(req) => req,
     };
+    let schedule = Schedule::new(near_home);
     match req {
-        ipc::Request::ScheduleRestartOperation(window_length, shutdown_at, cancel, resp_chan) => {
+        ipc::Request::ScheduleRestartOperation(
+            window_length,
+            shutdown_at,
+            cancel,
+            force,
+            resp_chan,
+        ) => {
+            // voters restart right away, unless they are asked for a window or a block height
+            let scheduled =
+                state == StateType::Validating || window_length.is_some() || shutdown_at.is_some();
             if cancel {
-                if let Some(pid) = validator_pid {
-                    let res = cancel_maintenance_shutdown(near_rpc_port, pid, near_home);
-                    if let Err(e) = resp_chan
-                        .send(ipc::ScheduleRestartOperationResponse {
-                            shutdown_at_blockheight: res.await,
-                        })
-                        .await
-                    {
-                        warn!(
-                            "Failed to respond to ipc request for canceling maintenance shutdown: {}",
-                            e
-                        );
-                    };
+                let mut res = Ok(());
+                if let Some(pid) = neard_pid {
+                    res = cancel_maintenance_shutdown(near_rpc_port, pid, near_home).await;
+                }
+                if res.is_ok() {
+                    res = schedule.clear();
                 }
-                SHUTDOWN_WITH_NEARD.store(false, Ordering::SeqCst);
+                if let Err(e) = resp_chan
+                    .send(ipc::ScheduleRestartOperationResponse {
+                        scheduled: res.map(|_| None),
+                    })
+                    .await
+                {
+                    warn!(
+                        "Failed to respond to ipc request for canceling maintenance shutdown: {}",
+                        e
+                    );
+                };
                 None
-            } else if let Some(pid) = validator_pid {
+            } else if let Some(pid) = neard_pid.filter(|_| scheduled) {
                 let res = schedule_maintenance_shutdown(
                     near_rpc_port,
                     pid,
@@ -381,21 +984,37 @@ async fn handle_request(
                     account_id,
                     window_length,
                     shutdown_at,
-                );
+                    force,
+                )
+                .await
+                .and_then(|(height, migration)| {
+                    let mut operation = ScheduledOperation::new(
+                        height,
+                        OperationMode::Shutdown,
+                        &state.to_string(),
+                        now,
+                    );
+                    operation.migration = migration;
+                    info!("Schedule {}", operation);
+                    schedule
+                        .set(&operation)
+                        .context("failed to persist the scheduled shutdown")?;
+                    Ok(Some(operation))
+                });
                 if let Err(e) = resp_chan
-                    .send(ipc::ScheduleRestartOperationResponse {
-                        shutdown_at_blockheight: res.await,
-                    })
+                    .send(ipc::ScheduleRestartOperationResponse { scheduled: res })
                     .await
                 {
-                    warn!("Failed to respond to ipc request for setting up maintenance restart on active node: {}", e);
+                    warn!(
+                        "Failed to respond to ipc request for setting up maintenance restart: {}",
+                        e
+                    );
                 };
-                SHUTDOWN_WITH_NEARD.store(true, Ordering::SeqCst);
                 None
             } else {
                 if let Err(e) = resp_chan
                     .send(ipc::ScheduleRestartOperationResponse {
-                        shutdown_at_blockheight: Ok(None),
+                        scheduled: Ok(None),
                     })
                     .await
                 {
@@ -404,31 +1023,189 @@ async fn handle_request(
                 Some(StateType::Shutdown)
             }
         }
+        ipc::Request::StageNeard(binary, force, resp_chan) => {
+            let validator_pid = neard_pid.filter(|_| state == StateType::Validating);
+            let result = stage_neard_binary(
+                near_rpc_port,
+                near_home,
+                account_id,
+                binary,
+                force,
+                validator_pid,
+                now,
+            )
+            .await;
+            let restart = matches!(result, Ok((_, None)));
+            if let Err(e) = resp_chan.send(ipc::StageNeardResponse { result }).await {
+                warn!("Failed to respond to ipc request for staging neard: {}", e);
+            };
+            // voters start the new binary right away
+            restart.then_some(StateType::Startup)
+        }
+        ipc::Request::Handover(_, resp_chan) => {
+            if let Err(e) = resp_chan
+                .send(ipc::HandoverResponse {
+                    result: Err(anyhow!("this node is not the active validator")),
+                })
+                .await
+            {
+                warn!("Failed to respond to ipc request for handover: {}", e);
+            };
+            None
+        }
+        ipc::Request::Drain(drained, resp_chan) => {
+            let result = set_drained(near_home, drained);
+            if result.is_ok() {
+                info!("{} this node", if drained { "Drain" } else { "Undrain" });
+                export_drained(drained);
+            }
+            if let Err(e) = resp_chan.send(ipc::DrainResponse { result }).await {
+                warn!("Failed to respond to ipc request for drain: {}", e);
+            };
+            None
+        }
     }
 }
 
 impl StateMachine {
-    async fn handle_startup(&mut self) -> Result<StateType> {
-        // give up after three times
-        'restart: for _ in 0..3 {
+    /// Returns a new status tracker of neard, that tolerates a neard that is still starting
+    fn neard_status(&self) -> NeardStatus {
+        let mut status = NeardStatus::new(&self.step_down_rules, &self.health_rules, &self.timings);
+        if let Some(neard) = self.neard_process.as_ref() {
+            status.starting_until = neard.started_at().add(self.timings.neard_startup_timeout);
+        }
+        status
+    }
+
+    /// Records why the voter neard stopped. Fails if neard exhausted its restart budget.
+    fn record_neard_stop(&mut self, reason: ExitReason, status: Option<&ExitStatus>) -> Result<()> {
+        let neard = match self.neard_process.take() {
+            Some(neard) => neard,
+            None => return Ok(()),
+        };
+        let record = CrashRecord::new(
+            neard.as_ref(),
+            &self.inner.to_string(),
+            reason,
+            status,
+            self.clock.now(),
+        );
+        drop(neard);
+        self.crashes.record(&record)
+    }
+
+    fn record_neard_exit(&mut self, res: Option<io::Result<ExitStatus>>) -> Result<()> {
+        match res {
+            Some(res) => self.record_neard_stop(ExitReason::from_status(&res), res.as_ref().ok()),
+            None => Ok(()),
+        }
+    }
+
+    /// Records why the voter neard exited and returns the next state, which is
+    /// Shutdown if neard stopped for a scheduled shutdown. `head` is the latest
+    /// block height neard reported.
+    fn handle_neard_exit(
+        &mut self,
+        res: Option<io::Result<ExitStatus>>,
+        head: Option<BlockHeight>,
+    ) -> Result<Transition> {
+        let state = match res {
+            Some(Ok(ref status)) if stopped_for_operation(&self.schedule, status, head) => {
+                match finish_scheduled_operation(&self.schedule, &self.settings.neard_home) {
+                    Some(OperationMode::Shutdown) => StateType::Shutdown,
+                    _ => StateType::Startup,
+                }
+            }
+            _ => StateType::Startup,
+        };
+        self.record_neard_exit(res)?;
+        Ok(Transition::new(state, TransitionReason::NeardExited))
+    }
+
+    /// Records the db version of the freshly started neard and how long it took to
+    /// migrate its database, if the version changed
+    async fn record_db_version(&mut self) {
+        let startup = match self.neard_process.as_ref() {
+            Some(neard) => neard.started_at().elapsed(),
+            None => return,
+        };
+        let version = match self.launcher.version(&self.settings).await {
+            Ok(version) => version,
+            Err(e) => {
+                warn!("Cannot get version of neard: {:#}", e);
+                return;
+            }
+        };
+        let home = &self.settings.neard_home;
+        let res = DbVersions::load(home).and_then(|mut versions| {
+            if let Some(migration) = versions.record_startup(version.db, startup, self.clock.now())
+            {
+                info!(
+                    "neard migrated its database from db version {} to {} in {}s",
+                    migration.from, migration.to, migration.duration
+                );
+            }
+            versions.save(home)
+        });
+        if let Err(e) = res {
+            warn!("Cannot record db version of neard: {:#}", e);
+        }
+    }
+
+    /// Waits until neard may be started again after it failed
+    async fn wait_for_restart(&mut self) -> Option<Transition> {
+        let restart_at = self.crashes.restart_at();
+        if restart_at > Instant::now() {
+            info!(
+                "Start neard again in {}s",
+                restart_at.duration_since(Instant::now()).as_secs()
+            );
+        }
+        loop {
+            tokio::select! {
+                _ = time::sleep_until(restart_at) => return None,
+                _ = self.exit_signal_handler.recv() => {
+                    return Some(Transition::new(StateType::Shutdown, TransitionReason::Signal))
+                }
+                req = self.request_chan.recv() => {
+                    if let Some(new_state) = handle_request(req, self.settings.near_rpc_addr.port(), &self.settings.neard_home, &self.settings.account_id, self.neard_process.as_ref().and_then(|n| n.pid()), self.inner, self.clock.now()).await {
+                        return Some(Transition::new(new_state, TransitionReason::IpcRequest));
+                    };
+                }
+            }
+        }
+    }
+
+    async fn handle_startup(&mut self) -> Result<Transition> {
+        // gives up, once neard exhausted its restart budget
+        'restart: loop {
             // stop old process if we still have one
             drop(self.neard_process.take());
 
+            if let Some(transition) = self.wait_for_restart().await {
+                return Ok(transition);
+            }
+
             // if `execve` already fails, a retry likely won't solve the issue, so just error out in this case.
-            self.neard_process = Some(setup_voter(&self.settings)?);
-            let startup_timeout = time::Instant::now().add(NEARD_STARTUP_TIMEOUT);
+            self.neard_process = Some(self.launcher.start_voter(&self.settings)?);
+            let startup_timeout = time::Instant::now().add(self.timings.neard_startup_timeout);
 
-            let mut neard_status = NeardStatus::new();
+            let mut neard_status =
+                NeardStatus::new(&self.step_down_rules, &self.health_rules, &self.timings);
 
             loop {
                 tokio::select! {
-                    _ = wait_for_neard_exit(self.neard_process.as_mut()) => {
+                    res = wait_for_neard_exit(self.neard_process.as_mut()) => {
+                        self.record_neard_exit(res)?;
                         continue 'restart;
                     }
-                    status = neard_status.query(&self.neard_client) => {
+                    status = neard_status.query(self.neard_client.as_ref()) => {
                         match status {
-                            Ok(_) => {
-                                return Ok(StateType::Syncing)
+                            Ok(status) => {
+                                self.record_db_version().await;
+                                let pid = self.neard_process.as_ref().and_then(|n| n.pid());
+                                reapply_scheduled_operation(&self.schedule, self.settings.near_rpc_addr.port(), &self.settings.neard_home, pid, status.sync_info.latest_block_height).await;
+                                return Ok(Transition::new(StateType::Syncing, TransitionReason::NeardStarted))
                             },
                             Err(e) => {
                                 warn!("Failed to request neard status: {}", e)
@@ -438,169 +1215,274 @@ impl StateMachine {
                     // startup timeout
                     _ = time::sleep_until(startup_timeout) => {
                         warn!("Neard: Timeout on startup");
+                        self.record_neard_stop(ExitReason::StartupTimeout, None)?;
                         continue 'restart;
                     },
                     _ = self.exit_signal_handler.recv() => {
-                        return Ok(StateType::Shutdown)
+                        return Ok(Transition::new(StateType::Shutdown, TransitionReason::Signal))
                     }
                     _ = self.reload_signal.recv() => {
-                        reload_configuration(&mut self.settings, &self.consul_client)?
+                        reload_configuration(&mut self.settings, self.election.as_ref())?
                     }
                     req = self.request_chan.recv() => {
-                        if let Some(new_state) = handle_request(req, self.settings.near_rpc_addr.port(), &self.settings.neard_home, &self.settings.account_id, None).await {
-                            return Ok(new_state);
+                        if let Some(new_state) = handle_request(req, self.settings.near_rpc_addr.port(), &self.settings.neard_home, &self.settings.account_id, self.neard_process.as_ref().and_then(|n| n.pid()), self.inner, self.clock.now()).await {
+                            return Ok(Transition::new(new_state, TransitionReason::IpcRequest));
                         };
                     }
                 }
             }
         }
-        bail!("Could not start neard")
     }
 
-    async fn handle_syncing(&mut self) -> Result<StateType> {
+    async fn handle_syncing(&mut self) -> Result<Transition> {
         let mut continuous_errors = 0;
-        let mut neard_status = NeardStatus::new();
+        let mut neard_status =
+            NeardStatus::new(&self.step_down_rules, &self.health_rules, &self.timings);
         loop {
             tokio::select! {
-                _ = wait_for_neard_exit(self.neard_process.as_mut()) => {
-                    return Ok(StateType::Startup);
+                res = wait_for_neard_exit(self.neard_process.as_mut()) => {
+                    return self.handle_neard_exit(res, neard_status.head);
                 }
                 _ = self.exit_signal_handler.recv() => {
-                    return Ok(StateType::Shutdown)
+                    return Ok(Transition::new(StateType::Shutdown, TransitionReason::Signal))
                 }
                 _ = self.reload_signal.recv() => {
-                    reload_configuration(&mut self.settings, &self.consul_client)?
+                    reload_configuration(&mut self.settings, self.election.as_ref())?
                 }
-                status = neard_status.query(&self.neard_client)=> {
+                status = neard_status.query(self.neard_client.as_ref())=> {
                     match status {
                         Ok(status) => {
                             continuous_errors = 0;
                             if !status.sync_info.syncing {
                                 // node is synced fully with the network
-                                return Ok(StateType::Registering);
+                                return Ok(Transition::new(StateType::Registering, TransitionReason::Synced));
                             }
                         }
                         Err(err) => {
                             warn!("Cannot reach neard status api: {}", err);
                             continuous_errors += 1;
-                            if continuous_errors == 3 {
-                                return Ok(StateType::Startup);
+                            if continuous_errors == self.timings.max_status_errors {
+                                return Ok(Transition::new(StateType::Startup, TransitionReason::NeardUnreachable));
                             }
                         }
                     }
                 }
                 req = self.request_chan.recv() => {
-                    if let Some(new_state) = handle_request(req, self.settings.near_rpc_addr.port(), &self.settings.neard_home, &self.settings.account_id, None).await {
-                        return Ok(new_state);
+                    if let Some(new_state) = handle_request(req, self.settings.near_rpc_addr.port(), &self.settings.neard_home, &self.settings.account_id, self.neard_process.as_ref().and_then(|n| n.pid()), self.inner, self.clock.now()).await {
+                        return Ok(Transition::new(new_state, TransitionReason::IpcRequest));
                     };
                 }
             }
         }
     }
 
-    async fn handle_registering(&mut self) -> Result<StateType> {
+    async fn handle_registering(&mut self) -> Result<Transition> {
         let mut create_session = CreateSession::new();
-        let mut neard_status = NeardStatus::new();
+        let mut neard_status = self.neard_status();
         loop {
             tokio::select! {
-                _ = wait_for_neard_exit(self.neard_process.as_mut()) => {
-                    return Ok(StateType::Startup)
+                res = wait_for_neard_exit(self.neard_process.as_mut()) => {
+                    return self.handle_neard_exit(res, neard_status.head)
                 },
                 _ = self.exit_signal_handler.recv() => {
-                    return Ok(StateType::Shutdown)
+                    return Ok(Transition::new(StateType::Shutdown, TransitionReason::Signal))
                 }
                 _ = self.reload_signal.recv() => {
-                    reload_configuration(&mut self.settings, &self.consul_client)?
+                    reload_configuration(&mut self.settings, self.election.as_ref())?
                 }
-                res = neard_status.handle_neard_desyncs(&self.neard_client) => {
+                res = neard_status.handle_neard_desyncs(self.neard_client.as_ref(), self.clock.as_ref()) => {
                     return Ok(res)
                 }
                 // When we cancel this task, we might leak a consul session,
                 // since it will however expire after 30s, this is fine.
-                res = create_session.run(&self.consul_client, &self.settings.node_id) => {
-                    self.consul_session = res;
-                    if self.consul_session.is_some() {
-                        return Ok(StateType::Voting)
+                res = create_session.run(self.election.as_ref(), &self.settings.node_id, self.timings.session_ttl) => {
+                    self.session = res;
+                    if self.session.is_some() {
+                        return Ok(Transition::new(StateType::Voting, TransitionReason::SessionCreated))
                     }
                 }
                 req = self.request_chan.recv() => {
-                    if let Some(new_state) = handle_request(req, self.settings.near_rpc_addr.port(), &self.settings.neard_home, &self.settings.account_id, None).await {
-                        return Ok(new_state);
+                    if let Some(new_state) = handle_request(req, self.settings.near_rpc_addr.port(), &self.settings.neard_home, &self.settings.account_id, self.neard_process.as_ref().and_then(|n| n.pid()), self.inner, self.clock.now()).await {
+                        return Ok(Transition::new(new_state, TransitionReason::IpcRequest));
                     };
                 }
             }
         }
     }
 
-    async fn handle_voting(&mut self) -> Result<StateType> {
+    async fn handle_voting(&mut self) -> Result<Transition> {
         // this session needs to be manually moved or destroyed!
-        let session = match self.consul_session.take() {
-            Some(s) => ScopedConsulSession::new(&self.consul_client, s),
+        let session = match self.session.take() {
+            Some(s) => ScopedSession::new(self.election.as_ref(), s),
             None => {
                 warn!("Got into validating state without consul session!");
-                return Ok(StateType::Registering);
+                return Ok(Transition::new(
+                    StateType::Registering,
+                    TransitionReason::SessionLost,
+                ));
             }
         };
 
-        let mut next_renewal = time::Instant::now().add(CONSUL_SESSION_RENEWAL);
+        let mut next_renewal = time::Instant::now().add(self.timings.session_renewal);
+        // do not take over again right after we stepped down
+        if let Some(until) = self
+            .step_down_until
+            .filter(|until| *until > time::Instant::now())
+        {
+            info!(
+                "Do not compete for leadership for {}s after stepping down",
+                until.duration_since(time::Instant::now()).as_secs()
+            );
+        }
         let mut next_acquire = time::Instant::now();
-        let mut neard_status = NeardStatus::new();
+        let mut neard_status = self.neard_status();
+        let mut candidacy = Candidacy::new(self.timings.candidate_grace);
+        let mut next_memory_check = time::Instant::now().add(self.timings.memory_check_frequency);
+        let mut memory_issue = None;
 
         loop {
+            // do not compete for leadership while we would step down right away again, neard is unhealthy, short of memory or we are drained
+            let cooling_down = self
+                .step_down_until
+                .is_some_and(|until| until > time::Instant::now());
+            let may_acquire = !cooling_down
+                && neard_status.is_usable()
+                && memory_issue.is_none()
+                && !self.double_sign.holds_off()
+                && !is_drained(&self.settings.neard_home);
+            if candidacy.registered && !may_acquire {
+                // nobody should hand over to us either
+                candidacy
+                    .withdraw(self.election.as_ref(), &self.keys, session.borrow())
+                    .await;
+            }
+            // our handover reserves the leadership for its target until it expires
+            let handover_expires = self.handover_session.as_ref().map(|(_, expires)| *expires);
             tokio::select! {
-                _ = wait_for_neard_exit(self.neard_process.as_mut()) => { return Ok(StateType::Startup) },
+                res = wait_for_neard_exit(self.neard_process.as_mut()) => {
+                    return self.handle_neard_exit(res, neard_status.head)
+                },
                 // renew sessions every 10s
                 _ = self.exit_signal_handler.recv() => {
                     session.destroy().await;
-                    return Ok(StateType::Shutdown)
+                    return Ok(Transition::new(StateType::Shutdown, TransitionReason::Signal))
                 }
                 _ = self.reload_signal.recv() => {
-                    reload_configuration(&mut self.settings, &self.consul_client)?
+                    reload_configuration(&mut self.settings, self.election.as_ref())?
+                }
+                _ = time::sleep_until(handover_expires.unwrap_or_else(time::Instant::now)), if handover_expires.is_some() => {
+                    withdraw_handover(self.election.as_ref(), &mut self.handover_session).await;
                 }
-                res = time::sleep_until(next_acquire).then(|()| acquire_key(&self.consul_client, &self.leader_key, &self.leader_metadata, session.borrow())) => {
+                res = time::sleep_until(next_acquire).then(|()| acquire_key(self.election.as_ref(), &self.keys, &self.leader_metadata, session.borrow(), &mut candidacy, self.clock.as_ref())), if may_acquire => {
                     if let ChorumResult::IsMaster = res {
+                        // the target of our handover did not take over
+                        withdraw_handover(self.election.as_ref(), &mut self.handover_session).await;
                         // move back the session so that we can use in the validating state
-                        self.consul_session = Some(session.into());
-                        return Ok(StateType::Validating)
+                        self.session = Some(session.into());
+                        return Ok(Transition::new(StateType::Validating, TransitionReason::LeaderAcquired))
                     }
-                    next_acquire = time::Instant::now().add(CONSUL_ACQUIRE_LEADER_FREQUENCY);
+                    next_acquire = time::Instant::now().add(self.timings.acquire_leader_frequency);
                 }
-                res = neard_status.handle_neard_desyncs(&self.neard_client) => {
-                    session.destroy().await;
-                    return Ok(res)
+                res = neard_status.handle_neard_desyncs_and_health(self.neard_client.as_ref(), self.clock.as_ref()) => {
+                    if let Some(res) = res {
+                        session.destroy().await;
+                        return Ok(res)
+                    }
+                }
+                _ = time::sleep_until(next_memory_check) => {
+                    next_memory_check = time::Instant::now().add(self.timings.memory_check_frequency);
+                    memory_issue = self.memory_rules.check(self.neard_process.as_ref().and_then(|n| n.pid()));
+                    if let Some(issue) = memory_issue {
+                        warn!("Do not compete for leadership: {}", issue);
+                        // a voter can restart right away, but restarting neard does not relieve the memory pressure of the host
+                        if self.memory_rules.action == MemoryAction::Restart && matches!(issue, MemoryIssue::Rss(_)) {
+                            session.destroy().await;
+                            // delays the next start, if neard keeps exceeding its limit
+                            self.record_neard_stop(ExitReason::MemoryLimit, None)?;
+                            return Ok(Transition::new(StateType::Startup, TransitionReason::MemoryPressure))
+                        }
+                    }
                 }
-                res = time::sleep_until(next_renewal).then(|()| self.consul_client.renew_session(session.borrow())) => {
+                res = time::sleep_until(next_renewal).then(|()| self.election.renew_session(session.borrow())) => {
 
                     if let Err(err) = res {
-                        if let Some(&ConsulError::SessionNotFound) = err.downcast_ref::<ConsulError>() {
+                        if let Some(&ElectionError::SessionNotFound) = err.downcast_ref::<ElectionError>() {
                             session.destroy().await;
-                            return Ok(StateType::Registering)
+                            return Ok(Transition::new(StateType::Registering, TransitionReason::SessionLost))
                         }
-                        warn!("failed to renew consul session: {}", err);
-                        next_renewal = time::Instant::now().add(CONSUL_SESSION_RENEWAL_ERROR);
+                        warn!("failed to renew session: {}", err);
+                        next_renewal = time::Instant::now().add(self.timings.session_renewal_retry);
                     } else {
-                        next_renewal = time::Instant::now().add(CONSUL_SESSION_RENEWAL);
+                        next_renewal = time::Instant::now().add(self.timings.session_renewal);
                     };
                 }
                 req = self.request_chan.recv() => {
-                    if let Some(new_state) = handle_request(req, self.settings.near_rpc_addr.port(), &self.settings.neard_home, &self.settings.account_id, None).await {
-                        return Ok(new_state);
+                    if let Some(new_state) = handle_request(req, self.settings.near_rpc_addr.port(), &self.settings.neard_home, &self.settings.account_id, self.neard_process.as_ref().and_then(|n| n.pid()), self.inner, self.clock.now()).await {
+                        return Ok(Transition::new(new_state, TransitionReason::IpcRequest));
                     };
                 }
             }
         }
     }
 
-    async fn handle_validating(&mut self) -> Result<StateType> {
+    async fn handle_validating(&mut self) -> Result<Transition> {
         // this session needs to be manually moved or destroyed!
-        let session = match self.consul_session.take() {
-            Some(s) => ScopedConsulSession::new(&self.consul_client, s),
+        let session = match self.session.take() {
+            Some(s) => ScopedSession::new(self.election.as_ref(), s),
             None => {
                 warn!("Got into validating state without consul session!");
-                return Ok(StateType::Registering);
+                return Ok(Transition::new(
+                    StateType::Registering,
+                    TransitionReason::SessionLost,
+                ));
             }
         };
 
+        // Our session might have expired since we acquired the lock, i.e. after a long
+        // pause of the process or a clock jump. Make sure we still hold it before we
+        // give neard the validator key.
+        let token = match self.election.leader(&self.keys.leader).await {
+            Ok(leader) => fencing_token(leader.as_ref(), session.borrow(), None),
+            Err(e) => Err(e).context("failed to read leader key"),
+        };
+        let token = match token {
+            Ok(token) => token,
+            Err(e) => {
+                warn!(
+                    "Refuse to start validator, we may be a stale leader: {:#}",
+                    e
+                );
+                self.session = Some(session.into());
+                return Ok(Transition::new(
+                    StateType::Voting,
+                    TransitionReason::StaleLeader,
+                ));
+            }
+        };
+        info!("Holding leader lock with fencing token {}", token);
+
+        // The leader lock does not help against a neard that was started with our key by hand
+        match self
+            .double_sign
+            .check_network_now(self.neard_client.as_ref())
+            .await
+        {
+            Ok(Some(evidence)) => {
+                warn!(
+                    "Refuse to start validator, another node validates with our key: {}",
+                    evidence
+                );
+                session.destroy().await;
+                return Ok(Transition::new(
+                    StateType::Registering,
+                    TransitionReason::DoubleSign,
+                ));
+            }
+            Ok(None) => {}
+            Err(e) => warn!("Cannot check network for other validators: {:#}", e),
+        }
+        self.double_sign.reset();
+
         // Stop neard that is not a validator.
         if let Some(p) = self.neard_process.take() {
             if let Err(e) = p.graceful_stop().context("Failed to stop validator") {
@@ -608,57 +1490,100 @@ impl StateMachine {
                 return Err(e);
             }
         };
-        let mut validator =
-            match setup_validator(&self.settings).context("Failed to start validator") {
-                Ok(v) => v,
-                Err(e) => {
-                    session.destroy().await;
-                    return Err(e);
-                }
-            };
+        let mut validator = match self
+            .launcher
+            .start_validator(&self.settings)
+            .context("Failed to start validator")
+        {
+            Ok(v) => v,
+            Err(e) => {
+                session.destroy().await;
+                return Err(e);
+            }
+        };
         let pid = validator.pid();
 
         let mut on_startup = true;
         let mut continuous_errors = 0;
-        let mut next_renewal = time::Instant::now().add(CONSUL_SESSION_RENEWAL);
-        let mut session_expired = time::Instant::now().add(CONSUL_LEADER_TIMEOUT);
-        let mut neard_status = NeardStatus::new();
+        let mut next_renewal = time::Instant::now().add(self.timings.session_renewal);
+        let mut session_expired = time::Instant::now().add(self.timings.leader_timeout);
+        let mut neard_status =
+            NeardStatus::new(&self.step_down_rules, &self.health_rules, &self.timings);
+        let mut next_failback_check =
+            time::Instant::now().add(self.timings.failback_check_frequency);
+        let mut block_height = None;
+        let mut requested_handover = None;
+        let mut next_fencing_check = time::Instant::now().add(self.timings.fencing_check_frequency);
+        let mut next_double_sign_check =
+            time::Instant::now().add(self.timings.double_sign_check_frequency);
+        let mut next_validator_stats =
+            time::Instant::now().add(self.timings.validator_stats_frequency);
+        let mut kickout_risk = false;
+        let mut disk_critical = false;
+        let mut next_memory_check = time::Instant::now().add(self.timings.memory_check_frequency);
 
         loop {
             tokio::select! {
-                res = validator.process().wait() => {
+                res = validator.wait() => {
                     let state = match res {
-                        Ok(_) if SHUTDOWN_WITH_NEARD.load(Ordering::SeqCst) => { // maintenance shutdown
-                            StateType::Shutdown
-                        },
-                        Ok(res) => { // maintenance restart
-                            info!("Neard shutdown with {}", res);
-                            StateType::Startup
+                        Ok(ref res) if stopped_for_operation(&self.schedule, res, block_height) => match finish_scheduled_operation(&self.schedule, &self.settings.neard_home) {
+                            Some(OperationMode::Shutdown) => StateType::Shutdown, // maintenance shutdown
+                            _ => { // maintenance restart
+                                info!("Neard shutdown with {}", res);
+                                StateType::Startup
+                            }
                         },
-                        Err(err) => {
+                        // crashed, the restarted neard gets the scheduled operation again
+                        Ok(_) => StateType::Startup,
+                        Err(ref err) => {
                             warn!("Cannot get status of neard process {}", err);
                             StateType::Startup
                         }
                     };
+                    let record = CrashRecord::new(validator.as_ref(), &self.inner.to_string(), ExitReason::from_status(&res), res.as_ref().ok(), self.clock.now());
                     drop(validator);
                     session.destroy().await;
-                    return Ok(state)
+                    self.crashes.record(&record)?;
+                    return Ok(Transition::new(state, TransitionReason::NeardExited))
                 }
                 _ = self.exit_signal_handler.recv() => {
                     drop(validator);
                     session.destroy().await;
-                    return Ok(StateType::Shutdown)
+                    return Ok(Transition::new(StateType::Shutdown, TransitionReason::Signal))
                 }
                 _ = self.reload_signal.recv() => {
-                    reload_configuration(&mut self.settings, &self.consul_client)?
+                    reload_configuration(&mut self.settings, self.election.as_ref())?
                 }
-                res = neard_status.query(&self.neard_client) => {
+                res = neard_status.query(self.neard_client.as_ref()) => {
                     match res {
                         Ok(status) => {
                             continuous_errors = 0;
+                            if on_startup {
+                                reapply_scheduled_operation(&self.schedule, self.settings.near_rpc_addr.port(), &self.settings.neard_home, pid, status.sync_info.latest_block_height).await;
+                            }
                             on_startup = false;
+                            block_height = Some(status.sync_info.latest_block_height);
+                            if let Some(reason) = neard_status.step_down.check(&status, self.clock.now()) {
+                                // Stop the validator before releasing the lock, so that a healthy
+                                // standby can take over without both of us validating.
+                                warn!("Step down from validating: {}", reason);
+                                drop(validator);
+                                session.destroy().await;
+                                self.step_down_until = Some(time::Instant::now().add(self.step_down_rules.cooldown));
+                                self.neard_process = Some(self.launcher.start_voter(&self.settings).context("Failed to start voter")?);
+                                // a new session without the leader lock, so that we keep voting
+                                return Ok(match self.election.create_session(&self.settings.node_id, self.timings.session_ttl).await {
+                                    Ok(s) => {
+                                        self.session = Some(s);
+                                        Transition::new(StateType::Voting, TransitionReason::StepDown)
+                                    }
+                                    Err(e) => {
+                                        warn!("Cannot create session after stepping down: {:#}", e);
+                                        Transition::new(StateType::Registering, TransitionReason::StepDown)
+                                    }
+                                })
+                            }
                             if status.sync_info.syncing {
-                                // FIXME, we might want to add a threshold after which we step down here.
                                 warn!("node is syncing!")
                             }
                         }
@@ -666,41 +1591,172 @@ impl StateMachine {
                             continuous_errors += 1;
                             warn!("Cannot reach neard status api: {}", err);
                             if on_startup {
-                                // On startup we give neard ~120s to make it's status api reachable.
+                                // On startup we give neard more time (~120s by default) to make its status api reachable.
                                 // This is needed on testnet where the startup can take a long time.
-                                if continuous_errors == 120 {
-                                    return Ok(StateType::Startup)
+                                if continuous_errors == self.timings.validator_startup_max_status_errors {
+                                    return Ok(Transition::new(StateType::Startup, TransitionReason::NeardUnreachable))
                                 }
-                            } else if continuous_errors == 3 {
-                                return Ok(StateType::Startup)
+                            } else if continuous_errors == self.timings.max_status_errors {
+                                return Ok(Transition::new(StateType::Startup, TransitionReason::NeardUnreachable))
                             }
                         }
                     }
                 }
-                res = time::sleep_until(next_renewal).then(|()| self.consul_client.renew_session(session.borrow())) => {
+                res = time::sleep_until(next_renewal).then(|()| self.election.renew_session(session.borrow())) => {
 
                     if let Err(err) = res {
-                        if let Some(&ConsulError::SessionNotFound) = err.downcast_ref::<ConsulError>() {
+                        if let Some(&ElectionError::SessionNotFound) = err.downcast_ref::<ElectionError>() {
                             // no need to unregister an expired session
-                            let _s : ConsulSession = session.into();
-                            return Ok(StateType::Registering)
+                            let _s : ElectionSession = session.into();
+                            return Ok(Transition::new(StateType::Registering, TransitionReason::SessionLost))
                         }
-                        warn!("failed to renew consul session: {}", err);
-                        next_renewal = time::Instant::now().add(CONSUL_SESSION_RENEWAL_ERROR);
+                        warn!("failed to renew session: {}", err);
+                        next_renewal = time::Instant::now().add(self.timings.session_renewal_retry);
                     } else {
-                        next_renewal = time::Instant::now().add(CONSUL_SESSION_RENEWAL);
-                        session_expired = time::Instant::now().add(CONSUL_LEADER_TIMEOUT);
+                        next_renewal = time::Instant::now().add(self.timings.session_renewal);
+                        session_expired = time::Instant::now().add(self.timings.leader_timeout);
                     };
                 }
+                res = time::sleep_until(next_fencing_check).then(|()| self.election.leader(&self.keys.leader)) => {
+                    next_fencing_check = time::Instant::now().add(self.timings.fencing_check_frequency);
+                    match res {
+                        Ok(leader) => {
+                            if let Err(e) = fencing_token(leader.as_ref(), session.borrow(), Some(token)) {
+                                warn!("Stop validating, we are a stale leader: {:#}", e);
+                                drop(validator);
+                                session.destroy().await;
+                                return Ok(Transition::new(StateType::Startup, TransitionReason::StaleLeader))
+                            }
+                        }
+                        // losing the connection is handled by the session renewal
+                        Err(e) => warn!("failed to read leader key: {}", e),
+                    }
+                }
+                res = time::sleep_until(next_double_sign_check).then(|()| self.double_sign.check(self.neard_client.as_ref())) => {
+                    next_double_sign_check = time::Instant::now().add(self.timings.double_sign_check_frequency);
+                    match res {
+                        Ok(Some(evidence)) => {
+                            warn!("Stop validating, another node validates with our key: {}", evidence);
+                            drop(validator);
+                            session.destroy().await;
+                            return Ok(Transition::new(StateType::Startup, TransitionReason::DoubleSign))
+                        }
+                        Ok(None) => {}
+                        Err(e) => warn!("Cannot check for other validators: {:#}", e),
+                    }
+                }
+                res = time::sleep_until(next_validator_stats).then(|()| ValidatorStats::query(self.neard_client.as_ref(), &self.settings.account_id)) => {
+                    next_validator_stats = time::Instant::now().add(self.timings.validator_stats_frequency);
+                    match res {
+                        Ok(stats) => {
+                            ValidatorStats::export(stats.as_ref());
+                            match stats.filter(|s| s.kickout_risk) {
+                                Some(s) => {
+                                    if !kickout_risk {
+                                        warn!("Validator at risk of being kicked out: produced {}/{} blocks and {}/{} chunks", s.produced_blocks, s.expected_blocks, s.produced_chunks, s.expected_chunks);
+                                    }
+                                    kickout_risk = true;
+                                }
+                                None => kickout_risk = false,
+                            }
+                        }
+                        Err(e) => warn!("Cannot get validator stats: {:#}", e),
+                    }
+                }
+                _ = time::sleep_until(next_memory_check) => {
+                    next_memory_check = time::Instant::now().add(self.timings.memory_check_frequency);
+                    if let Some(issue) = self.memory_rules.check(pid) {
+                        match (self.memory_rules.action, pid) {
+                            (MemoryAction::StepDown, _) => {
+                                // better a short gap until a standby takes over than an OOM kill mid-epoch
+                                warn!("Step down from validating: {}", issue);
+                                self.step_down_until = Some(time::Instant::now().add(self.step_down_rules.cooldown));
+                                drop(validator);
+                                session.destroy().await;
+                                return Ok(Transition::new(StateType::Startup, TransitionReason::MemoryPressure))
+                            }
+                            (MemoryAction::Restart, Some(pid)) => {
+                                match schedule_memory_restart(&self.schedule, self.settings.near_rpc_addr.port(), pid, &self.settings.neard_home, &self.settings.account_id, self.clock.now()).await {
+                                    Ok(Some(operation)) => warn!("{}, schedule {}", issue, operation),
+                                    Ok(None) => {}
+                                    Err(e) => warn!("{}, but cannot schedule a restart: {:#}", issue, e),
+                                }
+                            }
+                            (MemoryAction::Restart, None) => warn!("{}", issue),
+                        }
+                    }
+                }
+                res = time::sleep_until(next_failback_check).then(|()| handover_candidate(self.election.as_ref(), self.neard_client.as_ref(), &self.keys, &self.leader_metadata, &self.settings.account_id, block_height, &mut requested_handover)) => {
+                    next_failback_check = time::Instant::now().add(self.timings.failback_check_frequency);
+                    if let Some(node) = res {
+                        match announce_handover(self.election.as_ref(), &self.keys, &self.settings.node_id, &node, self.timings.handover_timeout, self.clock.now()).await {
+                            Ok(s) => {
+                                info!("Hand over leadership to {}", node);
+                                self.handover_session = Some((s, time::Instant::now().add(self.timings.handover_timeout)));
+                                drop(validator);
+                                session.destroy().await;
+                                return Ok(Transition::new(StateType::Startup, TransitionReason::Handover))
+                            }
+                            Err(e) => warn!("Cannot hand over leadership to {}: {}", node, e),
+                        }
+                    } else {
+                        let give_up = if is_drained(&self.settings.neard_home) {
+                            Some((TransitionReason::Drain, "this node is drained"))
+                        } else if let Some(usage) = critical_disk_usage(self.health_rules.disk.as_ref()) {
+                            if !disk_critical {
+                                warn!("Disk of neard is almost full: {}", usage);
+                            }
+                            disk_critical = true;
+                            Some((TransitionReason::DiskFull, "the disk of neard is almost full"))
+                        } else {
+                            if disk_critical {
+                                info!("Disk usage of neard is below the critical threshold again");
+                                disk_critical = false;
+                            }
+                            None
+                        };
+                        if let (Some((reason, why)), Some(height)) = (give_up, block_height) {
+                            if in_maintenance_window(self.neard_client.as_ref(), &self.settings.account_id, height).await {
+                                // only give up leadership if a synced and healthy node takes over
+                                match takeover_candidate(self.election.as_ref(), &self.keys, &self.settings.node_id).await {
+                                    Some(node) => match announce_handover(self.election.as_ref(), &self.keys, &self.settings.node_id, &node, self.timings.handover_timeout, self.clock.now()).await {
+                                        Ok(s) => {
+                                            info!("Hand over leadership to {}, {}", node, why);
+                                            self.handover_session = Some((s, time::Instant::now().add(self.timings.handover_timeout)));
+                                            drop(validator);
+                                            session.destroy().await;
+                                            return Ok(Transition::new(StateType::Startup, reason))
+                                        }
+                                        Err(e) => warn!("Cannot hand over leadership to {}: {}", node, e),
+                                    },
+                                    None => warn!("Keep leadership although {}, no candidate can take over", why),
+                                }
+                            }
+                        }
+                    }
+                }
                 _ = time::sleep_until(session_expired) => {
-                    warn!("Lost connection to consul, step back");
+                    warn!("Lost connection to election backend, step back");
                     // try to re-use our current session for voting
-                    self.consul_session = Some(session.into());
-                    return Ok(StateType::Voting)
+                    self.session = Some(session.into());
+                    return Ok(Transition::new(StateType::Voting, TransitionReason::SessionLost))
                 }
                 req = self.request_chan.recv() => {
-                    if let Some(new_state) = handle_request(req, self.settings.near_rpc_addr.port(), &self.settings.neard_home, &self.settings.account_id, pid).await {
-                        return Ok(new_state);
+                    if let Some(ipc::Request::Handover(target, resp_chan)) = req {
+                        let result = match is_candidate(self.election.as_ref(), &self.keys, &target).await {
+                            Ok(true) => {
+                                info!("Hand over leadership to {} in the next maintenance window", target);
+                                requested_handover = Some(target);
+                                Ok(())
+                            }
+                            Ok(false) => Err(anyhow!("{} is not a synced and voting node", target)),
+                            Err(e) => Err(e),
+                        };
+                        if let Err(e) = resp_chan.send(ipc::HandoverResponse { result }).await {
+                            warn!("Failed to respond to ipc request for handover: {}", e);
+                        };
+                    } else if let Some(new_state) = handle_request(req, self.settings.near_rpc_addr.port(), &self.settings.neard_home, &self.settings.account_id, pid, self.inner, self.clock.now()).await {
+                        return Ok(Transition::new(new_state, TransitionReason::IpcRequest));
                     };
                 }
             }
@@ -708,7 +1764,8 @@ impl StateMachine {
     }
 
     async fn next(&mut self) -> Result<StateType> {
-        let new_state = match &self.inner {
+        let session_id = self.session.as_ref().map(|s| s.id().to_string());
+        let transition = match &self.inner {
             StateType::Startup => self
                 .handle_startup()
                 .await
@@ -730,29 +1787,69 @@ impl StateMachine {
                 bail!("Programming Error: next() should be not called if we are about to shutdown");
             }
         }?;
+        let new_state = transition.state;
         if new_state != self.inner {
+            if self.inner == StateType::Validating {
+                // only the active validator exports its production
+                ValidatorStats::export(None);
+            }
             // FIXME: This is not atomic!
             STATE.with_label_values(&[&self.inner.to_string()]).set(0);
             STATE.with_label_values(&[&new_state.to_string()]).set(1);
-            info!("state changed: {:?} -> {:?}", self.inner, new_state)
+            info!(
+                "state changed: {:?} -> {:?} ({})",
+                self.inner, new_state, transition.reason
+            );
+            let hooks = self.settings.hooks.run(&HookPayload {
+                node_id: self.settings.node_id.clone(),
+                account_id: self.settings.account_id.to_string(),
+                old_state: self.inner.to_string(),
+                new_state: new_state.to_string(),
+                reason: transition.reason,
+            });
+            // hooks do not survive our exit, they are bounded by their timeout
+            if new_state == StateType::Shutdown {
+                for hook in hooks {
+                    let _ = hook.await;
+                }
+            }
+            let block_height = LATEST_BLOCK_HEIGHT.load(Ordering::SeqCst);
+            let entry = TransitionEntry {
+                timestamp: DateTime::<Utc>::from(self.clock.now()),
+                from: self.inner.to_string(),
+                to: new_state.to_string(),
+                reason: transition.reason,
+                block_height: (block_height > 0).then_some(block_height),
+                session_id: self
+                    .session
+                    .as_ref()
+                    .map(|s| s.id().to_string())
+                    .or(session_id),
+            };
+            if let Err(e) = self.journal.append(&entry) {
+                warn!("Failed to record state transition: {:#}", e);
+            }
         }
         self.inner = new_state;
         Ok(self.inner)
     }
 }
 
-/// Runs neard and participate in consul leader election
+/// Runs neard and participate in leader election
 pub async fn run_supervisor(
     settings: &Arc<Settings>,
     request_chan: Receiver<ipc::Request>,
 ) -> Result<()> {
     initialize_state_gauge();
+    export_drained(is_drained(&settings.neard_home));
 
     oom_score::adjust_oom_score(oom_score::KUUTAMOD_OOM_SCORE)
         .context("cannot adjust oom score")?;
+    NeardCgroup::setup(&CgroupLimits::from_settings(settings));
 
-    let mut state =
-        StateMachine::new(settings, request_chan).context("Failed to initialize state machine")?;
+    let env = Environment::from_settings(settings)?;
+    let mut state = StateMachine::new(settings, request_chan, env)
+        .context("Failed to initialize state machine")?;
 
     while state.next().await? != StateType::Shutdown {}
     Ok(())
@@ -0,0 +1,917 @@
+//! Deterministic simulation of the supervisor state machine.
+//!
+//! neard, its api and the election backend are replaced by fakes that the tests
+//! script. All tests run with the paused time of tokio, so scenarios that span
+//! minutes finish in milliseconds and always take the same path.
+
+use super::{Environment, StateMachine, StateType};
+use crate::clock::{Clock, TokioClock};
+use crate::crash_report::{crash_journal, ExitReason};
+use crate::ipc;
+use crate::journal::{Journal, TransitionReason};
+use crate::leader_protocol::{
+    candidates_prefix, handover_prefix, ElectionSession, Leader, LeaderElection,
+};
+use crate::local_election::MemoryElection;
+use crate::migration::DbVersions;
+use crate::near_client::{
+    MaintenanceWindowRPCResult, NeardApi, NetworkInfo, NetworkInfoPeer, ProtocolConfig,
+};
+use crate::neard_binary::NeardVersion;
+use crate::neard_process::{Neard, NeardLauncher};
+use crate::schedule::{OperationMode, Schedule, ScheduledOperation};
+use crate::settings::{default_settings, Settings};
+use anyhow::{bail, Result};
+use chrono::{DateTime, Utc};
+use futures_util::future::BoxFuture;
+use futures_util::FutureExt;
+use near_primitives::types::AccountId;
+use near_primitives::views::{EpochValidatorInfo, StatusResponse};
+use nix::unistd::Pid;
+use serde_json::json;
+use std::collections::HashMap;
+use std::io;
+use std::os::unix::process::ExitStatusExt;
+use std::process::ExitStatus;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::{mpsc, Notify};
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, Duration, Instant};
+
+/// How long the status api of a freshly started neard is not reachable
+const NEARD_STARTUP: Duration = Duration::from_secs(5);
+/// Node key a voter neard is started with
+const VOTER_NODE_KEY: &str = "ed25519:DcA2MzgpJbrUATQLLceocVckhhAqrkingax4oJ9kZ847";
+/// Node key of the validator in the settings of every simulated node
+const VALIDATOR_NODE_KEY: &str = "ed25519:6E8sCci9badyRkXb3JoRpBj5p8C6Tw41ELDZoiihKEtp";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Role {
+    Voter,
+    Validator,
+}
+
+#[derive(Debug)]
+struct NeardState {
+    /// Role of the running process, if there is one
+    running: Option<Role>,
+    started_at: Instant,
+    /// Wakes up the supervisor waiting for the running process to exit
+    exit: Arc<Notify>,
+    /// Exit code of the process once it exited
+    exit_code: i32,
+    syncing: bool,
+    /// Account neard validates for, as reported in its status
+    validator_account_id: Option<AccountId>,
+    /// Node key neard uses, as reported in its status
+    node_key: String,
+    /// Number of peers neard is connected to
+    peers: usize,
+    /// Whether neard is in a maintenance window of our account
+    in_maintenance_window: bool,
+    /// Db version of the neard binary
+    db_version: u32,
+    starts: u32,
+    validator_starts: u32,
+}
+
+/// A simulated neard, that implements both the process and its api
+#[derive(Debug, Clone)]
+struct SimulatedNeard {
+    state: Arc<Mutex<NeardState>>,
+    clock: TokioClock,
+}
+
+impl SimulatedNeard {
+    fn new(clock: TokioClock) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(NeardState {
+                running: None,
+                started_at: Instant::now(),
+                exit: Arc::new(Notify::new()),
+                exit_code: 0,
+                syncing: false,
+                validator_account_id: None,
+                node_key: VOTER_NODE_KEY.to_string(),
+                peers: 3,
+                in_maintenance_window: false,
+                db_version: 34,
+                starts: 0,
+                validator_starts: 0,
+            })),
+            clock,
+        }
+    }
+
+    fn with_state<T>(&self, f: impl FnOnce(&mut NeardState) -> T) -> T {
+        f(&mut self.state.lock().unwrap())
+    }
+
+    fn set_syncing(&self, syncing: bool) {
+        self.with_state(|s| s.syncing = syncing)
+    }
+
+    fn set_peers(&self, peers: usize) {
+        self.with_state(|s| s.peers = peers)
+    }
+
+    fn set_in_maintenance_window(&self, in_window: bool) {
+        self.with_state(|s| s.in_maintenance_window = in_window)
+    }
+
+    fn role(&self) -> Option<Role> {
+        self.with_state(|s| s.running)
+    }
+
+    fn set_db_version(&self, db_version: u32) {
+        self.with_state(|s| s.db_version = db_version)
+    }
+
+    fn starts(&self) -> u32 {
+        self.with_state(|s| s.starts)
+    }
+
+    fn validator_starts(&self) -> u32 {
+        self.with_state(|s| s.validator_starts)
+    }
+
+    /// Lets the running process exit unexpectedly
+    fn crash(&self) {
+        self.exit(1)
+    }
+
+    /// Lets the running process exit successfully, like at a scheduled block height
+    fn stop(&self) {
+        self.exit(0)
+    }
+
+    fn exit(&self, code: i32) {
+        self.with_state(|s| {
+            s.running = None;
+            s.exit_code = code;
+            s.exit.notify_one();
+        })
+    }
+
+    /// Latest block height of the running neard, a new block every second
+    fn head(&self) -> u64 {
+        self.with_state(|s| 1000 + s.started_at.elapsed().as_secs())
+    }
+
+    fn start(&self, role: Role, settings: &Settings) -> Result<Box<dyn Neard>> {
+        let exit = Arc::new(Notify::new());
+        self.with_state(|s| {
+            assert_eq!(s.running, None, "neard was started twice");
+            s.running = Some(role);
+            s.started_at = Instant::now();
+            s.exit = exit.clone();
+            s.starts += 1;
+            if role == Role::Validator {
+                s.validator_starts += 1;
+                s.validator_account_id = Some(settings.account_id.clone());
+                s.node_key = settings.validator_node_public_key.clone();
+            } else {
+                s.validator_account_id = None;
+                s.node_key = VOTER_NODE_KEY.to_string();
+            }
+        });
+        Ok(Box::new(SimulatedProcess {
+            neard: self.clone(),
+            exit,
+            started_at: Instant::now(),
+        }))
+    }
+
+    fn status_response(&self) -> Result<StatusResponse> {
+        let (syncing, started, validator_account_id, node_key) =
+            self.with_state(|s| match s.running {
+                Some(_) if s.started_at.elapsed() >= NEARD_STARTUP => Ok((
+                    s.syncing,
+                    s.started_at,
+                    s.validator_account_id.clone(),
+                    s.node_key.clone(),
+                )),
+                _ => bail!("connection refused"),
+            })?;
+        // a new block every second, so that no step down rule fires
+        let height = 1000 + started.elapsed().as_secs();
+        let block_time = DateTime::<Utc>::from(self.clock.now());
+        let zero_hash = "11111111111111111111111111111111";
+        Ok(serde_json::from_value(json!({
+            "version": { "version": "1.35.0", "build": "simulation" },
+            "chain_id": "localnet",
+            "protocol_version": 63,
+            "latest_protocol_version": 63,
+            "validators": [],
+            "sync_info": {
+                "latest_block_hash": zero_hash,
+                "latest_block_height": height,
+                "latest_state_root": zero_hash,
+                "latest_block_time": block_time.to_rfc3339(),
+                "syncing": syncing,
+            },
+            "validator_account_id": validator_account_id,
+            "validator_public_key": null,
+            "node_public_key": node_key,
+            "node_key": null,
+            "uptime_sec": 0,
+        }))?)
+    }
+}
+
+impl NeardLauncher for SimulatedNeard {
+    fn start_voter(&self, settings: &Settings) -> Result<Box<dyn Neard>> {
+        self.start(Role::Voter, settings)
+    }
+
+    fn start_validator(&self, settings: &Settings) -> Result<Box<dyn Neard>> {
+        self.start(Role::Validator, settings)
+    }
+
+    fn version<'a>(&'a self, _settings: &'a Settings) -> BoxFuture<'a, Result<NeardVersion>> {
+        async move {
+            Ok(NeardVersion {
+                version: "1.32.2".to_string(),
+                protocol: 59,
+                db: self.with_state(|s| s.db_version),
+            })
+        }
+        .boxed()
+    }
+}
+
+impl NeardApi for SimulatedNeard {
+    fn status(&self) -> BoxFuture<'_, Result<StatusResponse>> {
+        async move { self.status_response() }.boxed()
+    }
+
+    fn maintenance_windows<'a>(
+        &'a self,
+        _account_id: &'a AccountId,
+    ) -> BoxFuture<'a, Result<MaintenanceWindowRPCResult>> {
+        async move {
+            let in_window = self.with_state(|s| s.in_maintenance_window);
+            let windows = if in_window {
+                vec![(0, u64::MAX)]
+            } else {
+                vec![]
+            };
+            Ok(MaintenanceWindowRPCResult(windows))
+        }
+        .boxed()
+    }
+
+    fn network_info(&self) -> BoxFuture<'_, Result<NetworkInfo>> {
+        async move {
+            let peers = self.with_state(|s| s.peers);
+            Ok(NetworkInfo {
+                active_peers: (0..peers)
+                    .map(|i| NetworkInfoPeer {
+                        id: format!("ed25519:peer{i}"),
+                        addr: None,
+                        account_id: None,
+                    })
+                    .collect(),
+                known_producers: vec![],
+            })
+        }
+        .boxed()
+    }
+
+    fn validators(&self) -> BoxFuture<'_, Result<EpochValidatorInfo>> {
+        async move {
+            Ok(serde_json::from_value(json!({
+                "current_validators": [],
+                "next_validators": [],
+                "current_fishermen": [],
+                "next_fishermen": [],
+                "current_proposals": [],
+                "prev_epoch_kickout": [],
+                "epoch_start_height": 1000,
+                "epoch_height": 1,
+            }))?)
+        }
+        .boxed()
+    }
+
+    fn protocol_config(&self) -> BoxFuture<'_, Result<ProtocolConfig>> {
+        async move {
+            Ok(ProtocolConfig {
+                block_producer_kickout_threshold: 90,
+                chunk_producer_kickout_threshold: 90,
+                epoch_length: 43200,
+            })
+        }
+        .boxed()
+    }
+
+    fn metrics(&self) -> BoxFuture<'_, Result<HashMap<String, String>>> {
+        async move { Ok(HashMap::new()) }.boxed()
+    }
+}
+
+#[derive(Debug)]
+struct SimulatedProcess {
+    neard: SimulatedNeard,
+    exit: Arc<Notify>,
+    started_at: Instant,
+}
+
+impl Neard for SimulatedProcess {
+    fn wait(&mut self) -> BoxFuture<'_, io::Result<ExitStatus>> {
+        async move {
+            self.exit.notified().await;
+            let code = self.neard.with_state(|s| s.exit_code);
+            Ok(ExitStatus::from_raw(code << 8))
+        }
+        .boxed()
+    }
+
+    fn pid(&self) -> Option<Pid> {
+        None
+    }
+
+    fn started_at(&self) -> Instant {
+        self.started_at
+    }
+
+    fn stderr_tail(&self) -> Vec<String> {
+        vec!["thread 'main' panicked".to_string()]
+    }
+
+    fn graceful_stop(self: Box<Self>) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for SimulatedProcess {
+    fn drop(&mut self) {
+        let exit = &self.exit;
+        self.neard.with_state(|s| {
+            // the process may have crashed and was replaced already
+            if Arc::ptr_eq(&s.exit, exit) {
+                s.running = None;
+            }
+        })
+    }
+}
+
+/// Election backend shared by all nodes, that can become unreachable
+#[derive(Debug, Clone, Default)]
+struct SimulatedElection {
+    inner: MemoryElection,
+    unreachable: Arc<AtomicBool>,
+}
+
+impl SimulatedElection {
+    fn set_reachable(&self, reachable: bool) {
+        self.unreachable.store(!reachable, Ordering::SeqCst);
+    }
+
+    fn check(&self) -> Result<()> {
+        if self.unreachable.load(Ordering::SeqCst) {
+            bail!("connection refused");
+        }
+        Ok(())
+    }
+}
+
+impl LeaderElection for SimulatedElection {
+    fn create_session<'a>(
+        &'a self,
+        session_name: &'a str,
+        ttl: Duration,
+    ) -> BoxFuture<'a, Result<ElectionSession>> {
+        async move {
+            self.check()?;
+            self.inner.create_session(session_name, ttl).await
+        }
+        .boxed()
+    }
+
+    fn renew_session<'a>(&'a self, session: &'a ElectionSession) -> BoxFuture<'a, Result<()>> {
+        async move {
+            self.check()?;
+            self.inner.renew_session(session).await
+        }
+        .boxed()
+    }
+
+    fn destroy_session<'a>(&'a self, session: &'a ElectionSession) -> BoxFuture<'a, Result<()>> {
+        async move {
+            self.check()?;
+            self.inner.destroy_session(session).await
+        }
+        .boxed()
+    }
+
+    fn acquire_lock<'a>(
+        &'a self,
+        key: &'a str,
+        metadata: &'a HashMap<&'static str, String>,
+        session: &'a ElectionSession,
+    ) -> BoxFuture<'a, Result<bool>> {
+        async move {
+            self.check()?;
+            self.inner.acquire_lock(key, metadata, session).await
+        }
+        .boxed()
+    }
+
+    fn release_lock<'a>(
+        &'a self,
+        key: &'a str,
+        session: &'a ElectionSession,
+    ) -> BoxFuture<'a, Result<()>> {
+        async move {
+            self.check()?;
+            self.inner.release_lock(key, session).await
+        }
+        .boxed()
+    }
+
+    fn leader<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<Option<Leader>>> {
+        async move {
+            self.check()?;
+            self.inner.leader(key).await
+        }
+        .boxed()
+    }
+
+    fn list_locks<'a>(
+        &'a self,
+        prefix: &'a str,
+    ) -> BoxFuture<'a, Result<Vec<HashMap<String, String>>>> {
+        async move {
+            self.check()?;
+            self.inner.list_locks(prefix).await
+        }
+        .boxed()
+    }
+
+    fn set_token(&self, token: Option<&str>) -> Result<()> {
+        self.inner.set_token(token)
+    }
+}
+
+/// A supervisor running against simulated neard and election backend
+struct SimulatedNode {
+    neard: SimulatedNeard,
+    journal: Journal,
+    task: JoinHandle<Result<()>>,
+    // dropping the sender shuts down the supervisor
+    requests: mpsc::Sender<ipc::Request>,
+    home: tempfile::TempDir,
+}
+
+impl SimulatedNode {
+    fn spawn(node_id: &str, election: &SimulatedElection) -> Self {
+        Self::spawn_with(node_id, election, |_| {})
+    }
+
+    /// Like `spawn`, but lets the test change the settings of the node
+    fn spawn_with(
+        node_id: &str,
+        election: &SimulatedElection,
+        configure: impl FnOnce(&mut Settings),
+    ) -> Self {
+        let home = tempfile::tempdir().unwrap();
+        let mut settings = default_settings();
+        settings.node_id = node_id.to_string();
+        settings.account_id = "kuutamo.pool.f863973.m0".parse().unwrap();
+        settings.neard_home = home.path().to_path_buf();
+        settings.validator_node_public_key = VALIDATOR_NODE_KEY.to_string();
+        // the disk of the host running the tests must not influence the simulation
+        settings.disk_warning_usage = 100;
+        settings.disk_critical_usage = 100;
+        configure(&mut settings);
+
+        let clock = TokioClock::new();
+        let neard = SimulatedNeard::new(clock);
+        let env = Environment {
+            launcher: Box::new(neard.clone()),
+            neard_client: Box::new(neard.clone()),
+            election: Box::new(election.clone()),
+            clock: Box::new(clock),
+        };
+        let (requests, request_chan) = mpsc::channel(1);
+        let mut state = StateMachine::new(&settings, request_chan, env).unwrap();
+        let task = tokio::spawn(async move {
+            while state.next().await? != StateType::Shutdown {}
+            Ok(())
+        });
+        Self {
+            neard,
+            journal: Journal::new(home.path()),
+            task,
+            requests,
+            home,
+        }
+    }
+
+    /// Returns all transitions as (from, to, reason)
+    fn transitions(&self) -> Vec<(String, String, TransitionReason)> {
+        self.journal
+            .entries()
+            .unwrap()
+            .into_iter()
+            .map(|e| (e.from, e.to, e.reason))
+            .collect()
+    }
+
+    fn state(&self) -> Option<String> {
+        self.transitions().last().map(|(_, to, _)| to.clone())
+    }
+
+    /// Drains or undrains the node like `kneard-ctl drain`
+    async fn drain(&self, drained: bool) {
+        let (tx, mut rx) = mpsc::channel(1);
+        self.requests
+            .send(ipc::Request::Drain(drained, tx))
+            .await
+            .unwrap();
+        rx.recv().await.unwrap().result.unwrap();
+    }
+}
+
+impl Drop for SimulatedNode {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+fn transitions(
+    expected: &[(&str, &str, TransitionReason)],
+) -> Vec<(String, String, TransitionReason)> {
+    expected
+        .iter()
+        .map(|(from, to, reason)| (from.to_string(), to.to_string(), *reason))
+        .collect()
+}
+
+/// Advances the time in small steps and checks that never two nodes validate at once
+async fn run_for(duration: Duration, nodes: &[&SimulatedNode]) {
+    let end = Instant::now() + duration;
+    while Instant::now() < end {
+        let validators = nodes
+            .iter()
+            .filter(|n| n.neard.role() == Some(Role::Validator))
+            .count();
+        assert!(
+            validators <= 1,
+            "{validators} nodes validate at the same time"
+        );
+        sleep(Duration::from_millis(100)).await;
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_simulate_becoming_validator() {
+    let election = SimulatedElection::default();
+    let node = SimulatedNode::spawn("node0", &election);
+    node.neard.set_syncing(true);
+    run_for(Duration::from_secs(30), &[&node]).await;
+    assert_eq!(node.state().as_deref(), Some("Syncing"));
+
+    node.neard.set_syncing(false);
+    run_for(Duration::from_secs(5), &[&node]).await;
+    assert_eq!(
+        node.transitions(),
+        transitions(&[
+            ("Startup", "Syncing", TransitionReason::NeardStarted),
+            ("Syncing", "Registering", TransitionReason::Synced),
+            ("Registering", "Voting", TransitionReason::SessionCreated),
+            ("Voting", "Validating", TransitionReason::LeaderAcquired),
+        ])
+    );
+    assert_eq!(node.neard.role(), Some(Role::Validator));
+    assert_eq!(node.neard.validator_starts(), 1);
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_simulate_election_backend_unreachable_while_syncing() {
+    let election = SimulatedElection::default();
+    let node = SimulatedNode::spawn("node0", &election);
+    run_for(Duration::from_secs(10), &[&node]).await;
+    assert_eq!(node.neard.role(), Some(Role::Validator));
+
+    // consul is unreachable for 26s while neard falls behind
+    election.set_reachable(false);
+    node.neard.set_syncing(true);
+    run_for(Duration::from_secs(26), &[&node]).await;
+    // the validator must have stopped before our session expired in consul
+    assert_ne!(node.neard.role(), Some(Role::Validator));
+
+    election.set_reachable(true);
+    run_for(Duration::from_secs(10), &[&node]).await;
+    assert_eq!(node.state().as_deref(), Some("Syncing"));
+    node.neard.set_syncing(false);
+    run_for(Duration::from_secs(10), &[&node]).await;
+
+    assert_eq!(
+        node.transitions(),
+        transitions(&[
+            ("Startup", "Syncing", TransitionReason::NeardStarted),
+            ("Syncing", "Registering", TransitionReason::Synced),
+            ("Registering", "Voting", TransitionReason::SessionCreated),
+            ("Voting", "Validating", TransitionReason::LeaderAcquired),
+            ("Validating", "Voting", TransitionReason::SessionLost),
+            // the validator was stopped, so neard is started again as voter
+            ("Voting", "Startup", TransitionReason::NeardExited),
+            ("Startup", "Syncing", TransitionReason::NeardStarted),
+            ("Syncing", "Registering", TransitionReason::Synced),
+            ("Registering", "Voting", TransitionReason::SessionCreated),
+            ("Voting", "Validating", TransitionReason::LeaderAcquired),
+        ])
+    );
+    assert_eq!(node.neard.validator_starts(), 2);
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_simulate_failover_on_neard_crash() {
+    let election = SimulatedElection::default();
+    let node0 = SimulatedNode::spawn("node0", &election);
+    run_for(Duration::from_secs(10), &[&node0]).await;
+    let node1 = SimulatedNode::spawn("node1", &election);
+    run_for(Duration::from_secs(10), &[&node0, &node1]).await;
+    assert_eq!(node0.neard.role(), Some(Role::Validator));
+    assert_eq!(node1.state().as_deref(), Some("Voting"));
+
+    node0.neard.crash();
+    run_for(Duration::from_secs(30), &[&node0, &node1]).await;
+    assert_eq!(node1.neard.role(), Some(Role::Validator));
+    assert_eq!(node0.state().as_deref(), Some("Voting"));
+    assert_eq!(node0.neard.validator_starts(), 1);
+    assert_eq!(node1.neard.validator_starts(), 1);
+    assert_eq!(
+        node0.transitions()[4..],
+        transitions(&[
+            ("Validating", "Startup", TransitionReason::NeardExited),
+            ("Startup", "Syncing", TransitionReason::NeardStarted),
+            ("Syncing", "Registering", TransitionReason::Synced),
+            ("Registering", "Voting", TransitionReason::SessionCreated),
+        ])
+    );
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_simulate_step_down() {
+    let election = SimulatedElection::default();
+    let node0 = SimulatedNode::spawn_with("node0", &election, |s| {
+        s.step_down_max_syncing = Some(30);
+        s.step_down_cooldown = 120;
+    });
+    run_for(Duration::from_secs(10), &[&node0]).await;
+    let node1 = SimulatedNode::spawn("node1", &election);
+    run_for(Duration::from_secs(10), &[&node0, &node1]).await;
+    assert_eq!(node0.neard.role(), Some(Role::Validator));
+
+    node0.neard.set_syncing(true);
+    run_for(Duration::from_secs(35), &[&node0, &node1]).await;
+    node0.neard.set_syncing(false);
+    run_for(Duration::from_secs(10), &[&node0, &node1]).await;
+    assert_eq!(node0.neard.role(), Some(Role::Voter));
+    assert_eq!(node0.state().as_deref(), Some("Voting"));
+    assert_eq!(node1.neard.role(), Some(Role::Validator));
+    assert_eq!(
+        node0.transitions()[4..],
+        transitions(&[("Validating", "Voting", TransitionReason::StepDown)])
+    );
+
+    // no takeover while cooling down, the restarted node1 wins the lock again
+    node1.neard.crash();
+    run_for(Duration::from_secs(30), &[&node0, &node1]).await;
+    assert_eq!(node1.neard.role(), Some(Role::Validator));
+    assert_eq!(node0.neard.validator_starts(), 1);
+
+    run_for(Duration::from_secs(90), &[&node0, &node1]).await;
+    node1.neard.crash();
+    run_for(Duration::from_secs(2), &[&node0, &node1]).await;
+    assert_eq!(node0.neard.role(), Some(Role::Validator));
+    assert_eq!(node0.neard.validator_starts(), 2);
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_simulate_no_peers() {
+    let election = SimulatedElection::default();
+    let node0 = SimulatedNode::spawn("node0", &election);
+    node0.neard.set_peers(0);
+    run_for(Duration::from_secs(30), &[&node0]).await;
+    // a node without peers would not produce blocks, so it must not win the election
+    assert_eq!(node0.state().as_deref(), Some("Voting"));
+    assert_eq!(node0.neard.role(), Some(Role::Voter));
+
+    let node1 = SimulatedNode::spawn("node1", &election);
+    run_for(Duration::from_secs(10), &[&node0, &node1]).await;
+    assert_eq!(node1.neard.role(), Some(Role::Validator));
+
+    // takes over once it has peers and the session of the other node expired
+    node0.neard.set_peers(3);
+    drop(node1);
+    run_for(Duration::from_secs(45), &[&node0]).await;
+    assert_eq!(node0.neard.role(), Some(Role::Validator));
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_simulate_drain() {
+    let election = SimulatedElection::default();
+    let node0 = SimulatedNode::spawn("node0", &election);
+    run_for(Duration::from_secs(10), &[&node0]).await;
+    let node1 = SimulatedNode::spawn("node1", &election);
+    run_for(Duration::from_secs(10), &[&node0, &node1]).await;
+    assert_eq!(node0.neard.role(), Some(Role::Validator));
+
+    let candidates = || async {
+        election
+            .list_locks(&candidates_prefix("kuutamo.pool.f863973.m0"))
+            .await
+            .unwrap()
+            .iter()
+            .map(|c| c["NodeId"].clone())
+            .collect::<Vec<_>>()
+    };
+    assert!(candidates().await.contains(&"node1".to_string()));
+
+    // a drained standby withdraws its candidacy and never takes over
+    node1.drain(true).await;
+    run_for(Duration::from_secs(5), &[&node0, &node1]).await;
+    assert_eq!(node1.state().as_deref(), Some("Voting"));
+    assert!(!candidates().await.contains(&"node1".to_string()));
+    node0.neard.crash();
+    run_for(Duration::from_secs(40), &[&node0, &node1]).await;
+    assert_eq!(node0.neard.role(), Some(Role::Validator));
+    assert_eq!(node1.neard.validator_starts(), 0);
+
+    // a drained validator keeps validating until the next maintenance window
+    node0.drain(true).await;
+    run_for(Duration::from_secs(20), &[&node0, &node1]).await;
+    assert_eq!(node0.neard.role(), Some(Role::Validator));
+    // and as long as no candidate can take over
+    node0.neard.set_in_maintenance_window(true);
+    run_for(Duration::from_secs(20), &[&node0, &node1]).await;
+    assert_eq!(node0.neard.role(), Some(Role::Validator));
+    node1.drain(false).await;
+    run_for(Duration::from_secs(20), &[&node0, &node1]).await;
+    assert_eq!(node1.neard.role(), Some(Role::Validator));
+    assert_eq!(node0.state().as_deref(), Some("Voting"));
+    assert!(node0.transitions().contains(&(
+        "Validating".to_string(),
+        "Startup".to_string(),
+        TransitionReason::Drain
+    )));
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_simulate_failback_handover() {
+    let election = SimulatedElection::default();
+    let node0 = SimulatedNode::spawn("node0", &election);
+    run_for(Duration::from_secs(10), &[&node0]).await;
+    let node1 = SimulatedNode::spawn_with("node1", &election, |s| s.priority = 1);
+    run_for(Duration::from_secs(10), &[&node0, &node1]).await;
+    assert_eq!(node0.neard.role(), Some(Role::Validator));
+
+    // the validator hands over to the candidate with a higher priority
+    node0.neard.set_in_maintenance_window(true);
+    run_for(Duration::from_secs(12), &[&node0, &node1]).await;
+    assert_eq!(node1.neard.role(), Some(Role::Validator));
+    assert!(node0.transitions().contains(&(
+        "Validating".to_string(),
+        "Startup".to_string(),
+        TransitionReason::Handover
+    )));
+
+    // the announcement outlives the restart of neard until it expires
+    let handovers = || async {
+        election
+            .list_locks(&handover_prefix("kuutamo.pool.f863973.m0"))
+            .await
+            .unwrap()
+            .len()
+    };
+    run_for(Duration::from_secs(10), &[&node0, &node1]).await;
+    assert_eq!(node0.state().as_deref(), Some("Voting"));
+    assert_eq!(handovers().await, 1);
+    run_for(Duration::from_secs(30), &[&node0, &node1]).await;
+    assert_eq!(handovers().await, 0);
+    assert_eq!(node1.neard.role(), Some(Role::Validator));
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_simulate_scheduled_shutdown_on_voter() {
+    let election = SimulatedElection::default();
+    let node0 = SimulatedNode::spawn("node0", &election);
+    run_for(Duration::from_secs(10), &[&node0]).await;
+    let node1 = SimulatedNode::spawn("node1", &election);
+    run_for(Duration::from_secs(10), &[&node0, &node1]).await;
+    assert_eq!(node1.state().as_deref(), Some("Voting"));
+
+    // neard of the voter crashes or stops long before the scheduled block height,
+    // so it is restarted and keeps the operation
+    let schedule = Schedule::new(node1.home.path());
+    let now = TokioClock::new().now();
+    let operation = ScheduledOperation::new(u64::MAX, OperationMode::Shutdown, "Voting", now);
+    schedule.set(&operation).unwrap();
+    node1.neard.crash();
+    run_for(Duration::from_secs(20), &[&node0, &node1]).await;
+    node1.neard.stop();
+    run_for(Duration::from_secs(20), &[&node0, &node1]).await;
+    assert_eq!(node1.state().as_deref(), Some("Voting"));
+    assert_eq!(node1.neard.starts(), 3);
+    assert_eq!(schedule.get().unwrap(), Some(operation));
+
+    // neard of the voter stops at the scheduled block height, so kneard exits with it
+    let operation =
+        ScheduledOperation::new(node1.neard.head(), OperationMode::Shutdown, "Voting", now);
+    schedule.set(&operation).unwrap();
+    run_for(Duration::from_secs(2), &[&node0, &node1]).await;
+    node1.neard.stop();
+    run_for(Duration::from_secs(2), &[&node0, &node1]).await;
+    assert_eq!(
+        node1.transitions().last(),
+        Some(&(
+            "Voting".to_string(),
+            "Shutdown".to_string(),
+            TransitionReason::NeardExited
+        ))
+    );
+    assert_eq!(schedule.get().unwrap(), None);
+    assert_eq!(node0.neard.role(), Some(Role::Validator));
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_simulate_db_migration() {
+    let election = SimulatedElection::default();
+    let node = SimulatedNode::spawn("node0", &election);
+    run_for(Duration::from_secs(10), &[&node]).await;
+    let versions = DbVersions::load(node.home.path()).unwrap();
+    assert_eq!(versions.db, Some(34));
+    assert_eq!(versions.migrations, vec![]);
+
+    // the startup of neard with a new db version is recorded as migration
+    node.neard.set_db_version(35);
+    node.neard.crash();
+    run_for(Duration::from_secs(20), &[&node]).await;
+    assert_eq!(node.neard.role(), Some(Role::Validator));
+    let versions = DbVersions::load(node.home.path()).unwrap();
+    assert_eq!(versions.db, Some(35));
+    let migrations = versions
+        .migrations
+        .iter()
+        .map(|m| (m.from, m.to))
+        .collect::<Vec<_>>();
+    assert_eq!(migrations, vec![(34, 35)]);
+    assert!(versions.migrations[0].duration >= NEARD_STARTUP.as_secs());
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_simulate_validator_restart() {
+    // neard cannot switch its node key at runtime, the voter is restarted as validator
+    let election = SimulatedElection::default();
+    let node = SimulatedNode::spawn("node0", &election);
+    run_for(Duration::from_secs(20), &[&node]).await;
+    assert_eq!(node.neard.role(), Some(Role::Validator));
+    assert_eq!(node.neard.starts(), 2);
+    assert_eq!(node.neard.validator_starts(), 1);
+    let status = node.neard.status_response().unwrap();
+    assert_eq!(status.node_public_key.to_string(), VALIDATOR_NODE_KEY);
+    assert_eq!(
+        status
+            .validator_account_id
+            .map(|a| a.to_string())
+            .as_deref(),
+        Some("kuutamo.pool.f863973.m0")
+    );
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_simulate_crash_loop() {
+    let election = SimulatedElection::default();
+    let node = SimulatedNode::spawn("node0", &election);
+    let crash_loop = |duration: Duration| {
+        let neard = node.neard.clone();
+        async move {
+            let end = Instant::now() + duration;
+            while Instant::now() < end {
+                if neard.role().is_some() {
+                    neard.crash();
+                }
+                sleep(Duration::from_millis(100)).await;
+            }
+        }
+    };
+    // restarts are delayed by 1s, 2s, 4s, 8s and 16s
+    crash_loop(Duration::from_secs(40)).await;
+    assert_eq!(node.neard.starts(), 6);
+
+    // kneard gives up after 10 failures in a row
+    crash_loop(Duration::from_secs(1200)).await;
+    assert_eq!(node.neard.starts(), 11);
+    assert!(node.task.is_finished());
+    let crashes = crash_journal(node.home.path()).entries().unwrap();
+    assert_eq!(crashes.len(), 11);
+    assert_eq!(crashes[0].reason, ExitReason::Error);
+    assert_eq!(crashes[0].exit_code, Some(1));
+    assert_eq!(crashes[0].state, "Startup");
+    assert_eq!(crashes[0].stderr, vec!["thread 'main' panicked"]);
+}
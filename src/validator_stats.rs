@@ -0,0 +1,203 @@
+//! Tracks how many blocks and chunks our validator produced in the current epoch,
+//! so that missed production is noticed before neard gets kicked out
+
+use crate::near_client::{NeardApi, ProtocolConfig};
+use anyhow::{Context, Result};
+use lazy_static::lazy_static;
+use near_primitives::types::{AccountId, EpochHeight};
+use near_primitives::views::CurrentEpochValidatorInfo;
+use prometheus::{register_gauge, register_int_gauge_vec, Gauge, IntGaugeVec};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+lazy_static! {
+    static ref PRODUCED: IntGaugeVec = register_int_gauge_vec!(
+        "kuutamod_validator_produced",
+        "Blocks or chunks our validator produced in the current epoch",
+        &["type"],
+    )
+    .unwrap();
+    static ref EXPECTED: IntGaugeVec = register_int_gauge_vec!(
+        "kuutamod_validator_expected",
+        "Blocks or chunks our validator was expected to produce in the current epoch",
+        &["type"],
+    )
+    .unwrap();
+    static ref UPTIME: Gauge = register_gauge!(
+        "kuutamod_validator_uptime_ratio",
+        "Ratio of produced to expected blocks and chunks in the current epoch"
+    )
+    .unwrap();
+    static ref KICKOUT_RISK: Gauge = register_gauge!(
+        "kuutamod_validator_kickout_risk",
+        "1 if our validator produced less than the kickout thresholds of the network"
+    )
+    .unwrap();
+}
+
+/// Production of our validator in the current epoch
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ValidatorStats {
+    /// Our validator account
+    pub account_id: AccountId,
+    /// Height of the current epoch
+    pub epoch_height: EpochHeight,
+    /// Blocks produced in the current epoch
+    pub produced_blocks: u64,
+    /// Blocks we were expected to produce in the current epoch
+    pub expected_blocks: u64,
+    /// Chunks produced in the current epoch
+    pub produced_chunks: u64,
+    /// Chunks we were expected to produce in the current epoch
+    pub expected_chunks: u64,
+    /// Average of the ratios of produced to expected blocks and chunks, 1 if nothing was expected yet
+    pub uptime: f64,
+    /// Protocol config with the kickout thresholds of the network
+    pub thresholds: ProtocolConfig,
+    /// Whether we would be kicked out if the epoch ended now
+    pub kickout_risk: bool,
+}
+
+/// Ratio of produced to expected, None if nothing was expected
+fn ratio(produced: u64, expected: u64) -> Option<f64> {
+    (expected > 0).then(|| produced as f64 / expected as f64)
+}
+
+impl ValidatorStats {
+    /// Queries the production of `account_id` from neard,
+    /// None if the account does not validate in the current epoch
+    pub async fn query(c: &dyn NeardApi, account_id: &AccountId) -> Result<Option<Self>> {
+        let validators = c.validators().await.context("failed to get validators")?;
+        let validator = match validators
+            .current_validators
+            .iter()
+            .find(|v| &v.account_id == account_id)
+        {
+            Some(v) => v,
+            None => return Ok(None),
+        };
+        let thresholds = c
+            .protocol_config()
+            .await
+            .context("failed to get protocol config")?;
+        Ok(Some(Self::new(
+            validator,
+            validators.epoch_height,
+            thresholds,
+        )))
+    }
+
+    fn new(
+        validator: &CurrentEpochValidatorInfo,
+        epoch_height: EpochHeight,
+        thresholds: ProtocolConfig,
+    ) -> Self {
+        let blocks = ratio(validator.num_produced_blocks, validator.num_expected_blocks);
+        let chunks = ratio(validator.num_produced_chunks, validator.num_expected_chunks);
+        let ratios = [blocks, chunks].into_iter().flatten().collect::<Vec<_>>();
+        let uptime = if ratios.is_empty() {
+            1.0
+        } else {
+            ratios.iter().sum::<f64>() / ratios.len() as f64
+        };
+        let below = |ratio: Option<f64>, threshold: u8| {
+            ratio.is_some_and(|r| r * 100.0 < f64::from(threshold))
+        };
+        Self {
+            account_id: validator.account_id.clone(),
+            epoch_height,
+            produced_blocks: validator.num_produced_blocks,
+            expected_blocks: validator.num_expected_blocks,
+            produced_chunks: validator.num_produced_chunks,
+            expected_chunks: validator.num_expected_chunks,
+            uptime,
+            kickout_risk: below(blocks, thresholds.block_producer_kickout_threshold)
+                || below(chunks, thresholds.chunk_producer_kickout_threshold),
+            thresholds,
+        }
+    }
+
+    /// Exports the stats as prometheus metrics, resets them if `stats` is None
+    pub fn export(stats: Option<&Self>) {
+        let (blocks, chunks) = stats.map_or(((0, 0), (0, 0)), |s| {
+            (
+                (s.produced_blocks, s.expected_blocks),
+                (s.produced_chunks, s.expected_chunks),
+            )
+        });
+        for (kind, (produced, expected)) in [("blocks", blocks), ("chunks", chunks)] {
+            PRODUCED.with_label_values(&[kind]).set(produced as i64);
+            EXPECTED.with_label_values(&[kind]).set(expected as i64);
+        }
+        UPTIME.set(stats.map_or(0.0, |s| s.uptime));
+        KICKOUT_RISK.set(if stats.is_some_and(|s| s.kickout_risk) {
+            1.0
+        } else {
+            0.0
+        });
+    }
+}
+
+impl fmt::Display for ValidatorStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Account: {}", self.account_id)?;
+        writeln!(f, "Epoch: {}", self.epoch_height)?;
+        writeln!(
+            f,
+            "Blocks: {}/{} (kickout below {}%)",
+            self.produced_blocks,
+            self.expected_blocks,
+            self.thresholds.block_producer_kickout_threshold
+        )?;
+        writeln!(
+            f,
+            "Chunks: {}/{} (kickout below {}%)",
+            self.produced_chunks,
+            self.expected_chunks,
+            self.thresholds.chunk_producer_kickout_threshold
+        )?;
+        writeln!(f, "Uptime: {:.2}%", self.uptime * 100.0)?;
+        write!(
+            f,
+            "Kickout risk: {}",
+            if self.kickout_risk { "yes" } else { "no" }
+        )
+    }
+}
+
+#[test]
+fn test_validator_stats() {
+    let validator = |produced_blocks, expected_blocks, produced_chunks, expected_chunks| {
+        serde_json::from_value::<CurrentEpochValidatorInfo>(serde_json::json!({
+            "account_id": "kuutamo.pool.f863973.m0",
+            "public_key": "ed25519:11111111111111111111111111111111",
+            "is_slashed": false,
+            "stake": "1000",
+            "shards": [0],
+            "num_produced_blocks": produced_blocks,
+            "num_expected_blocks": expected_blocks,
+            "num_produced_chunks": produced_chunks,
+            "num_expected_chunks": expected_chunks,
+        }))
+        .unwrap()
+    };
+    let thresholds = ProtocolConfig {
+        block_producer_kickout_threshold: 90,
+        chunk_producer_kickout_threshold: 80,
+        epoch_length: 43200,
+    };
+
+    let fresh = ValidatorStats::new(&validator(0, 0, 0, 0), 1, thresholds);
+    assert_eq!(fresh.uptime, 1.0);
+    assert!(!fresh.kickout_risk);
+
+    let good = ValidatorStats::new(&validator(95, 100, 85, 100), 1, thresholds);
+    assert!((good.uptime - 0.9).abs() < 1e-9);
+    assert!(!good.kickout_risk);
+
+    let missed_blocks = ValidatorStats::new(&validator(89, 100, 100, 100), 1, thresholds);
+    assert!(missed_blocks.kickout_risk);
+
+    let missed_chunks = ValidatorStats::new(&validator(100, 100, 79, 100), 1, thresholds);
+    assert!(missed_chunks.kickout_risk);
+}